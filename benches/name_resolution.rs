@@ -0,0 +1,31 @@
+use std::time::Instant;
+
+use caurora::Interpreter;
+
+// A function with `count` locals, each read back several times by name.
+fn many_locals(count: usize) -> String {
+    let mut source = String::from("function sum() {\n");
+    for i in 0..count {
+        source.push_str(&format!("    var local_{} = {};\n", i, i));
+    }
+    source.push_str("    var total = 0;\n");
+    for _ in 0..4 {
+        for i in 0..count {
+            source.push_str(&format!("    total = total + local_{};\n", i));
+        }
+    }
+    source.push_str("    return total;\n}\nprint sum();\n");
+    source
+}
+
+// Compiling and running a function with hundreds of locals should stay well
+// under a second.
+fn main() {
+    for count in [100, 200, 400] {
+        let source = many_locals(count);
+        let mut interpreter = Interpreter::builder().capture_output().build();
+        let start = Instant::now();
+        interpreter.run(&source).unwrap();
+        println!("{} locals: {:?}", count, start.elapsed());
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::{Duration, Instant};
+
+use caurora::{scanner::Scanner, token::TokenType};
+
+fn generated(lines: usize) -> String {
+    (0..lines)
+        .map(|i| format!("var v{} = \"línea {} ✓\" + {}.5; // cömment\n", i, i, i))
+        .collect()
+}
+
+fn time_to_scan(source: &str) -> Duration {
+    (0..3)
+        .map(|_| {
+            let start = Instant::now();
+            let mut scanner = Scanner::new(source);
+            while scanner.scan_token().tokentype != TokenType::Eof {}
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+// Scanning eight times the text should take about eight times as long; a
+// scanner that rescans from the start for each character would take 64.
+fn main() {
+    let small = time_to_scan(&generated(2_000));
+    let large = time_to_scan(&generated(16_000));
+    println!("scan 2000 lines:  {:?}", small);
+    println!("scan 16000 lines: {:?}", large);
+    println!("8x the source took {:.1}x as long", large.as_secs_f64() / small.as_secs_f64());
+}
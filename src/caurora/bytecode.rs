@@ -0,0 +1,248 @@
+use std::rc::Rc;
+
+use super::{
+    memoryslice::{Chunk, MemorySlice},
+    opcodes::OpCode,
+    values::{Function, Object, Value},
+};
+
+// A compiled program on disk: the magic and version, then the chunks, each
+// its name, code and the line of every code word, then the constants, the
+// names of the global slots and the test blocks, each prefixed with its
+// length. Integers are little-endian.
+pub const MAGIC: &[u8; 4] = b"AURB";
+pub const VERSION: u16 = 6;
+
+const NUMBER: u8 = 0;
+const NIL: u8 = 1;
+const RAW: u8 = 2;
+const BOOL: u8 = 3;
+const STRING: u8 = 4;
+const FUNCTION: u8 = 5;
+
+pub fn encode(memory: &MemorySlice) -> Result<Vec<u8>, String> {
+    let mut out = MAGIC.to_vec();
+    out.extend(VERSION.to_le_bytes());
+    length(&mut out, memory.chunks().len());
+    for chunk in memory.chunks() {
+        string(&mut out, chunk.name());
+        words(&mut out, chunk.code());
+        length(&mut out, chunk.lines().len());
+        for line in chunk.lines() {
+            length(&mut out, *line);
+        }
+    }
+    length(&mut out, memory.constants().len());
+    for constant in memory.constants() {
+        match constant {
+            Value::Number(x) => {
+                out.push(NUMBER);
+                out.extend(x.to_le_bytes());
+            }
+            Value::Nil => out.push(NIL),
+            Value::Raw => out.push(RAW),
+            Value::Bool(b) => out.extend([BOOL, *b as u8]),
+            Value::Object(Object::String(s)) => {
+                out.push(STRING);
+                string(&mut out, s);
+            }
+            Value::Object(Object::Function(function)) => {
+                out.push(FUNCTION);
+                string(&mut out, &function.name);
+                for n in [&function.chunk, &function.arity, &function.min_arity] {
+                    length(&mut out, *n);
+                }
+            }
+            value => return Err(format!("cannot save the constant {} as bytecode", value)),
+        }
+    }
+    length(&mut out, memory.global_names().len());
+    for name in memory.global_names() {
+        string(&mut out, name);
+    }
+    length(&mut out, memory.get_tests().len());
+    for (name, chunk) in memory.get_tests() {
+        string(&mut out, name);
+        length(&mut out, *chunk);
+    }
+    Ok(out)
+}
+
+fn length(out: &mut Vec<u8>, n: usize) {
+    out.extend((n as u32).to_le_bytes());
+}
+
+fn words(out: &mut Vec<u8>, words: &[u16]) {
+    length(out, words.len());
+    for word in words {
+        out.extend(word.to_le_bytes());
+    }
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    length(out, s.len());
+    out.extend(s.as_bytes());
+}
+
+// Reads a program written by `encode`, checking it is safe to run: every
+// opcode must exist and every operand must point inside the program.
+pub fn decode(bytes: &[u8]) -> Result<MemorySlice, String> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("not an aurora bytecode file".to_owned());
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(format!(
+            "bytecode version {} is not supported (expected {})",
+            version, VERSION
+        ));
+    }
+    let mut chunks = Vec::new();
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        let code = reader.words()?;
+        let mut lines = Vec::new();
+        for _ in 0..reader.u32()? {
+            lines.push(reader.u32()? as usize);
+        }
+        chunks.push(Chunk::new(name, code, lines));
+    }
+    let mut constants = Vec::new();
+    for _ in 0..reader.u32()? {
+        constants.push(reader.value()?);
+    }
+    let mut globals = Vec::new();
+    for _ in 0..reader.u32()? {
+        globals.push(reader.string()?.into());
+    }
+    let mut tests = Vec::new();
+    for _ in 0..reader.u32()? {
+        tests.push((reader.string()?, reader.u32()? as usize));
+    }
+    if reader.offset != bytes.len() {
+        return Err(format!("unexpected data at byte {}", reader.offset));
+    }
+    let memory = MemorySlice::from_parts(chunks, constants, globals, tests);
+    validate(&memory)?;
+    Ok(memory)
+}
+
+fn validate(memory: &MemorySlice) -> Result<(), String> {
+    let Some(script) = memory.chunk(0) else {
+        return Err("the program has no script".to_owned());
+    };
+    for chunk in memory.chunks() {
+        validate_chunk(memory, chunk).map_err(|message| format!("{} in {}", message, chunk.name()))?;
+    }
+    if script.instructions().last().map(|last| last.opcode) != Some(OpCode::Eof) {
+        return Err("the code does not end with Eof".to_owned());
+    }
+    let chunks = memory.constants().iter().filter_map(|constant| match constant {
+        Value::Object(Object::Function(function)) => Some(function.chunk),
+        _ => None,
+    });
+    let tests = memory.get_tests().iter().map(|(_, chunk)| *chunk);
+    if chunks.chain(tests).any(|chunk| chunk >= memory.chunks().len()) {
+        return Err("a function names a missing chunk".to_owned());
+    }
+    Ok(())
+}
+
+// Every opcode must exist, with all its operand words, and every operand
+// must name a constant, a global or an offset inside the chunk.
+fn validate_chunk(memory: &MemorySlice, chunk: &Chunk) -> Result<(), String> {
+    let code = chunk.code();
+    if chunk.lines().len() != code.len() {
+        return Err("the lines table does not match the code".to_owned());
+    }
+    let mut offset = 0;
+    while offset < code.len() {
+        if OpCode::try_from(code[offset]).is_err() {
+            return Err(format!("invalid opcode {} at {}", code[offset], offset));
+        }
+        let instruction = chunk.instruction_at(offset).unwrap();
+        if offset + instruction.size() > code.len() {
+            return Err(format!("{:?} at {} is missing its operand", instruction.opcode, offset));
+        }
+        if let Some(operand) = instruction.operand {
+            if instruction.opcode.takes_constant() && operand as usize >= memory.get_constants_size() {
+                return Err(format!("{:?} at {} names a missing constant", instruction.opcode, offset));
+            }
+            if instruction.opcode.takes_global() && operand as usize >= memory.global_names().len() {
+                return Err(format!("{:?} at {} names a missing global", instruction.opcode, offset));
+            }
+            if instruction.opcode == OpCode::Loop && operand as usize > offset + instruction.size() {
+                return Err(format!("Loop at {} jumps before the start", offset));
+            }
+            if instruction.jump_target().is_some_and(|target| target >= code.len()) {
+                return Err(format!("{:?} at {} jumps past the end", instruction.opcode, offset));
+            }
+        }
+        offset += instruction.size();
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.checked_add(n).filter(|end| *end <= self.bytes.len());
+        let Some(end) = end else {
+            return Err(format!("bytecode is truncated at byte {}", self.bytes.len()));
+        };
+        let taken = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn words(&mut self) -> Result<Vec<u16>, String> {
+        let count = self.u32()? as usize;
+        let bytes = self.take(count.saturating_mul(2))?;
+        Ok(bytes
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+            .collect())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()? as usize;
+        let offset = self.offset;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| format!("invalid UTF-8 in the string at byte {}", offset))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let offset = self.offset;
+        Ok(match self.u8()? {
+            NUMBER => Value::Number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            NIL => Value::Nil,
+            RAW => Value::Raw,
+            BOOL => Value::Bool(self.u8()? != 0),
+            STRING => Value::Object(Object::String(self.string()?.into())),
+            FUNCTION => Value::Object(Object::Function(Rc::new(Function {
+                name: self.string()?,
+                chunk: self.u32()? as usize,
+                arity: self.u32()? as usize,
+                min_arity: self.u32()? as usize,
+            }))),
+            tag => return Err(format!("unknown constant tag {} at byte {}", tag, offset)),
+        })
+    }
+}
@@ -1,10 +1,21 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
 use super::{
-    errorlogger::log_error,
+    interner::{Interner, Symbol},
     memoryslice::MemorySlice,
     opcodes::OpCode,
+    peephole,
     scanner::Scanner,
+    stats::CompileStats,
     token::{Token, TokenType},
-    values::{Object, Value},
+    values::{Function, Object, Value},
+    virtualmachine::{arithmetic, compare},
 };
 
 #[repr(u16)]
@@ -18,9 +29,8 @@ enum Precedence {
     Comparison, // < > <= >=
     Term,       // + -
     Factor,     // * /
-    Unary,      // ! -
+    Power,      // **
     Call,       // . ()
-    Primary,
 }
 
 impl Precedence {
@@ -32,124 +42,409 @@ impl Precedence {
     }
 }
 
+struct Source {
+    path: PathBuf,
+    text: Rc<str>,
+}
+
+// Where an identifier resolved to: a slot in the current frame, a variable
+// captured from an enclosing function, or a global.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Variable {
+    Local(usize),
+    Upvalue(usize),
+    Global(Symbol),
+}
+
+// A variable a function captures: a slot of the enclosing function's frame,
+// or one of the enclosing function's own upvalues.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Upvalue {
+    index: usize,
+    is_local: bool,
+}
+
+// What kind of body is being compiled, which decides what a bare `return`
+// gives back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Function,
+    Method,
+    Initializer,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 struct Local {
-    name: Token,
+    name: Symbol,
     depth: usize,
     func_depth: usize,
+    // Whether a nested function captures it, so leaving its scope must move
+    // it off the stack rather than just drop it.
+    captured: bool,
+    // Declared with `const`, so it may not be assigned to.
+    constant: bool,
+}
+
+// A problem found while compiling, at a line and column of one of the
+// sources.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    // For a missing token: the one wanted and the text found instead.
+    pub expected: Option<TokenType>,
+    pub found: Option<String>,
+    // For text the scanner could not read: the source line it is on, shown
+    // under the message with a caret at the column.
+    pub snippet: Option<String>,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.path, self.line, self.column, self.message)?;
+        if let Some(found) = &self.found {
+            write!(f, ", found {}", found)?;
+        }
+        if let Some(snippet) = &self.snippet {
+            // Tabs are kept so the caret lines up however they are shown.
+            let padding: String = snippet
+                .chars()
+                .take(self.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            write!(f, "\n    {}\n    {}^", snippet, padding)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct Compiler {
     current: Token,
     previous: Token,
-    has_error: bool,
-    source: &'static str,
+    errors: Vec<CompileError>,
+    // Set by an error until the parser reaches the next statement; errors in
+    // between are likely fallout from the first and are not reported.
+    panic_mode: bool,
+    sources: Vec<Source>,
     memory: MemorySlice,
-    scanner: Scanner<'static>,
+    scanner: Scanner,
+    includes: Vec<Scanner>,
     locals: Vec<Local>,
+    interner: Interner,
+    name_constants: HashMap<Symbol, u32>,
     scope_depth: usize,
     func_returns: usize,
+    function_kinds: Vec<FunctionKind>,
+    // The upvalues of each function being compiled, innermost last.
+    upvalues: Vec<Vec<Upvalue>>,
+    // Whether each class being declared, innermost last, has a superclass.
+    classes: Vec<bool>,
+    functions: usize,
+    max_scope_depth: usize,
+    // The comparison operator that produced the operand just compiled, if
+    // any, so `a < b < c` can be rejected; parentheses clear it.
+    last_comparison: Option<Token>,
+    // The variable a bare identifier operand just read, which a postfix
+    // `++` or `--` may update.
+    last_variable: Option<(Variable, Token)>,
+    // Where the code of the left operand of the infix operator being
+    // compiled starts.
+    operand_start: usize,
+    // Where the most recent Call was emitted, so `return f(x);` can turn it
+    // into a TailCall.
+    last_call: Option<usize>,
+    // Top-level functions defined up front whose declarations have not been
+    // compiled yet, with the constant reserved for each.
+    hoisted: HashMap<Symbol, u32>,
+    // Top-level `const` names found by the same scan, so code compiled
+    // before a constant's declaration cannot assign to it either.
+    constant_names: HashSet<Symbol>,
+    // Print the value of each top-level expression statement, as the
+    // interactive prompt does.
+    echo: bool,
+    // Compute operators on literal operands at compile time.
+    fold: bool,
+    // Run the peephole pass over the finished code.
+    optimize: bool,
+    // Set once the statement just compiled always returns, so whatever
+    // follows it in the same block can never run. A block or `if` that always
+    // returns passes it on to the statement around it.
+    unreachable: bool,
+    warnings: Vec<CompileError>,
 }
 
 impl Compiler {
-    pub fn new(source: &'static str, memory: MemorySlice, scanner: Scanner<'static>) -> Self {
+    pub fn new(source: &str, memory: MemorySlice, scanner: Scanner) -> Self {
         Compiler {
             current: Token {
                 tokentype: TokenType::Nil,
                 start: 0,
                 length: 0,
                 line: 0,
+                column: 0,
+                source: 0,
             },
             previous: Token {
                 tokentype: TokenType::Nil,
                 start: 0,
                 length: 0,
                 line: 0,
+                column: 0,
+                source: 0,
             },
-            has_error: false,
-            source,
+            errors: Vec::new(),
+            panic_mode: false,
+            sources: vec![Source {
+                path: PathBuf::from("<script>"),
+                text: Rc::from(source),
+            }],
             memory,
             scanner,
+            includes: Vec::<Scanner>::new(),
             locals: Vec::<Local>::new(),
+            interner: Interner::default(),
+            name_constants: HashMap::new(),
             scope_depth: 0,
             func_returns: 0,
+            function_kinds: Vec::new(),
+            upvalues: Vec::new(),
+            classes: Vec::new(),
+            functions: 0,
+            max_scope_depth: 0,
+            last_comparison: None,
+            last_variable: None,
+            operand_start: 0,
+            last_call: None,
+            hoisted: HashMap::new(),
+            constant_names: HashSet::new(),
+            echo: false,
+            fold: true,
+            optimize: false,
+            unreachable: false,
+            warnings: Vec::new(),
         }
     }
 
-    pub fn compile(&mut self) -> MemorySlice {
+    pub fn set_path(&mut self, path: &str) {
+        self.sources[0].path = PathBuf::from(path);
+    }
+
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    pub fn set_fold(&mut self, fold: bool) {
+        self.fold = fold;
+    }
+
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    // Problems that do not stop the program from compiling, such as code
+    // that can never run.
+    pub fn warnings(&self) -> &[CompileError] {
+        &self.warnings
+    }
+
+    pub fn compile(&mut self) -> Result<MemorySlice, Vec<CompileError>> {
+        Ok(self.compile_incremental()?.0)
+    }
+
+    // Appends the source's code to the script of the memory the compiler was
+    // built over, replacing its trailing Eof, and returns the offset where
+    // the new code starts so a VM holding earlier state can resume from
+    // there. New bodies get chunks after the ones already there.
+    pub fn compile_incremental(&mut self) -> Result<(MemorySlice, usize), Vec<CompileError>> {
+        self.memory.pop_eof();
+        let entry = self.memory.chunk_size();
+        let first_chunk = self.memory.chunks().len();
+        self.hoist_functions();
         self.advance();
         while !self.match_token(TokenType::Eof) {
             self.declaration();
         }
-        // self.consume(TokenType::Eof, "Expect end of expression.");
         self.memory.push(OpCode::Eof);
-        self.memory.clone()
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+        if self.optimize {
+            peephole::optimize(&mut self.memory, 0, entry);
+            for chunk in first_chunk..self.memory.chunks().len() {
+                peephole::optimize(&mut self.memory, chunk, 0);
+            }
+        }
+        Ok((self.memory.clone(), entry))
     }
 
-    fn number(&mut self, can_assign: bool) {
-        let value: String = self
-            .source
-            .chars()
-            .skip(self.previous.start)
-            .take(self.previous.length)
-            .collect();
-        let value = value.parse::<f64>().unwrap();
-        self.memory
-            .push_constant(OpCode::Constant, Value::Number(value))
+    // Defines every top-level function before any other code runs, so code
+    // above a declaration can call it and two functions can call each other.
+    // A quick scan finds the names; each gets a reserved constant that its
+    // declaration fills in once compiled. The same scan notes top-level
+    // `const` names.
+    fn hoist_functions(&mut self) {
+        let mut scanner = Scanner::new(self.sources[0].text.clone());
+        let mut depth = 0usize;
+        let mut after_function = false;
+        let mut after_const = false;
+        loop {
+            let token = scanner.scan_token();
+            match token.tokentype {
+                TokenType::Eof => break,
+                TokenType::NewLine | TokenType::WhiteSpace => continue,
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace => depth = depth.saturating_sub(1),
+                TokenType::Identifier if after_function => {
+                    let name = self.parse_identifier(token);
+                    if !self.hoisted.contains_key(&name) {
+                        let constant = self.memory.reserve_constant();
+                        self.memory.set_line(token.line);
+                        let emitted = self.memory.push_constant_operand(OpCode::Constant, constant);
+                        self.emitted(emitted);
+                        self.global(OpCode::DefineGlobalSlot, name);
+                        self.hoisted.insert(name, constant);
+                    }
+                }
+                TokenType::Identifier if after_const => {
+                    let name = self.parse_identifier(token);
+                    self.constant_names.insert(name);
+                }
+                _ => (),
+            }
+            after_function = token.tokentype == TokenType::Fun && depth == 0;
+            after_const = token.tokentype == TokenType::Const && depth == 0;
+        }
+    }
+
+    pub fn stats(&self) -> CompileStats {
+        CompileStats {
+            functions: self.functions,
+            max_scope_depth: self.max_scope_depth,
+            ..CompileStats::of_memory(&self.memory)
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let lexeme = self.lexeme(self.previous);
+        let parsed = match lexeme.strip_prefix("0x").or_else(|| lexeme.strip_prefix("0X")) {
+            Some(digits) if separated(digits, |c| c.is_ascii_hexdigit()) => {
+                i64::from_str_radix(&digits.replace('_', ""), 16).ok().map(|n| n as f64)
+            }
+            None if separated(lexeme, |c| c.is_ascii_digit()) => {
+                lexeme.replace('_', "").parse::<f64>().ok()
+            }
+            _ => None,
+        };
+        let Some(value) = parsed else {
+            let message = format!("malformed number literal '{}'", lexeme);
+            self.error_at(self.previous, &message);
+            return;
+        };
+        self.emit_constant(OpCode::Constant, Value::Number(value));
+    }
+
+    fn lexeme(&self, token: Token) -> &str {
+        &self.sources[token.source].text[token.start..token.start + token.length]
     }
 
-    fn get_token_name(&self) -> String {
-        self.source
-            .chars()
-            .skip(self.current.start)
-            .take(self.current.length)
-            .collect()
+    // The text between a string literal's quotes. After a missing string has
+    // been reported, the token in its place gives an empty string.
+    fn string_contents(&self, token: Token) -> &str {
+        let lexeme = self.lexeme(token);
+        match token.tokentype {
+            TokenType::String => &lexeme[1..lexeme.len() - 1],
+            _ => "",
+        }
+    }
+
+    fn warn_at(&mut self, token: Token, message: &str) {
+        self.warnings.push(CompileError {
+            path: self.sources[token.source].path.display().to_string(),
+            line: token.line,
+            column: token.column,
+            message: message.to_owned(),
+            expected: None,
+            found: None,
+            snippet: None,
+        });
     }
 
+    fn error_at(&mut self, token: Token, message: &str) {
+        self.report(token, message, None);
+    }
+
+    // Records an error at the token, with what was found there when a token
+    // was expected.
+    fn report(&mut self, token: Token, message: &str, expected: Option<TokenType>) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        let found = expected.map(|_| match token.tokentype {
+            TokenType::Eof => "end of file".to_owned(),
+            _ => format!("'{}'", self.lexeme(token)),
+        });
+        let snippet = (token.tokentype == TokenType::Error).then(|| self.source_line(token).to_owned());
+        self.errors.push(CompileError {
+            path: self.sources[token.source].path.display().to_string(),
+            line: token.line,
+            column: token.column,
+            message: message.to_owned(),
+            expected,
+            found,
+            snippet,
+        });
+    }
+
+    // The whole line of source the token starts on, without its line ending.
+    fn source_line(&self, token: Token) -> &str {
+        let text = &self.sources[token.source].text;
+        let is_ending = |c: char| c == '\n' || c == '\r';
+        let start = text[..token.start].rfind(is_ending).map_or(0, |end| end + 1);
+        let end = text[token.start..].find(is_ending).map_or(text.len(), |end| token.start + end);
+        &text[start..end]
+    }
+
+    // Code is emitted after the token it comes from has been consumed, so it
+    // is attributed to the previous token's line.
     pub fn advance(&mut self) {
         self.previous = self.current;
+        self.memory.set_line(self.previous.line);
         loop {
             self.current = self.scanner.scan_token();
-            // println!(
-            //     "Current Token <{}> {:#?}",
-            //     self.get_token_name(),
-            //     &self.current
-            // );
             match self.current.tokentype {
-                TokenType::NewLine => {
-                    self.memory.line_end();
+                TokenType::Eof if !self.includes.is_empty() => {
+                    self.scanner = self.includes.pop().unwrap();
                     continue;
                 }
-                TokenType::WhiteSpace => continue,
+                TokenType::NewLine | TokenType::WhiteSpace => continue,
                 TokenType::Error => {
-                    self.has_error = true;
-                    log_error(&self.scanner.error_msg)
+                    let message = self.scanner.error_msg.clone();
+                    self.error_at(self.current, &message);
                 }
                 _ => break,
             }
         }
     }
 
-    fn grouping(&mut self, can_assign: bool) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
-        self.consume(
-            TokenType::RightParen,
-            &format!("Expect ')' after expression at {}", self.current),
-        );
+        self.consume(TokenType::RightParen, "expect ')' after expression.");
     }
 
-    fn unary(&mut self, can_assign: bool) {
+    fn unary(&mut self, _can_assign: bool) {
         let operator = self.previous.tokentype;
+        let start = self.memory.chunk_size();
 
-        self.parse_precedence(Precedence::Unary.repr());
+        // The operand may contain `**`, so `-2 ** 2` is `-(2 ** 2)`.
+        self.parse_precedence(Precedence::Power.repr());
 
         match operator {
-            TokenType::Minus => self.memory.push(OpCode::Negate),
-            TokenType::Bang => self.memory.push(OpCode::Not),
-            _ => log_error(&format!(
-                "invalid unary operator exptected - or ! at {}",
-                self.current
-            )),
+            TokenType::Minus => self.operator(start, &[OpCode::Negate]),
+            TokenType::Bang => self.operator(start, &[OpCode::Not]),
+            _ => self.error_at(self.previous, "expect '-' or '!'"),
         }
     }
 
@@ -157,10 +452,7 @@ impl Compiler {
         if self.current.tokentype == tokentype {
             self.advance();
         } else {
-            panic!(
-                "Faild to Consume Correct token type {}, {}, current: {} , prev: {}",
-                tokentype, message, self.current, self.previous
-            );
+            self.report(self.current, message.trim_end_matches('.'), Some(tokentype));
         }
     }
 
@@ -191,6 +483,7 @@ impl Compiler {
             TokenType::Plus => self.binary(can_assign),
             TokenType::Slash => self.binary(can_assign),
             TokenType::Star => self.binary(can_assign),
+            TokenType::StarStar => self.binary(can_assign),
             TokenType::BangEqual => self.binary(can_assign),
             TokenType::EqualEqual => self.binary(can_assign),
             TokenType::Greater => self.binary(can_assign),
@@ -200,6 +493,9 @@ impl Compiler {
             TokenType::And => self.and_op(),
             TokenType::Or => self.or_op(),
             TokenType::LeftParen => self.call_func(),
+            TokenType::Dot => self.dot(can_assign),
+            TokenType::LeftBracket => self.index(can_assign),
+            TokenType::PlusPlus | TokenType::MinusMinus => self.postfix_increment(),
             _ => {
                 return None;
             }
@@ -216,8 +512,12 @@ impl Compiler {
             TokenType::True => self.literal(can_assign),
             TokenType::False => self.literal(can_assign),
             TokenType::Bang => self.unary(can_assign),
+            TokenType::PlusPlus | TokenType::MinusMinus => self.prefix_increment(),
             TokenType::String => self.string(can_assign),
             TokenType::Identifier => self.identifier(can_assign),
+            TokenType::LeftBracket => self.array_literal(),
+            TokenType::This => self.this(),
+            TokenType::Super => self.super_method(),
             _ => {
                 return None;
             }
@@ -226,94 +526,245 @@ impl Compiler {
     }
 
     fn call_func(&mut self) {
-        self.memory.push(OpCode::PopStoreTmp);
-
-        self.func_returns += 1;
         let mut args = 0;
-        while !self.match_token(TokenType::RightParen) {
+        while !self.check(TokenType::RightParen) && !self.check(TokenType::Eof) {
             self.expression();
-
-            if self.check(TokenType::Comma) {
-                self.advance();
-            }
             args += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
         }
+        self.consume(TokenType::RightParen, "expect ')' after arguments.");
 
-        self.memory.push(OpCode::SetSP);
+        self.last_call = Some(self.memory.chunk_size());
         self.memory.push(OpCode::Call);
         self.memory.push_raw(args as u16);
-        self.func_returns -= 1;
+        self.last_comparison = None;
     }
 
-    fn identifier(&mut self, can_assign: bool) {
-        let local_var = self.find_local_var();
-        let global_var = self.parse_identifier(self.previous);
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "expect property name after '.'.");
+        let property = self.parse_identifier(self.previous);
         if can_assign && self.match_token(TokenType::Equal) {
             self.expression();
-            if local_var < 0 {
-                self.memory.push_constant(
-                    OpCode::SetGlobalVar,
-                    Value::Object(Object::String(global_var)),
-                )
-            } else {
-                self.memory
-                    .push_constant(OpCode::SetLocalVar, Value::Number(local_var as f64))
+            self.name_constant(OpCode::SetProperty, property);
+        } else {
+            self.name_constant(OpCode::GetProperty, property);
+        }
+    }
+
+    fn array_literal(&mut self) {
+        let mut elements = 0;
+        while !self.check(TokenType::RightBracket) && !self.check(TokenType::Eof) {
+            self.expression();
+            elements += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
             }
+        }
+        self.consume(TokenType::RightBracket, "expect ',' or ']' after array element.");
+
+        self.memory.push(OpCode::BuildArray);
+        self.memory.push_raw(elements as u16);
+    }
+
+    // `a[i]` reads an element; `a[i] = value` stores one and leaves the value.
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "expect ']' after index.");
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.memory.push(OpCode::IndexSet);
         } else {
-            if local_var < 0 {
-                self.memory.push_constant(
-                    OpCode::GetGlobalVar,
-                    Value::Object(Object::String(global_var)),
-                )
-            } else {
-                self.memory
-                    .push_constant(OpCode::GetLocalVar, Value::Number(local_var as f64))
+            self.memory.push(OpCode::IndexGet);
+        }
+        self.last_comparison = None;
+    }
+
+    // The receiver of a method sits in slot zero of its frame.
+    fn this(&mut self) {
+        match self.function_kinds.last() {
+            Some(FunctionKind::Method | FunctionKind::Initializer) => {
+                self.get_variable(Variable::Local(0))
+            }
+            _ => self.error_at(self.previous, "cannot use 'this' outside of a method"),
+        }
+    }
+
+    // `super.name` binds `this` to the method the superclass would find. Which
+    // superclass that is depends on the class defining the running method, so
+    // the VM resolves it from the call frame.
+    fn super_method(&mut self) {
+        let keyword = self.previous;
+        let problem = match (self.classes.last(), self.function_kinds.last()) {
+            (None, _) => Some("outside of a class"),
+            (Some(false), _) => Some("in a class with no superclass"),
+            (_, Some(FunctionKind::Method | FunctionKind::Initializer)) => None,
+            _ => Some("outside of a method"),
+        };
+        if let Some(problem) = problem {
+            self.error_at(keyword, &format!("cannot use 'super' {}", problem));
+        }
+        self.consume(TokenType::Dot, "expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "expect superclass method name.");
+        let name = self.parse_identifier(self.previous);
+        self.get_variable(Variable::Local(0));
+        self.name_constant(OpCode::GetSuper, name);
+    }
+
+    fn identifier(&mut self, can_assign: bool) {
+        let name = self.previous;
+        let variable = self.resolve_variable();
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.check_assignable(name);
+            self.expression();
+            self.set_variable(variable);
+        } else {
+            self.get_variable(variable);
+            self.last_variable = Some((variable, name));
+        }
+    }
+
+    fn resolve_variable(&mut self) -> Variable {
+        let name = self.parse_identifier(self.previous);
+        if let Some(index) = self.local_index(name, self.func_returns) {
+            return Variable::Local(index - self.frame_start(self.func_returns));
+        }
+        match self.resolve_upvalue(name, self.func_returns) {
+            Some(index) => Variable::Upvalue(index),
+            None => Variable::Global(name),
+        }
+    }
+
+    // Finds the innermost local called `name` declared by the function
+    // `func_depth` levels deep, as an index into `locals`.
+    fn local_index(&self, name: Symbol, func_depth: usize) -> Option<usize> {
+        self.locals
+            .iter()
+            .rposition(|local| local.name == name && local.func_depth == func_depth)
+    }
+
+    // Resolves `name` in the functions enclosing the one `func_depth` levels
+    // deep, threading it through each function in between as an upvalue.
+    fn resolve_upvalue(&mut self, name: Symbol, func_depth: usize) -> Option<usize> {
+        if func_depth == 0 {
+            return None;
+        }
+        if let Some(index) = self.local_index(name, func_depth - 1) {
+            self.locals[index].captured = true;
+            let slot = index - self.frame_start(func_depth - 1);
+            return Some(self.add_upvalue(func_depth, slot, true));
+        }
+        let index = self.resolve_upvalue(name, func_depth - 1)?;
+        Some(self.add_upvalue(func_depth, index, false))
+    }
+
+    fn add_upvalue(&mut self, func_depth: usize, index: usize, is_local: bool) -> usize {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.upvalues[func_depth - 1];
+        match upvalues.iter().position(|existing| *existing == upvalue) {
+            Some(position) => position,
+            None => {
+                upvalues.push(upvalue);
+                upvalues.len() - 1
             }
         }
     }
 
-    fn find_local_var(&mut self) -> isize {
-        if self.locals.len() == 0 {
-            return -1;
+    fn get_variable(&mut self, variable: Variable) {
+        match variable {
+            Variable::Local(slot) => {
+                self.emit_constant(OpCode::GetLocalVar, Value::Number(slot as f64));
+            }
+            Variable::Upvalue(index) => {
+                self.memory.push(OpCode::GetUpvalue);
+                self.memory.push_raw(index as u16);
+            }
+            Variable::Global(name) => self.global(OpCode::GetGlobalSlot, name),
         }
-        for i in (0..self.locals.len()).rev() {
-            if self.parse_identifier(self.locals[i].name) == self.parse_identifier(self.previous) {
-                return (self.locals.len() - i).try_into().unwrap();
+    }
+
+    fn set_variable(&mut self, variable: Variable) {
+        match variable {
+            Variable::Local(slot) => {
+                self.emit_constant(OpCode::SetLocalVar, Value::Number(slot as f64));
             }
+            Variable::Upvalue(index) => {
+                self.memory.push(OpCode::SetUpvalue);
+                self.memory.push_raw(index as u16);
+            }
+            Variable::Global(name) => self.global(OpCode::SetGlobalSlot, name),
         }
-        -1
+    }
+
+    // Emits `variable = variable + 1` (or `- 1`), leaving the new value.
+    fn step_variable(&mut self, variable: Variable, operator: TokenType) {
+        self.get_variable(variable);
+        self.emit_constant(OpCode::Constant, Value::Number(1.0));
+        match operator {
+            TokenType::PlusPlus => self.memory.push(OpCode::Add),
+            _ => self.memory.push(OpCode::Subtract),
+        }
+        self.set_variable(variable);
+    }
+
+    fn prefix_increment(&mut self) {
+        let operator = self.previous;
+        if !self.match_token(TokenType::Identifier) {
+            let message = format!("'{}' can only be applied to a variable", self.lexeme(operator));
+            self.error_at(operator, &message);
+        }
+        let name = self.previous;
+        let variable = self.resolve_variable();
+        if name.tokentype == TokenType::Identifier {
+            self.check_assignable(name);
+        }
+        self.step_variable(variable, operator.tokentype);
+    }
+
+    // The variable's old value is already on the stack and stays there as
+    // the result.
+    fn postfix_increment(&mut self) {
+        let operator = self.previous;
+        let Some((variable, name)) = self.last_variable else {
+            let message = format!("'{}' can only be applied to a variable", self.lexeme(operator));
+            self.error_at(operator, &message);
+            return;
+        };
+        self.check_assignable(name);
+        self.step_variable(variable, operator.tokentype);
+        self.memory.push(OpCode::Pop);
+    }
+
+    // The index in `locals` of slot zero of the function `func_depth` levels
+    // deep. Slots count from the frame base, which is the callee inside a
+    // function and the bottom of the stack at the top level.
+    fn frame_start(&self, func_depth: usize) -> usize {
+        self.locals
+            .iter()
+            .position(|local| local.func_depth == func_depth)
+            .unwrap_or(self.locals.len())
     }
 
     fn parse_precedence(&mut self, precedence: u16) {
-        // print!(
-        //     "Starting preced at <{}> {:#?}",
-        //     self.get_token_name(),
-        //     self.current
-        // );
         self.advance();
         let can_assign = precedence <= Precedence::Assignment.repr();
+        self.last_variable = None;
+        let start = self.memory.chunk_size();
         match self.prefix(can_assign) {
             Some(_) => (),
-            None => log_error(&format!(
-                "Error at token {} not usable as prefix",
-                self.previous
-            )),
+            None => self.error_at(self.previous, "expect expression"),
         }
+        self.last_comparison = None;
         while precedence <= self.get_rule(self.current.tokentype).repr() {
             self.advance();
+            self.operand_start = start;
             self.infix(can_assign);
+            self.last_variable = None;
         }
         if can_assign && self.match_token(TokenType::Equal) {
-            panic!(
-                "Invalid assignment at current: {:#?}, prev: {:#?}",
-                self.current, self.previous
-            )
+            self.error_at(self.previous, "invalid assignment target")
         }
-        // print!(
-        //     "Ending preced at <{}> {:#?}",
-        //     self.get_token_name(),
-        //     self.current
-        // );
     }
 
     fn get_rule(&self, op: TokenType) -> Precedence {
@@ -322,6 +773,7 @@ impl Compiler {
             TokenType::Plus => Precedence::Term,
             TokenType::Slash => Precedence::Factor,
             TokenType::Star => Precedence::Factor,
+            TokenType::StarStar => Precedence::Power,
             TokenType::BangEqual => Precedence::Equality,
             TokenType::EqualEqual => Precedence::Equality,
             TokenType::Greater => Precedence::Comparison,
@@ -331,56 +783,127 @@ impl Compiler {
             TokenType::And => Precedence::And,
             TokenType::Or => Precedence::Or,
             TokenType::LeftParen => Precedence::Call,
+            TokenType::Dot => Precedence::Call,
+            TokenType::LeftBracket => Precedence::Call,
+            TokenType::PlusPlus => Precedence::Call,
+            TokenType::MinusMinus => Precedence::Call,
             _ => Precedence::None,
         }
     }
 
-    fn binary(&mut self, can_assign: bool) {
+    fn binary(&mut self, _can_assign: bool) {
         let operator = self.previous.tokentype;
+        let operator_token = self.previous;
+        let start = self.operand_start;
+        let left_comparison = self.last_comparison;
         let precendence = self.get_rule(operator);
-        self.parse_precedence(precendence.repr() + 1);
-
+        // `**` is right-associative, so its right operand may be another `**`.
         match operator {
-            TokenType::Plus => self.memory.push(OpCode::Add),
-            TokenType::Minus => self.memory.push(OpCode::Subtract),
-            TokenType::Star => self.memory.push(OpCode::Multiply),
-            TokenType::Slash => self.memory.push(OpCode::Divide),
-            TokenType::BangEqual => {
-                self.memory.push(OpCode::Equal);
-                self.memory.push(OpCode::Not)
-            }
-            TokenType::EqualEqual => self.memory.push(OpCode::Equal),
-            TokenType::Greater => self.memory.push(OpCode::Greater),
-            TokenType::GreaterEqual => {
-                self.memory.push(OpCode::Less);
-                self.memory.push(OpCode::Not)
+            TokenType::StarStar => self.parse_precedence(precendence.repr()),
+            _ => self.parse_precedence(precendence.repr() + 1),
+        }
+
+        let comparison = matches!(
+            precendence,
+            Precedence::Comparison | Precedence::Equality
+        );
+        if comparison && left_comparison.is_some() {
+            self.error_at(operator_token, "comparisons cannot be chained");
+        }
+        self.last_comparison = comparison.then_some(operator_token);
+
+        let opcodes: &[OpCode] = match operator {
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Subtract],
+            TokenType::Star => &[OpCode::Multiply],
+            TokenType::Slash => &[OpCode::Divide],
+            TokenType::StarStar => &[OpCode::Power],
+            TokenType::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenType::EqualEqual => &[OpCode::Equal],
+            TokenType::Greater => &[OpCode::Greater],
+            TokenType::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            TokenType::Less => &[OpCode::Less],
+            TokenType::LessEqual => &[OpCode::Greater, OpCode::Not],
+            _ => return self.error_at(self.previous, "expect a binary operator"),
+        };
+        self.operator(start, opcodes);
+    }
+
+    // Emits an operator's instructions, whose operands' code starts at
+    // `start`. When that code only pushes literals, it is replaced by the
+    // single value the operator would leave.
+    fn operator(&mut self, start: usize, opcodes: &[OpCode]) {
+        match self.folded(start, opcodes) {
+            Some(value) => {
+                self.memory.truncate(start);
+                match value {
+                    Value::Nil => self.memory.push(OpCode::Nil),
+                    Value::Bool(true) => self.memory.push(OpCode::True),
+                    Value::Bool(false) => self.memory.push(OpCode::False),
+                    value => {
+                        self.emit_constant(OpCode::Constant, value);
+                    }
+                }
             }
-            TokenType::Less => self.memory.push(OpCode::Less),
-            TokenType::LessEqual => {
-                self.memory.push(OpCode::Greater);
-                self.memory.push(OpCode::Not)
+            None => {
+                for opcode in opcodes {
+                    self.memory.push(*opcode);
+                }
             }
-            _ => log_error(&format!("invalid binary operator at {}", self.current)),
         }
     }
 
-    fn literal(&mut self, can_assign: bool) {
+    // The value left by running `opcodes` after the code from `start` on, if
+    // that code is nothing but literals and none of it can fail.
+    fn folded(&self, start: usize, opcodes: &[OpCode]) -> Option<Value> {
+        if !self.fold {
+            return None;
+        }
+        let mut stack = self.literals(start)?;
+        for opcode in opcodes {
+            fold(*opcode, &mut stack)?;
+        }
+        match stack.len() {
+            1 => stack.pop(),
+            _ => None,
+        }
+    }
+
+    // The values the code from `start` on pushes, if it does nothing else.
+    fn literals(&self, start: usize) -> Option<Vec<Value>> {
+        let mut stack = Vec::new();
+        let mut offset = start;
+        while offset < self.memory.chunk_size() {
+            let instruction = self.memory.current().instruction_at(offset)?;
+            stack.push(match instruction.opcode {
+                OpCode::Constant | OpCode::ConstantLong => {
+                    match self.memory.get_constant(instruction.operand?)? {
+                        value @ (Value::Number(_) | Value::Object(Object::String(_))) => value,
+                        _ => return None,
+                    }
+                }
+                OpCode::Nil => Value::Nil,
+                OpCode::True => Value::Bool(true),
+                OpCode::False => Value::Bool(false),
+                _ => return None,
+            });
+            offset += instruction.size();
+        }
+        Some(stack)
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
         match self.previous.tokentype {
             TokenType::Nil => self.memory.push(OpCode::Nil),
             TokenType::True => self.memory.push(OpCode::True),
             TokenType::False => self.memory.push(OpCode::False),
-            _ => panic!("Invalid literal type {:#?}", self.previous),
+            _ => self.error_at(self.previous, "expect a literal"),
         }
     }
 
-    fn string(&mut self, can_assign: bool) {
-        let current_string: String = self
-            .source
-            .chars()
-            .skip(self.previous.start + 1)
-            .take(self.previous.length - 2)
-            .collect();
-        self.memory.push_constant(
+    fn string(&mut self, _can_assign: bool) {
+        let current_string = self.string_contents(self.previous).into();
+        self.emit_constant(
             OpCode::Constant,
             Value::Object(Object::String(current_string)),
         );
@@ -401,9 +924,61 @@ impl Compiler {
     fn declaration(&mut self) {
         if self.match_token(TokenType::Var) {
             self.var_declaration()
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration()
         } else {
             self.statement()
         }
+        if self.panic_mode {
+            self.synchronize();
+        }
+        // After a return there is no frame left to check.
+        if !self.unreachable {
+            self.assert_stack();
+        }
+    }
+
+    // Skips the rest of a statement that failed to compile, stopping after a
+    // `;` or before a token that starts a statement or ends a block, so the
+    // following statements are still checked.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while self.current.tokentype != TokenType::Eof {
+            if self.previous.tokentype == TokenType::SemiColon {
+                return;
+            }
+            match self.current.tokentype {
+                TokenType::Var
+                | TokenType::Const
+                | TokenType::Fun
+                | TokenType::Class
+                | TokenType::Test
+                | TokenType::Include
+                | TokenType::Import
+                | TokenType::For
+                | TokenType::If
+                | TokenType::Switch
+                | TokenType::Case
+                | TokenType::Default
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Assert
+                | TokenType::Return
+                | TokenType::RightBrace => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    // Debug builds check after every statement that the current frame holds
+    // exactly its locals in scope, catching compiler/VM disagreements early.
+    fn assert_stack(&mut self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let slots = self.locals.len() - self.frame_start(self.func_returns);
+        self.memory.push(OpCode::AssertStack);
+        self.memory.push_raw(slots as u16);
     }
 
     fn var_declaration(&mut self) {
@@ -421,87 +996,210 @@ impl Compiler {
 
         if self.scope_depth > 0 {
             self.local_var(local_var);
-            self.memory.push(OpCode::SetSP);
             return;
         }
 
-        self.memory.push_constant(
-            OpCode::DefineGlobalVar,
-            Value::Object(Object::String(global_var)),
-        )
+        self.define_global(local_var, global_var)
     }
 
-    fn param_declaration(&mut self) {
+    // Like `var`, but the value must be given and can never be assigned to
+    // again. An inner `var` of the same name may still shadow it.
+    fn const_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "expect identifier after const.");
+        let name = self.previous;
+        self.consume(TokenType::Equal, "expect '=' after constant name.");
+        self.expression();
+        self.consume(TokenType::SemiColon, "expect ';' after value.");
+
+        if self.scope_depth > 0 {
+            self.local_var(name);
+            if let Some(local) = self.locals.last_mut() {
+                local.constant = true;
+            }
+            return;
+        }
+
+        let global = self.parse_identifier(name);
+        self.define_global(name, global);
+        let text = self.interner.resolve(global).to_owned();
+        self.memory.declare_constant_global(&text);
+    }
+
+    // Defines a global by the declaration at `name`, which may not replace a
+    // constant declared before it.
+    fn define_global(&mut self, name: Token, global: Symbol) {
+        if self.memory.is_constant_global(self.interner.resolve(global)) {
+            let message = format!("cannot redeclare constant '{}'", self.lexeme(name));
+            self.error_at(name, &message);
+        }
+        self.global(OpCode::DefineGlobalSlot, global)
+    }
+
+    // Reports an assignment to the variable named by `name` if it was
+    // declared with `const`, looking it up the way `resolve_variable` does.
+    fn check_assignable(&mut self, name: Token) {
+        let symbol = self.parse_identifier(name);
+        let constant = match (0..=self.func_returns).rev().find_map(|depth| self.local_index(symbol, depth)) {
+            Some(index) => self.locals[index].constant,
+            None => {
+                let text = self.interner.resolve(symbol);
+                self.memory.is_constant_global(text) || self.constant_names.contains(&symbol)
+            }
+        };
+        if constant {
+            let message = format!("cannot assign to constant '{}'", self.lexeme(name));
+            self.error_at(name, &message);
+        }
+    }
+
+    // Declares the parameter in slot `slot`. A default value is compiled into
+    // the start of the body and only runs when the caller left the argument
+    // out, so it may use the parameters before it. Returns whether there was
+    // one.
+    fn param_declaration(&mut self, slot: usize) -> bool {
         self.consume(TokenType::Identifier, "expect identifier after (.");
         let local_var = self.previous;
 
         self.local_var(local_var);
 
-        if self.check(TokenType::Comma) {
-            self.advance();
+        let has_default = self.match_token(TokenType::Equal);
+        if has_default {
+            self.memory.push(OpCode::ArgMissing);
+            self.memory.push_raw(slot as u16);
+            let supplied = self.push_jmp(OpCode::JmpFalse);
+            self.memory.push(OpCode::Pop);
+            self.expression();
+            self.set_variable(Variable::Local(slot));
+            self.memory.push(OpCode::Pop);
+            let end = self.push_jmp(OpCode::Jmp);
+            self.patch_address(supplied);
+            self.memory.push(OpCode::Pop);
+            self.patch_address(end);
         }
+        has_default
     }
 
-    fn func_address_declar(&mut self) -> usize {
-        self.consume(TokenType::Identifier, "expect identifier after function.");
-        let local_var = self.previous;
-        let global_var = self.parse_identifier(self.previous);
-        let func_address = self.memory.get_memory_size() + 6;
-
-        self.func_returns += 1;
-        self.begin_scope();
-        let mut arity = 0;
+    // Opens the function's frame, pushes the function value and opens the
+    // chunk its body compiles into. The parameters are declared after that,
+    // as their defaults belong to the body; the arity is filled into the
+    // function value once they are all known.
+    fn function_header(&mut self, name: Symbol, kind: FunctionKind, hoisted: Option<u32>) {
+        self.begin_function(kind);
         self.consume(
             TokenType::LeftParen,
             "expect '(' after 'function identifier'.",
         );
-        while !self.match_token(TokenType::RightParen) {
-            self.param_declaration();
-            arity += 1;
-        }
 
-        self.memory.push_constant(
-            OpCode::Constant,
-            Value::Object(Object::Function {
-                name: global_var.clone(),
-                address: func_address,
+        let name = self.interner.resolve(name).to_owned();
+        let function = |chunk, arity, min_arity| {
+            Value::Object(Object::Function(Rc::new(Function {
+                name: name.clone(),
+                chunk,
                 arity,
-            }),
-        );
+                min_arity,
+            })))
+        };
+        let constant = match hoisted {
+            Some(constant) => {
+                let emitted = self.memory.push_constant_operand(OpCode::Constant, constant);
+                self.emitted(emitted);
+                constant
+            }
+            None => self.emit_constant(OpCode::Constant, function(0, 0, 0)),
+        };
+        let chunk = self.begin_body(name.clone());
 
-        if self.scope_depth - 1 > 0 {
-            self.local_var(local_var);
-        } else {
-            self.memory.push_constant(
-                OpCode::DefineGlobalVar,
-                Value::Object(Object::String(global_var)),
-            )
+        let mut arity = 0;
+        let mut min_arity = None;
+        while !self.check(TokenType::RightParen) && !self.check(TokenType::Eof) {
+            let param = self.current;
+            arity += 1;
+            if self.param_declaration(arity) {
+                min_arity.get_or_insert(arity - 1);
+            } else if min_arity.is_some() {
+                let message = format!(
+                    "parameter '{}' needs a default as an earlier one has one",
+                    self.lexeme(param)
+                );
+                self.error_at(param, &message);
+            }
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
         }
+        self.consume(TokenType::RightParen, "expect ')' after parameters.");
+        self.memory
+            .replace_constant(constant, function(chunk, arity, min_arity.unwrap_or(arity)));
+    }
 
-        self.push_jmp(OpCode::Jmp)
+    // Opens the scope of a function body. Slot zero of every call frame holds
+    // the callee itself, so it is reserved under a name no identifier can have.
+    fn begin_function(&mut self, kind: FunctionKind) {
+        self.function_kinds.push(kind);
+        self.upvalues.push(Vec::new());
+        self.func_returns += 1;
+        self.unreachable = false;
+        self.begin_scope();
+        let callee = self.interner.intern("");
+        self.locals.push(Local {
+            name: callee,
+            depth: self.scope_depth,
+            func_depth: self.func_returns,
+            captured: false,
+            constant: false,
+        });
     }
 
+    // Declares a local in the innermost scope. A name may shadow one from an
+    // outer scope but not one declared in the same scope, parameters
+    // included.
     fn local_var(&mut self, name: Token) {
         if self.scope_depth == 0 {
             return;
         }
 
+        let token = name;
+        let name = self.parse_identifier(token);
+        let declared = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == self.scope_depth && local.func_depth == self.func_returns)
+            .any(|local| local.name == name);
+        if declared {
+            let message = format!("variable '{}' already declared in this scope", self.lexeme(token));
+            self.error_at(token, &message);
+        }
         self.locals.push(Local {
-            name: name,
+            name,
             depth: self.scope_depth,
             func_depth: self.func_returns,
+            captured: false,
+            constant: false,
         });
     }
 
     fn statement(&mut self) {
         if self.match_token(TokenType::Print) {
             self.print_statement();
+        } else if self.match_token(TokenType::Assert) {
+            self.assert_statement();
         } else if self.match_token(TokenType::Fun) {
             self.function();
+        } else if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Test) {
+            self.test_declaration();
+        } else if self.match_token(TokenType::Include) {
+            self.include_directive();
+        } else if self.match_token(TokenType::Import) {
+            self.import_directive();
         } else if self.match_token(TokenType::For) {
             self.for_statement();
         } else if self.match_token(TokenType::If) {
             self.if_statement();
+        } else if self.match_token(TokenType::Switch) {
+            self.switch_statement();
         } else if self.match_token(TokenType::While) {
             self.while_statement();
         } else if self.match_token(TokenType::Return) {
@@ -509,52 +1207,317 @@ impl Compiler {
         } else if self.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
-            self.end_scope();
+            // After a return the frame is gone, locals and all.
+            if self.unreachable {
+                self.skip(Self::end_scope);
+            } else {
+                self.end_scope();
+            }
         } else {
             self.expression_statement();
         }
     }
 
+    // Return pops the result and drops the whole frame, locals included.
     fn return_statement(&mut self) {
+        let keyword = self.previous;
+        let initializer = self.function_kinds.last() == Some(&FunctionKind::Initializer);
         if self.match_token(TokenType::SemiColon) {
-            self.return_scope();
-            self.memory.push(OpCode::Return);
+            if initializer {
+                self.get_variable(Variable::Local(0));
+            } else {
+                self.memory.push(OpCode::Nil);
+            }
+        } else if initializer {
+            self.error_at(keyword, "cannot return a value from an initializer");
         } else {
             self.expression();
-            self.memory.push(OpCode::PopStoreTmp);
             self.consume(TokenType::SemiColon, "expected ; after return value");
-            self.return_scope();
-            self.memory.push(OpCode::Return);
+            // A call that is the last thing before the Return gives the return
+            // value itself, so its frame can replace this one.
+            if let Some(call) = self.last_call.filter(|call| call + 2 == self.memory.chunk_size()) {
+                self.memory.replace_at_location(call, OpCode::TailCall.repr());
+            }
         }
+        self.memory.push(OpCode::Return);
+        self.unreachable = true;
     }
 
+    // The function value is pushed and the body compiled into a chunk of its
+    // own; once it is done its captures are known, and the value is bound to
+    // its name.
     fn function(&mut self) {
-        let func_end = self.func_address_declar();
+        self.functions += 1;
+        self.consume(TokenType::Identifier, "expect identifier after function.");
+        let local_var = self.previous;
+        let global_var = self.parse_identifier(self.previous);
+        let is_local = self.scope_depth > 0;
+        // The function value lives in the enclosing frame, so its name is
+        // declared there before the parameters are.
+        if is_local {
+            self.local_var(local_var);
+        }
+
+        let hoisted = if is_local { None } else { self.hoisted.remove(&global_var) };
+        self.function_header(global_var, FunctionKind::Function, hoisted);
         self.consume(
             TokenType::LeftBrace,
             "expect '{' after 'function parameters'.",
         );
 
         self.block();
-        self.end_scope();
-        self.memory.push(OpCode::Return);
+        let upvalues = self.end_function();
+        self.end_body();
+        self.closure(&upvalues);
+
+        if !is_local {
+            self.global(OpCode::DefineGlobalSlot, global_var)
+        }
+    }
+
+    // Turns the function value on the stack into a closure over `upvalues`.
+    // Each capture follows Closure as an instruction of its own.
+    fn closure(&mut self, upvalues: &[Upvalue]) {
+        if upvalues.is_empty() {
+            return;
+        }
+        self.memory.push(OpCode::Closure);
+        self.memory.push_raw(upvalues.len() as u16);
+        for upvalue in upvalues {
+            if upvalue.is_local {
+                self.memory.push(OpCode::CaptureLocal);
+            } else {
+                self.memory.push(OpCode::CaptureUpvalue);
+            }
+            self.memory.push_raw(upvalue.index as u16);
+        }
+    }
 
-        self.patch_address(func_end);
+    // Closes a function body with its implicit return: the instance for an
+    // initializer and nil for anything else. Return drops the whole frame, so
+    // the body's locals need no Pops of their own. Gives back the variables
+    // the function captured.
+    fn end_function(&mut self) -> Vec<Upvalue> {
+        self.scope_depth -= 1;
+        let frame_start = self.frame_start(self.func_returns);
+        self.locals.truncate(frame_start);
+        match self.function_kinds.pop() {
+            Some(FunctionKind::Initializer) => self.get_variable(Variable::Local(0)),
+            _ => self.memory.push(OpCode::Nil),
+        }
+        self.memory.push(OpCode::Return);
         self.func_returns -= 1;
+        self.unreachable = false;
+        self.upvalues.pop().unwrap_or_default()
+    }
+
+    // `class Name < Super { method(params) { ... } ... }` makes the class,
+    // binds its name, then reloads it to link the superclass and attach each
+    // method in turn.
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "expect class name.");
+        let class_token = self.previous;
+        let name = self.parse_identifier(class_token);
+        self.name_constant(OpCode::Class, name);
+        let class = if self.scope_depth > 0 {
+            self.local_var(class_token);
+            Variable::Local(self.locals.len() - 1 - self.frame_start(self.func_returns))
+        } else {
+            self.global(OpCode::DefineGlobalSlot, name);
+            Variable::Global(name)
+        };
+
+        self.get_variable(class);
+        let has_superclass = self.match_token(TokenType::Less);
+        if has_superclass {
+            self.consume(TokenType::Identifier, "expect superclass name after '<'.");
+            if self.lexeme(self.previous) == self.lexeme(class_token) {
+                let message = format!("class '{}' cannot inherit from itself", self.lexeme(class_token));
+                self.error_at(self.previous, &message);
+            }
+            let superclass = self.resolve_variable();
+            self.get_variable(superclass);
+            self.memory.push(OpCode::Inherit);
+        }
+
+        self.classes.push(has_superclass);
+        self.consume(TokenType::LeftBrace, "expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "expect '}' after class body.");
+        self.memory.push(OpCode::Pop);
+        self.classes.pop();
+    }
+
+    fn method(&mut self) {
+        self.functions += 1;
+        self.consume(TokenType::Identifier, "expect method name.");
+        let name = self.parse_identifier(self.previous);
+        let kind = match self.lexeme(self.previous) {
+            "init" => FunctionKind::Initializer,
+            _ => FunctionKind::Method,
+        };
+        self.function_header(name, kind, None);
+        self.consume(TokenType::LeftBrace, "expect '{' before method body.");
+        self.block();
+        let upvalues = self.end_function();
+        self.end_body();
+        self.closure(&upvalues);
+        self.name_constant(OpCode::Method, name);
+    }
+
+    // Tests are run on their own rather than called as values, so there is
+    // no closure to hold captured locals.
+    fn test_declaration(&mut self) {
+        self.consume(TokenType::String, "expect test name after 'test'.");
+        let test_token = self.previous;
+        let name = self.string_contents(self.previous).to_owned();
+        let test_name = format!("test {}", self.lexeme(test_token));
+        let chunk = self.begin_body(test_name);
+        self.memory.register_test(name, chunk);
+
+        self.begin_function(FunctionKind::Function);
+        self.consume(TokenType::LeftBrace, "expect '{' after test name.");
+        self.block();
+        if !self.end_function().is_empty() {
+            let message = format!("test {} cannot use locals declared outside it", self.lexeme(test_token));
+            self.error_at(test_token, &message);
+        }
+        self.end_body();
+    }
+
+    // Opens the chunk a body called `name` compiles into, which stack traces
+    // show it by, and returns its index. A call compiled before it is in
+    // another chunk, so it cannot become a tail call.
+    fn begin_body(&mut self, name: String) -> usize {
+        self.last_call = None;
+        self.memory.begin_chunk(name)
+    }
+
+    // Goes back to compiling into the enclosing chunk once a body is done.
+    fn end_body(&mut self) {
+        self.last_call = None;
+        self.memory.end_chunk();
+    }
+
+    // Splices the tokens of another file into the stream as if its text were
+    // pasted here. Including a file that is already being included is a cycle,
+    // and including the same file twice is rejected rather than silently
+    // compiling it again.
+    fn include_directive(&mut self) {
+        self.consume(TokenType::String, "expect file name after 'include'.");
+        let directive = self.previous;
+        let Some(path) = self.directive_path(directive, "include") else {
+            self.consume(TokenType::SemiColon, "expect ';' after include.");
+            return;
+        };
+        if self.reject_cycle(directive, &path, "include") {
+            self.consume(TokenType::SemiColon, "expect ';' after include.");
+            return;
+        }
+        if self.sources.iter().any(|source| same_file(source, &path)) {
+            self.error_at(directive, &format!("'{}' is already included", path.display()));
+            self.consume(TokenType::SemiColon, "expect ';' after include.");
+            return;
+        }
+        self.splice(directive, path, "include");
+    }
+
+    // Compiles another file's declarations in place the first time it is
+    // imported; importing it again, directly or through another file, does
+    // nothing. Only the top level may import, so the file's variables are
+    // always globals.
+    fn import_directive(&mut self) {
+        self.consume(TokenType::String, "expect file name after 'import'.");
+        let directive = self.previous;
+        if self.scope_depth > 0 || !self.function_kinds.is_empty() {
+            self.error_at(directive, "import is only allowed at the top level");
+        }
+        let path = self.directive_path(directive, "import");
+        let path = path.filter(|path| !self.reject_cycle(directive, path, "import"));
+        let Some(path) = path else {
+            self.consume(TokenType::SemiColon, "expect ';' after import.");
+            return;
+        };
+        if self.sources.iter().any(|source| same_file(source, &path)) {
+            self.consume(TokenType::SemiColon, "expect ';' after import.");
+            return;
+        }
+        self.splice(directive, path, "import");
+    }
+
+    // The file a directive names, resolved against the file it appears in.
+    // The path is kept as it is written from the root script's directory, so
+    // messages show every file the same way.
+    fn directive_path(&mut self, directive: Token, keyword: &str) -> Option<PathBuf> {
+        // The missing file name has already been reported.
+        if directive.tokentype != TokenType::String {
+            return None;
+        }
+        let name = self.string_contents(directive);
+        let including = &self.sources[directive.source].path;
+        let path = including.parent().unwrap_or(Path::new("")).join(name);
+        match fs::metadata(&path) {
+            Ok(_) => Some(path),
+            Err(e) => {
+                let message = format!("cannot {} '{}': {}", keyword, path.display(), e);
+                self.error_at(directive, &message);
+                None
+            }
+        }
+    }
+
+    // A file that is still being read further up the chain of directives
+    // would never finish. Reports the cycle and returns whether there is one.
+    fn reject_cycle(&mut self, directive: Token, path: &Path, keyword: &str) -> bool {
+        let mut chain: Vec<usize> = self.includes.iter().map(|scanner| scanner.source_id()).collect();
+        chain.push(directive.source);
+        if chain.iter().any(|id| same_file(&self.sources[*id], path)) {
+            let chain_names: Vec<String> = chain
+                .iter()
+                .map(|id| self.sources[*id].path.display().to_string())
+                .collect();
+            let message = format!("{} cycle: {} -> {}", keyword, chain_names.join(" -> "), path.display());
+            self.error_at(directive, &message);
+            return true;
+        }
+        false
+    }
+
+    fn splice(&mut self, directive: Token, path: PathBuf, keyword: &str) {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                let message = format!("cannot {} '{}': {}", keyword, path.display(), e);
+                self.error_at(directive, &message);
+                self.consume(TokenType::SemiColon, &format!("expect ';' after {}.", keyword));
+                return;
+            }
+        };
+        let text: Rc<str> = Rc::from(text);
+        self.sources.push(Source { path, text: text.clone() });
+
+        // The ';' is already the lookahead token, so consuming it reads the
+        // first token of the spliced file; its Eof resumes the parent.
+        let scanner = Scanner::new(text).with_source_id(self.sources.len() - 1);
+        let parent = std::mem::replace(&mut self.scanner, scanner);
+        self.includes.push(parent);
+        self.consume(TokenType::SemiColon, &format!("expect ';' after {}.", keyword));
     }
 
     fn for_statement(&mut self) {
         self.begin_scope();
         self.consume(TokenType::LeftParen, "expect '(' after 'for'.");
         if self.match_token(TokenType::SemiColon) {
-            self.consume(TokenType::SemiColon, "expect ';' after for")
+            // No initializer.
         } else if self.match_token(TokenType::Var) {
             self.var_declaration()
         } else {
             self.expression_statement()
         }
 
-        let mut loop_start = self.memory.get_memory_size();
+        let mut loop_start = self.memory.chunk_size();
         let mut exit_jmp: isize = -1;
         if !self.match_token(TokenType::SemiColon) {
             self.expression();
@@ -566,7 +1529,7 @@ impl Compiler {
 
         if !self.match_token(TokenType::RightParen) {
             let body_jmp = self.push_jmp(OpCode::Jmp);
-            let steps = self.memory.get_memory_size();
+            let steps = self.memory.chunk_size();
 
             self.expression();
             self.memory.push(OpCode::Pop);
@@ -585,28 +1548,123 @@ impl Compiler {
             self.memory.push(OpCode::Pop);
         }
 
-        self.end_scope()
+        self.end_scope();
+        self.unreachable = false;
     }
 
+    // A condition that is a single literal, as `false` or a folded `1 > 2`
+    // is, decides the branch at compile time: there are no jumps, and the
+    // branch that cannot run is compiled only to be dropped.
     fn if_statement(&mut self) {
         self.consume(TokenType::LeftParen, "expect '(' after 'if'.");
+        let condition = self.memory.chunk_size();
         self.expression();
         self.consume(TokenType::RightParen, "expect ')' after condition.");
-        let thn_address = self.push_jmp(OpCode::JmpFalse);
-        self.memory.push(OpCode::Pop);
-        self.statement();
-        let else_address = self.push_jmp(OpCode::Jmp);
-        self.patch_address(thn_address);
-        self.memory.push(OpCode::Pop);
-        if self.match_token(TokenType::Else) {
+        let known = match self.literals(condition).as_deref() {
+            Some([value]) => Some(!value.is_falsey()),
+            _ => None,
+        };
+
+        let thn_address = match known {
+            Some(_) => {
+                self.discard(condition);
+                None
+            }
+            None => {
+                let jump = self.push_jmp(OpCode::JmpFalse);
+                self.memory.push(OpCode::Pop);
+                Some(jump)
+            }
+        };
+        self.branch(known != Some(false));
+        let then_returns = std::mem::take(&mut self.unreachable);
+
+        let else_address = thn_address.map(|thn_address| {
+            let jump = self.push_jmp(OpCode::Jmp);
+            self.patch_address(thn_address);
+            self.memory.push(OpCode::Pop);
+            jump
+        });
+        let else_returns = self.match_token(TokenType::Else) && {
+            self.branch(known != Some(true));
+            self.unreachable
+        };
+        if let Some(else_address) = else_address {
+            self.patch_address(else_address);
+        }
+        self.unreachable = match known {
+            Some(true) => then_returns,
+            Some(false) => else_returns,
+            None => then_returns && else_returns,
+        };
+    }
+
+    fn branch(&mut self, reachable: bool) {
+        if reachable {
             self.statement();
+        } else {
+            self.warn_at(self.current, "unreachable code");
+            self.skip(Self::statement);
+        }
+    }
+
+    // Each case compares a copy of the subject and skips to the next case when
+    // it differs. A matching case drops the subject before its body, so the
+    // body's locals sit where the block expects them, then jumps to the end.
+    fn switch_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "expect '(' after 'switch'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "expect ')' after switch subject.");
+        self.consume(TokenType::LeftBrace, "expect '{' before switch cases.");
+
+        let mut end_jumps = Vec::<usize>::new();
+        while self.match_token(TokenType::Case) {
+            self.memory.push(OpCode::Dup);
+            self.expression();
+            self.consume(TokenType::Colon, "expect ':' after case value.");
+            self.memory.push(OpCode::Equal);
+            let next_case = self.push_jmp(OpCode::JmpFalse);
+            self.memory.push(OpCode::Pop);
+            self.memory.push(OpCode::Pop);
+            self.case_body();
+            end_jumps.push(self.push_jmp(OpCode::Jmp));
+            self.patch_address(next_case);
+            self.memory.push(OpCode::Pop);
+        }
+
+        self.memory.push(OpCode::Pop);
+        if self.match_token(TokenType::Default) {
+            let default = self.previous;
+            self.consume(TokenType::Colon, "expect ':' after 'default'.");
+            self.case_body();
+            if self.check(TokenType::Case) || self.check(TokenType::Default) {
+                self.error_at(default, "'default' must be the last clause of a switch");
+            }
+        }
+        self.consume(TokenType::RightBrace, "expect '}' after switch cases.");
+
+        for jump in end_jumps {
+            self.patch_address(jump);
         }
-        self.patch_address(else_address);
+        self.unreachable = false;
+    }
+
+    fn case_body(&mut self) {
+        self.begin_scope();
+        self.unreachable = false;
+        let mut warned = false;
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::Eof)
+        {
+            self.block_declaration(&mut warned);
+        }
+        self.end_scope();
     }
 
     fn while_statement(&mut self) {
-        let loop_start = self.memory.get_memory_size();
-        //println!("LOOOP START {}", loop_start);
+        let loop_start = self.memory.chunk_size();
         self.consume(TokenType::LeftParen, "expect '(' after 'if'.");
         self.expression();
         self.consume(TokenType::RightParen, "expect ')' after condition.");
@@ -618,84 +1676,274 @@ impl Compiler {
 
         self.patch_address(end_address);
         self.memory.push(OpCode::Pop);
+        self.unreachable = false;
     }
 
     fn push_loop(&mut self, loop_start: usize) {
         self.memory.push(OpCode::Loop);
-        let steps = self.memory.get_memory_size() - loop_start + 1;
-        // println!(
-        //     "==============jmping to {} {}",
-        //     steps,
-        //     self.memory.get_memory_size()
-        // );
+        let steps = self.memory.chunk_size() + 2 - loop_start;
         self.memory.push_raw(steps as u16);
+        self.memory.push_raw((steps >> 16) as u16);
     }
 
+    // Points the jump whose operand is at `jmp_address` at the next word.
     fn patch_address(&mut self, jmp_address: usize) {
-        let steps = self.memory.get_memory_size() - jmp_address - 1;
-        self.memory.replace_at_location(jmp_address, steps as u16)
+        let steps = self.memory.chunk_size() - jmp_address - 2;
+        self.memory.replace_at_location(jmp_address, steps as u16);
+        self.memory.replace_at_location(jmp_address + 1, (steps >> 16) as u16);
     }
 
+    // Emits a jump with a placeholder distance for `patch_address` to fill,
+    // returning where its operand is.
     fn push_jmp(&mut self, op: OpCode) -> usize {
         self.memory.push(op);
         self.memory.push(OpCode::Panic);
-        self.memory.get_memory_size() - 1
+        self.memory.push(OpCode::Panic);
+        self.memory.chunk_size() - 2
     }
 
     fn begin_scope(&mut self) {
         self.scope_depth += 1;
+        if self.scope_depth > self.max_scope_depth {
+            self.max_scope_depth = self.scope_depth;
+        }
     }
 
     fn block(&mut self) {
+        let mut warned = false;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
-            self.declaration();
+            self.block_declaration(&mut warned);
         }
         self.consume(TokenType::RightBrace, "expect '}' after block.")
     }
 
-    fn return_scope(&mut self) {
-        // println!(
-        //     "func depth {} \n LOCALS \n {:#?}",
-        //     self.func_returns, self.locals
-        // );
-        for i in 0..self.locals.len()
-        {
-            if !(self.locals[i].depth > self.scope_depth - 1
-            || self.locals[i].func_depth > (self.func_returns - 1)) {
-                break;
-            }
-            // println!("current depth POP 1");
-            self.memory.push(OpCode::Pop);
+    // Once a declaration of a block always returns, the ones after it can
+    // never run. They are still compiled, so their errors are reported and
+    // their locals keep the slots they would have, but their code is
+    // dropped, with one warning for the block.
+    fn block_declaration(&mut self, warned: &mut bool) {
+        if !self.unreachable {
+            self.declaration();
+            return;
+        }
+        if !std::mem::replace(warned, true) {
+            self.warn_at(self.current, "unreachable code");
+        }
+        self.skip(Self::declaration);
+        self.unreachable = true;
+    }
+
+    // Compiles code that can never run and drops what it emitted, with the
+    // bodies and constants it added. A hoisted function declared in it was
+    // given a constant up front; that goes back to nil rather than point at
+    // a dropped chunk, which the next body would reuse.
+    fn skip(&mut self, compile: fn(&mut Self)) {
+        let start = self.memory.chunk_size();
+        let chunks = self.memory.chunks().len();
+        let constants = self.memory.get_constants_size();
+        compile(self);
+        self.discard(start);
+        self.memory.truncate_chunks(chunks);
+        self.memory.truncate_constants(constants);
+        self.name_constants.retain(|_, index| (*index as usize) < constants);
+        let dropped: Vec<usize> = self
+            .memory
+            .constants()
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| matches!(value, Value::Object(Object::Function(function)) if function.chunk >= chunks))
+            .map(|(index, _)| index)
+            .collect();
+        for index in dropped {
+            self.memory.replace_constant(index as u32, Value::Nil);
         }
     }
 
+    // Drops the code from `start` on, which nothing refers to any more.
+    fn discard(&mut self, start: usize) {
+        self.memory.truncate(start);
+        self.last_call = self.last_call.filter(|call| *call < start);
+    }
+
+    // Drops the scope's locals, popping each run of uncaptured ones at once
+    // and closing the captured ones in between.
     fn end_scope(&mut self) {
         self.scope_depth -= 1;
-        while self.locals.len() > 0 && self.locals.last().unwrap().depth > self.scope_depth {
-            self.memory.push(OpCode::Pop);
-            self.locals.pop();
+        let mut pending = 0;
+        while !self.locals.is_empty() && self.locals.last().unwrap().depth > self.scope_depth {
+            if self.locals.pop().unwrap().captured {
+                self.pop_n(pending);
+                pending = 0;
+                self.memory.push(OpCode::CloseUpvalue);
+            } else {
+                pending += 1;
+            }
         }
+        self.pop_n(pending);
     }
 
-    fn parse_identifier(&mut self, token: Token) -> String {
-        let var_name: String = self
-            .source
-            .chars()
-            .skip(token.start)
-            .take(token.length)
-            .collect();
-        var_name
+    fn pop_n(&mut self, count: usize) {
+        match count {
+            0 => (),
+            1 => self.memory.push(OpCode::Pop),
+            _ => {
+                self.memory.push(OpCode::PopN);
+                self.memory.push_raw(count as u16);
+            }
+        }
+    }
+
+    fn parse_identifier(&mut self, token: Token) -> Symbol {
+        let text = &self.sources[token.source].text;
+        self.interner.intern(&text[token.start..token.start + token.length])
     }
 
+    // Emits `op` with the slot of the global called `name`.
+    fn global(&mut self, op: OpCode, name: Symbol) {
+        let emitted = self.memory.push_global(op, self.interner.resolve(name));
+        self.emitted(emitted);
+    }
+
+    // Emits `op` with `value`'s constant, returning its index.
+    fn emit_constant(&mut self, op: OpCode, value: Value) -> u32 {
+        let emitted = self.memory.push_constant(op, value);
+        self.emitted(emitted).unwrap_or(0)
+    }
+
+    // Reports why an instruction could not be emitted, such as a program
+    // outgrowing the operand that names a constant.
+    fn emitted<T>(&mut self, result: Result<T, String>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.error_at(self.previous, &message);
+                None
+            }
+        }
+    }
+
+    // Emits `op` with the constant holding `name`, reusing the constant
+    // already made for that symbol instead of copying the name again.
+    fn name_constant(&mut self, op: OpCode, name: Symbol) {
+        match self.name_constants.get(&name) {
+            Some(index) => {
+                let emitted = self.memory.push_constant_index(op, *index);
+                self.emitted(emitted);
+            }
+            None => {
+                let value = Value::Object(Object::String(self.interner.resolve(name).into()));
+                let index = self.emit_constant(op, value);
+                self.name_constants.insert(name, index);
+            }
+        }
+    }
+
+    // `print a, b, c;` leaves all three values on the stack for one PrintN.
     fn print_statement(&mut self) {
-        self.expression();
+        let mut count = 0;
+        loop {
+            if self.check(TokenType::SemiColon) {
+                let after = if count == 0 { "'print'" } else { "','" };
+                self.error_at(self.current, &format!("expect value after {}", after));
+            }
+            self.expression();
+            count += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
         self.consume(TokenType::SemiColon, "expect ';' after value.");
-        self.memory.push(OpCode::Print)
+        if count == 1 {
+            self.memory.push(OpCode::Print);
+        } else {
+            self.memory.push(OpCode::PrintN);
+            self.memory.push_raw(count);
+        }
+    }
+
+    // The message is a string literal kept as the Assert operand, so nothing
+    // is evaluated for it unless the assertion fails.
+    fn assert_statement(&mut self) {
+        self.expression();
+        let message = if self.match_token(TokenType::Comma) {
+            self.consume(TokenType::String, "expect message string after ','.");
+            Value::Object(Object::String(self.string_contents(self.previous).into()))
+        } else {
+            Value::Nil
+        };
+        self.consume(TokenType::SemiColon, "expect ';' after assertion.");
+        self.emit_constant(OpCode::Assert, message);
     }
 
+    // When echoing, a top-level expression other than an assignment is
+    // printed rather than dropped, and the last one may leave out its `;`.
     fn expression_statement(&mut self) {
+        let start = self.memory.chunk_size();
         self.expression();
-        self.consume(TokenType::SemiColon, "expect ';' after expression.");
-        self.memory.push(OpCode::Pop)
+        let echo = self.echo && self.scope_depth == 0 && self.function_kinds.is_empty();
+        if !(echo && self.check(TokenType::Eof)) {
+            self.consume(TokenType::SemiColon, "expect ';' after expression.");
+        }
+        if echo && !self.assigned_since(start) {
+            self.memory.push(OpCode::Print)
+        } else {
+            self.memory.push(OpCode::Pop)
+        }
+    }
+
+    // Whether the code emitted from `start` on ends by storing a value.
+    fn assigned_since(&self, start: usize) -> bool {
+        let last = self.memory.instructions().filter(|i| i.offset >= start).last();
+        matches!(
+            last.map(|i| i.opcode),
+            Some(
+                OpCode::SetGlobalSlot
+                    | OpCode::SetLocalVar
+                    | OpCode::SetUpvalue
+                    | OpCode::SetProperty
+                    | OpCode::IndexSet
+            )
+        )
+    }
+}
+
+// Applies one operator to the literals on `stack` the way the VM does, or
+// gives None where the VM would stop with an error, so the error still
+// happens at runtime.
+fn fold(opcode: OpCode, stack: &mut Vec<Value>) -> Option<()> {
+    let result = match opcode {
+        OpCode::Negate => match stack.pop()? {
+            Value::Number(x) => Value::Number(-x),
+            _ => return None,
+        },
+        OpCode::Not => Value::Bool(stack.pop()?.is_falsey()),
+        _ => {
+            let b = stack.pop()?;
+            let a = stack.pop()?;
+            match opcode {
+                OpCode::Equal => Value::Bool(a.equals(&b)),
+                OpCode::Greater => Value::Bool(compare(&a, &b).ok()? == Some(Ordering::Greater)),
+                OpCode::Less => Value::Bool(compare(&a, &b).ok()? == Some(Ordering::Less)),
+                _ => arithmetic(opcode, &a, &b).ok()?,
+            }
+        }
+    };
+    stack.push(result);
+    Some(())
+}
+
+// Checks that every `_` in a number literal sits between two digits.
+fn separated(text: &str, is_digit: impl Fn(char) -> bool) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    chars.iter().enumerate().all(|(i, &c)| {
+        c != '_'
+            || (i > 0 && is_digit(chars[i - 1]) && chars.get(i + 1).is_some_and(|&n| is_digit(n)))
+    })
+}
+
+fn same_file(source: &Source, path: &Path) -> bool {
+    match (fs::canonicalize(&source.path), fs::canonicalize(path)) {
+        (Ok(source), Ok(path)) => source == path,
+        _ => false,
     }
 }
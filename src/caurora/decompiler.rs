@@ -0,0 +1,578 @@
+use std::fmt::Write;
+
+use super::{
+    memoryslice::{Instruction, MemorySlice},
+    opcodes::OpCode,
+    values::{Object, Value},
+};
+
+pub fn decompile(memory: &MemorySlice) -> String {
+    let mut decompiler = Decompiler {
+        memory,
+        instructions: Vec::new(),
+        out: String::new(),
+        locals: Vec::new(),
+        upvalues: Vec::new(),
+        method: false,
+        initializer: false,
+    };
+    decompiler.chunk(0, 0);
+    // Tests only run after the script, wherever they were written.
+    for (name, chunk) in memory.get_tests() {
+        decompiler.line(0, &format!("test {:?} {{", name));
+        decompiler.frame(*chunk, 1, Vec::new(), None, Vec::new());
+        decompiler.line(0, "}");
+    }
+    decompiler.out
+}
+
+struct Decompiler<'a> {
+    memory: &'a MemorySlice,
+    // The instructions of the chunk being decompiled.
+    instructions: Vec<Instruction>,
+    out: String,
+    // Names of the locals in the current frame, by slot. A local declaration
+    // leaves its value on the stack, so whatever is still pending when a
+    // statement completes was declared as a local before it.
+    locals: Vec<String>,
+    // Names of the variables the current frame's upvalues capture.
+    upvalues: Vec<String>,
+    // Whether the frame being decompiled is a method, whose slot zero is
+    // `this`, and whether it is `init`, whose returns give `this` back.
+    method: bool,
+    initializer: bool,
+}
+
+impl Decompiler<'_> {
+    fn block(&mut self, start: usize, end: usize, depth: usize) {
+        let mut exprs = Vec::<String>::new();
+        self.run(start, end, depth, &mut exprs);
+        self.declare_locals(depth, &mut exprs);
+    }
+
+    // Decompiles the whole of chunk `chunk`, coming back to the one being
+    // decompiled after.
+    fn chunk(&mut self, chunk: usize, depth: usize) {
+        let code = self.memory.chunk(chunk).map(|chunk| chunk.instructions().collect());
+        let outer = std::mem::replace(&mut self.instructions, code.unwrap_or_default());
+        self.block(0, self.instructions.len(), depth);
+        self.instructions = outer;
+    }
+
+    // Decompiles a function, method or test body, whose frame starts with the
+    // callee (the receiver for `method`) in slot zero followed by `params`.
+    fn frame(
+        &mut self,
+        chunk: usize,
+        depth: usize,
+        params: Vec<String>,
+        method: Option<&str>,
+        upvalues: Vec<String>,
+    ) {
+        let slots = std::iter::once(String::new()).chain(params);
+        let locals = std::mem::replace(&mut self.locals, slots.collect());
+        let upvalues = std::mem::replace(&mut self.upvalues, upvalues);
+        let outer = (self.method, self.initializer);
+        (self.method, self.initializer) = (method.is_some(), method == Some("init"));
+        self.chunk(chunk, depth);
+        (self.method, self.initializer) = outer;
+        self.upvalues = upvalues;
+        self.locals = locals;
+    }
+
+    fn expression(&mut self, start: usize, end: usize, depth: usize) -> String {
+        let mut exprs = Vec::<String>::new();
+        self.run(start, end, depth, &mut exprs);
+        exprs.pop().unwrap_or_else(|| "/* ? */".to_owned())
+    }
+
+    fn declare_locals(&mut self, depth: usize, exprs: &mut Vec<String>) {
+        for expr in std::mem::take(exprs) {
+            let name = local_name(self.locals.len(), &self.upvalues);
+            self.line(depth, &format!("var {} = {};", name, expr));
+            self.locals.push(name);
+        }
+    }
+
+    fn run(&mut self, start: usize, end: usize, depth: usize, exprs: &mut Vec<String>) {
+        let mut i = start;
+        while i < end {
+            let instruction = self.instructions[i];
+            match instruction.opcode {
+                OpCode::Constant | OpCode::ConstantLong => match self.constant(instruction) {
+                    // A hoisted top-level function; its declaration comes later
+                    // and loads the same constant again.
+                    Value::Object(Object::Function(_)) if self.hoisted(i) => {
+                        i += 2;
+                        continue;
+                    }
+                    Value::Object(Object::Function(function)) => {
+                        self.declare_locals(depth, exprs);
+                        // A local function can capture itself, so it is named before its body.
+                        let global = self.opcode_at(self.skip_captures(i + 1)) == Some(OpCode::DefineGlobalSlot);
+                        if !global {
+                            self.locals.push(function.name.to_string());
+                        }
+                        let upvalues = self.captures(i + 1);
+                        let params = parameters(function.arity, &upvalues);
+                        self.line(depth, &format!("function {}({}) {{", function.name, params.join(", ")));
+                        self.frame(function.chunk, depth + 1, params, None, upvalues);
+                        self.line(depth, "}");
+                        i = self.skip_captures(i + 1) + global as usize;
+                        continue;
+                    }
+                    value => exprs.push(literal(&value)),
+                },
+                OpCode::Nil => exprs.push("nil".to_owned()),
+                OpCode::True => exprs.push("true".to_owned()),
+                OpCode::False => exprs.push("false".to_owned()),
+                OpCode::Negate => {
+                    let value = pop(exprs);
+                    exprs.push(format!("-{}", value));
+                }
+                OpCode::Not => {
+                    let value = pop(exprs);
+                    exprs.push(format!("!{}", value));
+                }
+                OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Power
+                | OpCode::Equal
+                | OpCode::Greater
+                | OpCode::Less => {
+                    let comparison = matches!(
+                        instruction.opcode,
+                        OpCode::Equal | OpCode::Greater | OpCode::Less
+                    );
+                    let negated = comparison && self.opcode_at(i + 1) == Some(OpCode::Not);
+                    let operator = match (instruction.opcode, negated) {
+                        (OpCode::Add, _) => "+",
+                        (OpCode::Subtract, _) => "-",
+                        (OpCode::Multiply, _) => "*",
+                        (OpCode::Divide, _) => "/",
+                        (OpCode::Power, _) => "**",
+                        (OpCode::Equal, false) => "==",
+                        (OpCode::Equal, true) => "!=",
+                        (OpCode::Greater, false) => ">",
+                        (OpCode::Greater, true) => "<=",
+                        (OpCode::Less, false) => "<",
+                        _ => ">=",
+                    };
+                    if negated {
+                        i += 1;
+                    }
+                    let right = pop(exprs);
+                    let left = pop(exprs);
+                    exprs.push(format!("({} {} {})", left, operator, right));
+                }
+                OpCode::GetGlobalSlot => exprs.push(self.name(instruction)),
+                OpCode::SetGlobalSlot => {
+                    let value = pop(exprs);
+                    exprs.push(format!("{} = {}", self.name(instruction), value));
+                }
+                OpCode::DefineGlobalSlot => {
+                    let value = pop(exprs);
+                    self.declare_locals(depth, exprs);
+                    self.line(depth, &format!("var {} = {};", self.name(instruction), value));
+                }
+                OpCode::GetProperty => {
+                    let object = pop(exprs);
+                    exprs.push(format!("{}.{}", object, self.name(instruction)));
+                }
+                OpCode::GetLocalVar => exprs.push(self.local(instruction)),
+                OpCode::SetLocalVar => {
+                    let value = pop(exprs);
+                    exprs.push(format!("{} = {}", self.local(instruction), value));
+                }
+                OpCode::Print => {
+                    let value = pop(exprs);
+                    self.declare_locals(depth, exprs);
+                    self.line(depth, &format!("print {};", value));
+                }
+                OpCode::PrintN => {
+                    let count = instruction.operand.unwrap_or(0) as usize;
+                    let values = exprs.split_off(exprs.len().saturating_sub(count));
+                    self.declare_locals(depth, exprs);
+                    self.line(depth, &format!("print {};", values.join(", ")));
+                }
+                OpCode::Assert => {
+                    let value = pop(exprs);
+                    self.declare_locals(depth, exprs);
+                    match self.constant(instruction) {
+                        Value::Object(Object::String(message)) => {
+                            self.line(depth, &format!("assert {}, {:?};", value, message))
+                        }
+                        _ => self.line(depth, &format!("assert {};", value)),
+                    }
+                }
+                // With nothing pending, a Pop drops a local at the end of its scope.
+                OpCode::Pop => match exprs.pop() {
+                    Some(value) if postfix(&value, exprs.last()).is_some() => {
+                        let stepped = postfix(&value, exprs.last()).unwrap();
+                        exprs.pop();
+                        exprs.push(stepped);
+                    }
+                    Some(value) => {
+                        self.declare_locals(depth, exprs);
+                        self.line(depth, &format!("{};", value));
+                    }
+                    None => {
+                    self.locals.pop();
+                }
+                },
+                OpCode::PopN => {
+                    let count = instruction.operand.unwrap_or(0) as usize;
+                    self.locals.truncate(self.locals.len().saturating_sub(count));
+                }
+                OpCode::CloseUpvalue => {
+                    self.locals.pop();
+                }
+                OpCode::Call | OpCode::TailCall => {
+                    let count = instruction.operand.unwrap_or(0) as usize;
+                    let args = exprs.split_off(exprs.len().saturating_sub(count));
+                    let callee = pop(exprs);
+                    exprs.push(format!("{}({})", callee, args.join(", ")));
+                }
+                OpCode::BuildArray => {
+                    let count = instruction.operand.unwrap_or(0) as usize;
+                    let elements = exprs.split_off(exprs.len().saturating_sub(count));
+                    exprs.push(format!("[{}]", elements.join(", ")));
+                }
+                OpCode::IndexGet => {
+                    let index = pop(exprs);
+                    let target = pop(exprs);
+                    exprs.push(format!("{}[{}]", target, index));
+                }
+                OpCode::IndexSet => {
+                    let value = pop(exprs);
+                    let index = pop(exprs);
+                    let target = pop(exprs);
+                    exprs.push(format!("{}[{}] = {}", target, index, value));
+                }
+                OpCode::Return => {
+                    let value = pop(exprs);
+                    self.declare_locals(depth, exprs);
+                    let bare = if self.initializer { "this" } else { "nil" };
+                    match value.as_str() {
+                        value if value == bare && i + 1 == end => (),
+                        value if value == bare => self.line(depth, "return;"),
+                        _ => self.line(depth, &format!("return {};", value)),
+                    }
+                }
+                OpCode::JmpFalse | OpCode::JmpTrue => {
+                    let target = self.target_index(instruction);
+                    let condition = pop(exprs);
+                    i = self.conditional(i, target, condition, depth, exprs);
+                    continue;
+                }
+                OpCode::Dup if self.switch_case(i).is_some() => {
+                    let subject = pop(exprs);
+                    self.declare_locals(depth, exprs);
+                    i = self.switch_statement(i, subject, depth);
+                    continue;
+                }
+                OpCode::Dup => {
+                    let value = pop(exprs);
+                    exprs.push(value.clone());
+                    exprs.push(value);
+                }
+                OpCode::Class => {
+                    self.declare_locals(depth, exprs);
+                    i = self.class_declaration(i, depth);
+                    continue;
+                }
+                // A default parameter value is compiled as an `if` on this.
+                OpCode::ArgMissing => {
+                    exprs.push(format!("missing(local_{})", instruction.operand.unwrap_or(0)))
+                }
+                OpCode::GetUpvalue => exprs.push(self.upvalue(instruction)),
+                OpCode::SetUpvalue => {
+                    let value = pop(exprs);
+                    exprs.push(format!("{} = {}", self.upvalue(instruction), value));
+                }
+                OpCode::GetSuper => {
+                    pop(exprs);
+                    exprs.push(format!("super.{}", self.name(instruction)));
+                }
+                OpCode::SetProperty => {
+                    let value = pop(exprs);
+                    let object = pop(exprs);
+                    exprs.push(format!("{}.{} = {}", object, self.name(instruction), value));
+                }
+                OpCode::AssertStack => (),
+                OpCode::Eof => break,
+                _ => self.line(
+                    depth,
+                    &format!("// unrecognized {:?} at {:0>4}", instruction.opcode, instruction.offset),
+                ),
+            }
+            i += 1;
+        }
+    }
+
+    fn conditional(
+        &mut self,
+        i: usize,
+        target: usize,
+        condition: String,
+        depth: usize,
+        exprs: &mut Vec<String>,
+    ) -> usize {
+        let jump = self.instructions[i].opcode;
+        let before_target = target.checked_sub(1).map(|t| self.instructions[t]);
+        match before_target.map(|ins| ins.opcode) {
+            Some(OpCode::Jmp) if jump == OpCode::JmpFalse => {
+                let else_end = self.target_index(before_target.unwrap());
+                self.declare_locals(depth, exprs);
+                self.line(depth, &format!("if {} {{", parenthesized(&condition)));
+                self.block(i + 2, target - 1, depth + 1);
+                if else_end > target + 1 {
+                    self.line(depth, "} else {");
+                    self.block(target + 1, else_end, depth + 1);
+                }
+                self.line(depth, "}");
+                else_end
+            }
+            Some(OpCode::Loop) if jump == OpCode::JmpFalse => {
+                self.declare_locals(depth, exprs);
+                if self.opcode_at(i + 2) == Some(OpCode::Jmp) {
+                    let body = self.target_index(self.instructions[i + 2]);
+                    let step = self.expression(i + 3, body - 2, depth);
+                    self.line(depth, &format!("for (; {}; {}) {{", condition, step));
+                    self.block(body, target - 1, depth + 1);
+                } else {
+                    self.line(depth, &format!("while {} {{", parenthesized(&condition)));
+                    self.block(i + 2, target - 1, depth + 1);
+                }
+                self.line(depth, "}");
+                target + 1
+            }
+            _ => {
+                let operator = if jump == OpCode::JmpFalse { "and" } else { "or" };
+                let right = self.expression(i + 2, target, depth);
+                exprs.push(format!("({} {} {})", condition, operator, right));
+                target
+            }
+        }
+    }
+
+    // Cases compile to `Dup <value> Equal JmpFalse Pop Pop <body> Jmp end`
+    // with the JmpFalse landing on a Pop; returns the JmpFalse's index.
+    fn switch_case(&self, dup: usize) -> Option<usize> {
+        let jump = (dup + 2..self.instructions.len()).find(|j| {
+            self.opcode_at(*j) == Some(OpCode::JmpFalse) && self.opcode_at(j - 1) == Some(OpCode::Equal)
+        })?;
+        let target = self.target_index(self.instructions[jump]);
+        let shape = [
+            self.opcode_at(jump + 1),
+            self.opcode_at(jump + 2),
+            self.opcode_at(target - 1),
+            self.opcode_at(target),
+        ];
+        (shape == [Some(OpCode::Pop), Some(OpCode::Pop), Some(OpCode::Jmp), Some(OpCode::Pop)])
+            .then_some(jump)
+    }
+
+    fn switch_statement(&mut self, mut i: usize, subject: String, depth: usize) -> usize {
+        self.line(depth, &format!("switch {} {{", parenthesized(&subject)));
+        let mut end = None;
+        while let Some(jump) = (self.opcode_at(i) == Some(OpCode::Dup)).then(|| self.switch_case(i)).flatten() {
+            let target = self.target_index(self.instructions[jump]);
+            let value = self.expression(i + 1, jump - 1, depth);
+            self.line(depth + 1, &format!("case {}:", value));
+            self.block(jump + 3, target - 1, depth + 2);
+            end = Some(self.target_index(self.instructions[target - 1]));
+            i = target + 1;
+        }
+        let end = end.unwrap_or(i + 1);
+        if end > i + 1 {
+            self.line(depth + 1, "default:");
+            self.block(i + 1, end, depth + 2);
+        }
+        self.line(depth, "}");
+        end
+    }
+
+    // A class is made, bound to its name and reloaded, then an optional
+    // superclass read is followed by Inherit, and each method is a function
+    // constant followed by any captures and Method;
+    // a Pop drops the reloaded class. Returns the index after that Pop.
+    fn class_declaration(&mut self, i: usize, depth: usize) -> usize {
+        let global = self.opcode_at(i + 1) == Some(OpCode::DefineGlobalSlot);
+        let mut k = i + if global { 3 } else { 2 };
+        let name = self.name(self.instructions[i]);
+        if self.opcode_at(k + 1) == Some(OpCode::Inherit) {
+            let superclass = self.expression(k, k + 1, depth);
+            self.line(depth, &format!("class {} < {} {{", name, superclass));
+            k += 2;
+        } else {
+            self.line(depth, &format!("class {} {{", name));
+        }
+        while self.opcode_at(k) == Some(OpCode::Constant) {
+            let Value::Object(Object::Function(function)) = self.constant(self.instructions[k]) else {
+                break;
+            };
+            let upvalues = self.captures(k + 1);
+            let params = parameters(function.arity, &upvalues);
+            self.line(depth + 1, &format!("{}({}) {{", function.name, params.join(", ")));
+            self.frame(function.chunk, depth + 2, params, Some(&function.name), upvalues);
+            self.line(depth + 1, "}");
+            k = self.skip_captures(k + 1) + 1;
+        }
+        self.line(depth, "}");
+        if !global {
+            self.locals.push(name);
+        }
+        k + 1
+    }
+
+    // A top-level function is bound to its name before any other code runs,
+    // then loaded again where it is declared.
+    fn hoisted(&self, i: usize) -> bool {
+        let load = self.instructions[i];
+        self.opcode_at(i + 1) == Some(OpCode::DefineGlobalSlot)
+            && self.instructions[i + 1..]
+                .iter()
+                .any(|later| later.opcode == load.opcode && later.operand == load.operand)
+    }
+
+    // Steps over the Closure after a function body, and the captures that
+    // follow it, if the function captures anything.
+    fn skip_captures(&self, i: usize) -> usize {
+        match self.opcode_at(i) {
+            Some(OpCode::Closure) => i + 1 + self.instructions[i].operand.unwrap_or(0) as usize,
+            _ => i,
+        }
+    }
+
+    // Names what each capture after the Closure at `i`, if there is one, reads
+    // from the frame being decompiled.
+    fn captures(&self, i: usize) -> Vec<String> {
+        if self.opcode_at(i) != Some(OpCode::Closure) {
+            return Vec::new();
+        }
+        (i + 1..self.skip_captures(i))
+            .map(|c| {
+                let capture = self.instructions[c];
+                let index = capture.operand.unwrap_or(0) as usize;
+                let names = match capture.opcode {
+                    OpCode::CaptureLocal => &self.locals,
+                    _ => &self.upvalues,
+                };
+                names.get(index).cloned().unwrap_or_else(|| "/* ? */".to_owned())
+            })
+            .collect()
+    }
+
+    fn target_index(&self, instruction: Instruction) -> usize {
+        let target = instruction.jump_target().unwrap_or(instruction.offset);
+        self.instructions
+            .binary_search_by_key(&target, |ins| ins.offset)
+            .unwrap_or_else(|index| index)
+    }
+
+    fn opcode_at(&self, i: usize) -> Option<OpCode> {
+        self.instructions.get(i).map(|ins| ins.opcode)
+    }
+
+    fn constant(&self, instruction: Instruction) -> Value {
+        instruction
+            .operand
+            .and_then(|index| self.memory.get_constant(index))
+            .unwrap_or(Value::Raw)
+    }
+
+    // The name a global opcode's slot or a property opcode's constant holds.
+    fn name(&self, instruction: Instruction) -> String {
+        if instruction.opcode.takes_global() {
+            let name = instruction.operand.and_then(|slot| self.memory.global_name(slot as u16));
+            return name.map_or_else(|| "/* ? */".to_owned(), str::to_owned);
+        }
+        match self.constant(instruction) {
+            Value::Object(Object::String(name)) => name.to_string(),
+            value => format!("/* {} */", literal(&value)),
+        }
+    }
+
+    fn local(&self, instruction: Instruction) -> String {
+        match self.constant(instruction) {
+            Value::Number(slot) if slot == 0.0 && self.method => "this".to_owned(),
+            Value::Number(slot) => match self.locals.get(slot as usize) {
+                Some(name) if !name.is_empty() => name.clone(),
+                _ => format!("local_{}", slot),
+            },
+            value => format!("/* {} */", literal(&value)),
+        }
+    }
+
+    fn upvalue(&self, instruction: Instruction) -> String {
+        let index = instruction.operand.unwrap_or(0) as usize;
+        self.upvalues.get(index).cloned().unwrap_or_else(|| format!("upvalue_{}", index))
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        writeln!(self.out, "{}{}", "    ".repeat(depth), text).unwrap();
+    }
+}
+
+// Locals are named for their slot, but a nested frame's slots start over, so
+// a name one of the frame's upvalues already has gets a suffix instead.
+fn local_name(slot: usize, upvalues: &[String]) -> String {
+    let mut name = format!("local_{}", slot);
+    let mut n = 1;
+    while upvalues.contains(&name) {
+        name = format!("local_{}_{}", slot, n);
+        n += 1;
+    }
+    name
+}
+
+fn parameters(arity: usize, upvalues: &[String]) -> Vec<String> {
+    (1..=arity).map(|p| local_name(p, upvalues)).collect()
+}
+
+fn pop(exprs: &mut Vec<String>) -> String {
+    exprs.pop().unwrap_or_else(|| "/* ? */".to_owned())
+}
+
+// `x++` compiles to a read of `x` kept as the result, then `x = (x + 1)`
+// whose own value is popped.
+fn postfix(assignment: &str, old: Option<&String>) -> Option<String> {
+    let old = old?;
+    ["++", "--"].iter().find_map(|operator| {
+        let step = format!("{} = ({} {} 1)", old, old, &operator[..1]);
+        (assignment == step).then(|| format!("{}{}", old, operator))
+    })
+}
+
+fn parenthesized(expr: &str) -> String {
+    if expr.starts_with('(') && expr.ends_with(')') {
+        expr.to_owned()
+    } else {
+        format!("({})", expr)
+    }
+}
+
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Number(x) => x.to_string(),
+        Value::Nil => "nil".to_owned(),
+        Value::Raw => "?".to_owned(),
+        Value::Bool(b) => b.to_string(),
+        Value::Object(Object::String(s)) => format!("{:?}", s),
+        Value::Object(Object::Function(function)) => format!("<fn {}>", function.name),
+        Value::Object(Object::NativeFunction(native)) => format!("<native {}>", native.name),
+        Value::Object(Object::Module(module)) => format!("<module {}>", module.name),
+        Value::Object(
+            object @ (Object::Class(_) | Object::Instance(_) | Object::BoundMethod(_) | Object::Closure(_)),
+        ) => {
+            object.to_string()
+        }
+        Value::Object(Object::Array(elements)) => format!(
+            "[{}]",
+            elements.borrow().iter().map(literal).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
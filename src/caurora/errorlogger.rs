@@ -1,3 +1,6 @@
 pub fn log_error(error: &str) {
     panic!("{}",error);
-}
\ No newline at end of file
+}
+pub fn log_warning(warning: &str) {
+    eprintln!("warning: {}", warning);
+}
@@ -0,0 +1,381 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    rc::Rc,
+    time::Instant,
+};
+
+use super::{
+    compiler::{CompileError, Compiler},
+    memoryslice::MemorySlice,
+    random::Random,
+    scanner::Scanner,
+    stats::{CompileStats, ExecutionStats, ProfileData},
+    values::Value,
+    virtualmachine::{InterpretResult, RuntimeError, VmBuilder, VmOptions, VM},
+};
+
+// Why running a script did not finish normally.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CauroraError {
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+    // The script called `exit(code)`.
+    Exit(i32),
+    // The script ran more instructions than the interpreter's fuel allows.
+    OutOfFuel,
+}
+
+impl fmt::Display for CauroraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CauroraError::Compile(errors) => {
+                let lines: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CauroraError::Runtime(error) => write!(f, "Runtime error on {}", error),
+            CauroraError::Exit(code) => write!(f, "exited with code {}", code),
+            CauroraError::OutOfFuel => write!(f, "ran out of fuel"),
+        }
+    }
+}
+
+impl std::error::Error for CauroraError {}
+
+// Compiles and runs a script with the default builtins, printing to stdout.
+// A script that calls `exit(0)` still counts as a success.
+pub fn run_source(source: &str) -> Result<(), CauroraError> {
+    match Interpreter::new().run(source) {
+        Err(CauroraError::Exit(0)) => Ok(()),
+        result => result,
+    }
+}
+
+pub struct InterpreterBuilder {
+    globals: Vec<(Rc<str>, Value)>,
+    output: Option<Box<dyn Write>>,
+    capture: bool,
+    path: String,
+    echo: bool,
+    fold: bool,
+    optimize: bool,
+    options: VmOptions,
+    trace: Option<Box<dyn Write>>,
+}
+
+impl InterpreterBuilder {
+    // Defines a global before any script runs, replacing a builtin of the
+    // same name.
+    pub fn global(mut self, name: &str, value: Value) -> Self {
+        self.globals.push((name.into(), value));
+        self
+    }
+
+    pub fn output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    // Keeps what scripts print for `Interpreter::take_output` instead of
+    // writing it anywhere.
+    pub fn capture_output(mut self) -> Self {
+        self.capture = true;
+        self
+    }
+
+    // The name compile errors report the source under.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_owned();
+        self
+    }
+
+    // Print the value of each top-level expression statement, as the
+    // interactive prompt does.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    // Compute operators on literal operands at compile time, as is the
+    // default.
+    pub fn fold(mut self, fold: bool) -> Self {
+        self.fold = fold;
+        self
+    }
+
+    // Run the peephole pass over each script's code.
+    pub fn optimize(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    // The most instructions each run may execute before it is stopped with
+    // `CauroraError::OutOfFuel`, for scripts that may never finish.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.options.fuel = Some(fuel);
+        self
+    }
+
+    pub fn max_call_depth(mut self, depth: usize) -> Self {
+        self.options.max_call_depth = Some(depth);
+        self
+    }
+
+    // Counts and times every opcode run; see `Interpreter::profile`.
+    pub fn profile(mut self, enabled: bool) -> Self {
+        self.options.profile = enabled;
+        self
+    }
+
+    // Counts the instructions each run executes; see
+    // `Interpreter::execution_stats`.
+    pub fn stats(mut self, enabled: bool) -> Self {
+        self.options.stats = enabled;
+        self
+    }
+
+    // Whether scripts get the builtins at all.
+    pub fn builtins(mut self, enabled: bool) -> Self {
+        self.options.builtins = enabled;
+        self
+    }
+
+    // Whether scripts get the builtins that touch the filesystem, for hosts
+    // that want a sandbox.
+    pub fn files(mut self, enabled: bool) -> Self {
+        self.options.files = enabled;
+        self
+    }
+
+    // Where to log each instruction before it runs.
+    pub fn trace(mut self, trace: Box<dyn Write>) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    pub fn build(self) -> Interpreter {
+        let memory = MemorySlice::new();
+        let mut globals = VM::builder()
+            .memory(&memory)
+            .options(self.options.clone())
+            .build()
+            .into_globals();
+        globals.extend(self.globals);
+        let captured = self.capture.then(|| Rc::new(RefCell::new(Vec::new())));
+        let output: Box<dyn Write> = match (&captured, self.output) {
+            (Some(buffer), _) => Box::new(Captured(buffer.clone())),
+            (None, Some(output)) => output,
+            (None, None) => Box::new(io::stdout()),
+        };
+        Interpreter {
+            memory: MemorySlice::new(),
+            globals,
+            output: Rc::new(RefCell::new(output)),
+            captured,
+            path: self.path,
+            echo: self.echo,
+            fold: self.fold,
+            optimize: self.optimize,
+            options: self.options,
+            trace: self.trace.map(|trace| Rc::new(RefCell::new(trace))),
+            started: Instant::now(),
+            random: Random::from_time(),
+            file_error: None,
+            warnings: Vec::new(),
+            compile_stats: CompileStats::default(),
+            execution_stats: None,
+            profile: None,
+        }
+    }
+}
+
+// Runs scripts one after another on the same program, so globals and
+// functions one defines are there for the next.
+pub struct Interpreter {
+    memory: MemorySlice,
+    globals: HashMap<Rc<str>, Value>,
+    output: Rc<RefCell<Box<dyn Write>>>,
+    captured: Option<Rc<RefCell<Vec<u8>>>>,
+    path: String,
+    echo: bool,
+    fold: bool,
+    optimize: bool,
+    options: VmOptions,
+    trace: Option<Rc<RefCell<Box<dyn Write>>>>,
+    // What `clock()` counts from in every run.
+    started: Instant,
+    // The generator `rand()` draws from, carried from run to run so `seed(n)`
+    // holds for later ones.
+    random: Random,
+    // Why the last file builtin failed, kept for `last_error()` in later runs.
+    file_error: Option<String>,
+    // What the last compile warned about and counted.
+    warnings: Vec<CompileError>,
+    compile_stats: CompileStats,
+    // What the last run counted, when asked to.
+    execution_stats: Option<ExecutionStats>,
+    profile: Option<ProfileData>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter::builder().build()
+    }
+
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder {
+            globals: Vec::new(),
+            output: None,
+            capture: false,
+            path: "<script>".to_owned(),
+            echo: false,
+            fold: true,
+            optimize: false,
+            options: VmOptions::default(),
+            trace: None,
+        }
+    }
+
+    pub fn globals(&self) -> &HashMap<Rc<str>, Value> {
+        &self.globals
+    }
+
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    // What scripts have printed since the last call, when built with
+    // `capture_output`.
+    pub fn take_output(&mut self) -> String {
+        match &self.captured {
+            Some(buffer) => String::from_utf8_lossy(&buffer.take()).into_owned(),
+            None => String::new(),
+        }
+    }
+
+    // The compile warnings from the last call to `run` or `compile`.
+    pub fn warnings(&self) -> &[CompileError] {
+        &self.warnings
+    }
+
+    pub fn compile_stats(&self) -> &CompileStats {
+        &self.compile_stats
+    }
+
+    // What the last run executed, when built with `stats`.
+    pub fn execution_stats(&self) -> Option<&ExecutionStats> {
+        self.execution_stats.as_ref()
+    }
+
+    // The opcode counts and times of the last run, when built with `profile`.
+    pub fn profile(&self) -> Option<&ProfileData> {
+        self.profile.as_ref()
+    }
+
+    // Compiles the source onto the program so far and runs the new code. A
+    // source that fails to compile leaves the program as it was.
+    pub fn run(&mut self, source: &str) -> Result<(), CauroraError> {
+        let (memory, entry) = self.compile(source)?;
+        self.execute(memory, entry)
+    }
+
+    // Compiles the source onto the program so far without running it, giving
+    // the program and the offset in its script the new code starts at.
+    pub fn compile(&mut self, source: &str) -> Result<(MemorySlice, usize), CauroraError> {
+        let mut compiler = Compiler::new(source, self.memory.clone(), Scanner::new(source));
+        compiler.set_path(&self.path);
+        compiler.set_echo(self.echo);
+        compiler.set_fold(self.fold);
+        compiler.set_optimize(self.optimize);
+        let compiled = compiler.compile_incremental();
+        self.warnings = compiler.warnings().to_vec();
+        self.compile_stats = compiler.stats();
+        compiled.map_err(CauroraError::Compile)
+    }
+
+    // Makes `memory` the program, as compiled by `compile` or loaded from
+    // bytecode, and runs its script from `entry`.
+    pub fn execute(&mut self, memory: MemorySlice, entry: usize) -> Result<(), CauroraError> {
+        self.memory = memory;
+        let mut vm = self
+            .vm_builder(&self.memory)
+            .carry_globals(std::mem::take(&mut self.globals))
+            .build();
+        vm.set_file_error(self.file_error.take());
+        let result = vm.resume_at(entry);
+        self.execution_stats = vm.stats().cloned();
+        self.profile = vm.profile().cloned();
+        self.random = vm.random().clone();
+        self.file_error = vm.file_error().map(str::to_owned);
+        let error = vm.error().cloned();
+        self.globals = vm.into_globals();
+        outcome(result, error)
+    }
+
+    // Runs each test block of the program on a copy of the globals the
+    // script left, passing its name and how it went to `report` as it
+    // finishes.
+    pub fn run_tests(&mut self, mut report: impl FnMut(&str, Result<(), CauroraError>)) {
+        for (name, chunk) in self.memory.get_tests() {
+            let mut vm = self.vm_builder(&self.memory).carry_globals(self.globals.clone()).build();
+            let result = vm.run_function(*chunk);
+            report(name, outcome(result, vm.error().cloned()));
+        }
+    }
+
+    fn vm_builder<'a>(&self, memory: &'a MemorySlice) -> VmBuilder<'a> {
+        let builder = VM::builder()
+            .memory(memory)
+            .options(self.options.clone())
+            .started(self.started)
+            .random(self.random.clone())
+            .output(Box::new(Shared(self.output.clone())));
+        match &self.trace {
+            Some(trace) => builder.trace(Box::new(Shared(trace.clone()))),
+            None => builder,
+        }
+    }
+}
+
+fn outcome(result: InterpretResult, error: Option<RuntimeError>) -> Result<(), CauroraError> {
+    match (result, error) {
+        (InterpretResult::InterpretRuntimeError, Some(error)) => Err(CauroraError::Runtime(error)),
+        (InterpretResult::InterpretExit(code), _) => Err(CauroraError::Exit(code)),
+        (InterpretResult::InterpretOutOfFuel, _) => Err(CauroraError::OutOfFuel),
+        _ => Ok(()),
+    }
+}
+
+// The interpreter's output or trace, shared by the VM for each run.
+struct Shared(Rc<RefCell<Box<dyn Write>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
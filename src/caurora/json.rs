@@ -0,0 +1,72 @@
+use std::{collections::HashMap, fmt::Write, rc::Rc};
+
+use super::values::{Object, Value};
+
+// Renders globals as a JSON object with sorted keys. Values JSON cannot hold
+// directly become tagged placeholders such as `{"$function":"add"}`, and
+// non-finite numbers become null.
+pub fn globals_json(globals: &HashMap<Rc<str>, Value>) -> String {
+    let mut names: Vec<&Rc<str>> = globals.keys().collect();
+    names.sort();
+    let mut out = String::from("{");
+    for (i, name) in names.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        string(&mut out, name);
+        out.push(':');
+        value(&mut out, &globals[name]);
+    }
+    out.push('}');
+    out
+}
+
+fn value(out: &mut String, value: &Value) {
+    match value {
+        Value::Number(x) if x.is_finite() => write!(out, "{}", x).unwrap(),
+        Value::Number(_) | Value::Nil | Value::Raw => out.push_str("null"),
+        Value::Bool(b) => write!(out, "{}", b).unwrap(),
+        Value::Object(Object::String(s)) => string(out, s),
+        Value::Object(Object::Array(elements)) => {
+            out.push('[');
+            for (i, element) in elements.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                self::value(out, element);
+            }
+            out.push(']');
+        }
+        Value::Object(Object::Function(function)) => tagged(out, "$function", &function.name),
+        Value::Object(Object::Closure(closure)) => self::value(out, &closure.function),
+        Value::Object(Object::NativeFunction(native)) => tagged(out, "$native", &native.name),
+        Value::Object(Object::Module(module)) => tagged(out, "$module", &module.name),
+        Value::Object(Object::Class(class)) => tagged(out, "$class", &class.name),
+        Value::Object(Object::Instance(instance)) => tagged(out, "$instance", &instance.class.name),
+        Value::Object(Object::BoundMethod(_)) => tagged(out, "$method", &value.to_string()),
+    }
+}
+
+fn tagged(out: &mut String, tag: &str, name: &str) {
+    out.push('{');
+    string(out, tag);
+    out.push(':');
+    string(out, name);
+    out.push('}');
+}
+
+fn string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
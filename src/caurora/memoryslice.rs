@@ -1,110 +1,511 @@
-use super::{opcodes::OpCode, values::Value};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+use super::{
+    opcodes::OpCode,
+    values::{Object, Value},
+};
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MemorySlice {
-    memory: Vec<u16>,
+    // The script's code is chunk zero; every function, method and test body
+    // is compiled into a chunk of its own.
+    chunks: Vec<Chunk>,
+    // The chunks being compiled into, innermost last. Code goes to the
+    // script when none is open.
+    open: Vec<usize>,
     constants: Vec<Value>,
-    lines: Vec<u16>,
+    // Where each shareable constant already sits, so pushing one again finds
+    // its slot without scanning `constants`.
+    constant_slots: HashMap<ConstantKey, u32>,
+    // The name of every global the code refers to, indexed by the slot the
+    // global opcodes carry.
+    globals: Vec<Rc<str>>,
+    global_slots: HashMap<Rc<str>, u16>,
+    // Globals declared with `const`, kept so code compiled onto this program
+    // later cannot assign to them either. Only the compiler needs them.
+    constant_globals: HashSet<Rc<str>>,
+    line: usize,
+    deduplicated: usize,
+    // The name of every test and the chunk holding its body.
+    tests: Vec<(String, usize)>,
+}
+
+// The code of the script or of one body, with the source line of every
+// word, so any offset an error happens at maps straight back to its line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    name: String,
+    code: Vec<u16>,
+    lines: Vec<usize>,
+}
+
+impl Chunk {
+    pub fn new(name: String, code: Vec<u16>, lines: Vec<usize>) -> Self {
+        Chunk { name, code, lines }
+    }
+
+    // The function the chunk is the body of, or `script`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn code(&self) -> &[u16] {
+        &self.code
+    }
+
+    pub fn lines(&self) -> &[usize] {
+        &self.lines
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn get_line(&self, offset: usize) -> usize {
+        self.lines.get(offset).copied().unwrap_or(0)
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        self.instructions_from(0)
+    }
+
+    pub fn instructions_from(&self, offset: usize) -> Instructions<'_> {
+        Instructions {
+            code: &self.code,
+            offset,
+        }
+    }
+
+    pub fn instruction_at(&self, offset: usize) -> Option<Instruction> {
+        self.instructions_from(offset).next()
+    }
+}
+
+impl Default for MemorySlice {
+    fn default() -> Self {
+        MemorySlice::new()
+    }
 }
 
 impl MemorySlice {
     pub fn new() -> Self {
-        return MemorySlice {
-            memory: Vec::<u16>::new(),
-            constants: Vec::<Value>::new(),
-            lines: Vec::<u16>::new(),  
-        };
+        MemorySlice::from_parts(
+            vec![Chunk::new("script".to_owned(), Vec::new(), Vec::new())],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        )
+    }
+
+    // Rebuilds a program from its saved parts, as loaded from a bytecode file.
+    pub fn from_parts(
+        chunks: Vec<Chunk>,
+        constants: Vec<Value>,
+        globals: Vec<Rc<str>>,
+        tests: Vec<(String, usize)>,
+    ) -> Self {
+        let mut constant_slots = HashMap::new();
+        for (index, constant) in constants.iter().enumerate() {
+            if let Some(key) = ConstantKey::of(constant) {
+                constant_slots.entry(key).or_insert(index as u32);
+            }
+        }
+        let global_slots = globals
+            .iter()
+            .enumerate()
+            .map(|(slot, name)| (name.clone(), slot as u16))
+            .collect();
+        MemorySlice {
+            chunks,
+            open: Vec::new(),
+            constants,
+            constant_slots,
+            globals,
+            global_slots,
+            constant_globals: HashSet::new(),
+            line: 0,
+            deduplicated: 0,
+            tests,
+        }
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    pub fn chunk(&self, index: usize) -> Option<&Chunk> {
+        self.chunks.get(index)
+    }
+
+    // The chunk code is being compiled into.
+    pub fn current(&self) -> &Chunk {
+        &self.chunks[self.open.last().copied().unwrap_or(0)]
+    }
+
+    fn current_mut(&mut self) -> &mut Chunk {
+        &mut self.chunks[self.open.last().copied().unwrap_or(0)]
+    }
+
+    // Opens a new chunk for the body of `name`, which code goes to until the
+    // matching `end_chunk`. Returns its index.
+    pub fn begin_chunk(&mut self, name: String) -> usize {
+        self.chunks.push(Chunk::new(name, Vec::new(), Vec::new()));
+        self.open.push(self.chunks.len() - 1);
+        self.chunks.len() - 1
+    }
+
+    pub fn end_chunk(&mut self) {
+        self.open.pop();
+    }
+
+    // Drops the chunks from `count` on, with the tests they hold.
+    pub fn truncate_chunks(&mut self, count: usize) {
+        self.chunks.truncate(count.max(1));
+        self.open.retain(|chunk| *chunk < count);
+        self.tests.retain(|(_, chunk)| *chunk < count);
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    // Drops the constants from `count` on, which no code may use any more.
+    pub fn truncate_constants(&mut self, count: usize) {
+        self.constants.truncate(count);
+        self.constant_slots.retain(|_, index| (*index as usize) < count);
+    }
+
+    pub fn global_names(&self) -> &[Rc<str>] {
+        &self.globals
+    }
+
+    pub fn global_name(&self, slot: u16) -> Option<&str> {
+        self.globals.get(slot as usize).map(|name| &**name)
+    }
+
+    pub fn declare_constant_global(&mut self, name: &str) {
+        self.constant_globals.insert(Rc::from(name));
+    }
+
+    pub fn is_constant_global(&self, name: &str) -> bool {
+        self.constant_globals.contains(name)
+    }
+
+    // The line the words pushed from now on were compiled from.
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
     }
 
     pub fn push(&mut self, oc: OpCode) {
-        self.memory.push(oc.repr())
+        self.push_raw(oc.repr())
     }
 
     pub fn push_raw(&mut self, oc: u16) {
-        self.memory.push(oc)
+        let line = self.line;
+        let chunk = self.current_mut();
+        chunk.code.push(oc);
+        chunk.lines.push(line);
     }
 
-    pub fn line_end(&mut self) {
-        let linelocation:isize = self.memory.len() as isize -1;
-        if linelocation < 0 {
-            return
+    // Drops the script's final Eof, so more code can be appended after it.
+    pub fn pop_eof(&mut self) {
+        let script = &mut self.chunks[0];
+        if let Some(last) = script.instructions().last() {
+            if last.opcode == OpCode::Eof {
+                script.code.truncate(last.offset);
+                script.lines.truncate(last.offset);
+            }
         }
-        self.lines.push((linelocation) as u16)
     }
 
-    pub fn get_line(&mut self, op_location: u16) -> u16 {
-        let mut line = 0;
-        for new_line in &self.lines {
-            if *new_line <= op_location as u16  {
-                line += 1;
-            } else {
-                break;
-            }
-        }
-        line
+    // Drops the code of the current chunk from `size` on, with its lines.
+    pub fn truncate(&mut self, size: usize) {
+        let chunk = self.current_mut();
+        chunk.code.truncate(size);
+        chunk.lines.truncate(size);
     }
 
-    pub fn read_at_ip(&self, index: usize) -> Option<u16> {
-        self.memory.get(index).copied()
+    // Replaces the code of chunk `index` from `start` on.
+    pub fn rewrite(&mut self, index: usize, start: usize, code: Vec<u16>, lines: Vec<usize>) {
+        let chunk = &mut self.chunks[index];
+        chunk.code.truncate(start);
+        chunk.lines.truncate(start);
+        chunk.code.extend(code);
+        chunk.lines.extend(lines);
     }
-    
-    pub fn replace_at_location(&mut self, index:usize, v: u16) {
-        self.memory[index] = v
+
+    pub fn replace_at_location(&mut self, index: usize, v: u16) {
+        self.current_mut().code[index] = v
     }
 
-    pub fn get_constant(&self, index: u16) -> Option<Value>{
+    pub fn get_constant(&self, index: u32) -> Option<Value> {
         self.constants.get(index as usize).cloned()
     }
 
-    pub fn get_memory_size(&mut self) -> usize {
-        self.memory.len()
+    // The words of code in every chunk together.
+    pub fn get_memory_size(&self) -> usize {
+        self.chunks.iter().map(Chunk::len).sum()
+    }
+
+    // The words in the chunk being compiled, the offset the next one lands at.
+    pub fn chunk_size(&self) -> usize {
+        self.current().len()
+    }
+
+    pub fn get_constants_size(&self) -> usize {
+        self.constants.len()
+    }
+
+    pub fn get_deduplicated_count(&self) -> usize {
+        self.deduplicated
+    }
+
+    pub fn register_test(&mut self, name: String, chunk: usize) {
+        self.tests.push((name, chunk))
+    }
+
+    pub fn get_tests(&self) -> &[(String, usize)] {
+        &self.tests
+    }
+
+    // Every instruction, chunk by chunk.
+    pub fn instructions(&self) -> impl Iterator<Item = Instruction> + '_ {
+        self.chunks.iter().flat_map(Chunk::instructions)
+    }
+
+    pub fn opcode_counts(&self) -> Vec<(OpCode, usize)> {
+        let mut counts = Vec::<(OpCode, usize)>::new();
+        for instruction in self.instructions() {
+            match counts.iter_mut().find(|(op, _)| *op == instruction.opcode) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((instruction.opcode, 1)),
+            }
+        }
+        counts.sort_by_key(|(op, _)| op.repr());
+        counts
     }
 
-    pub fn debug(&mut self, name: &str) {
+    pub fn debug(&self, name: &str) {
         println!("== {} ==", name);
-        println!("--------------------------------");
-        println!("");
-        println!("Memory:");
-        println!("--------------------------------");
-        let mut constant = false;
-        for i in 0..self.memory.len() {
-            if !constant {
-                let opcode: OpCode = unsafe { std::mem::transmute(self.memory[i]) }; 
-                println!("{:0>4} -- {:#?} -- {:#?}", i, self.memory[i], opcode);
-                if opcode == OpCode::Constant || opcode == OpCode::DefineGlobalVar || opcode == OpCode::SetGlobalVar 
-                || opcode == OpCode::GetGlobalVar || opcode == OpCode::SetLocalVar || opcode == OpCode::GetLocalVar ||
-                opcode == OpCode::Jmp || opcode == OpCode::JmpFalse || opcode == OpCode::JmpTrue || opcode == OpCode::Loop {
-                    constant = true;
+        print!("{}", self.disassemble());
+    }
+
+    // The script's listing, then each body's under a `== name ==` header.
+    pub fn disassemble(&self) -> String {
+        let mut listing = self.disassemble_chunk(&self.chunks[0]);
+        for chunk in &self.chunks[1..] {
+            listing.push_str(&format!("\n== {} ==\n", chunk.name));
+            listing.push_str(&self.disassemble_chunk(chunk));
+        }
+        listing
+    }
+
+    // One line per instruction: offset, source line (`|` when unchanged),
+    // opcode, and its operand with what it refers to, be that a constant, a
+    // jump's destination or a call's argument count.
+    fn disassemble_chunk(&self, chunk: &Chunk) -> String {
+        let mut listing = String::new();
+        let mut previous_line = None;
+        for instruction in chunk.instructions() {
+            let line = chunk.get_line(instruction.offset);
+            let line_column = match previous_line {
+                Some(previous) if previous == line => "|".to_owned(),
+                _ => line.to_string(),
+            };
+            previous_line = Some(line);
+            let name = format!("{:?}", instruction.opcode);
+            let operand = match instruction.operand {
+                None => String::new(),
+                Some(operand) => {
+                    let detail = if let Some(target) = instruction.jump_target() {
+                        format!("-> {:04}", target)
+                    } else if instruction.opcode.takes_global() {
+                        match self.global_name(operand as u16) {
+                            Some(name) => format!("{:?}", name),
+                            None => "<missing global>".to_owned(),
+                        }
+                    } else if instruction.opcode.takes_constant() {
+                        match self.get_constant(operand) {
+                            Some(Value::Object(Object::String(s))) => format!("{:?}", s),
+                            Some(value) => value.to_string(),
+                            None => "<missing constant>".to_owned(),
+                        }
+                    } else if matches!(instruction.opcode, OpCode::Call | OpCode::TailCall) {
+                        "args".to_owned()
+                    } else {
+                        String::new()
+                    };
+                    format!("{:>5} {}", operand, detail)
                 }
-            }else {
-                constant = false;
-                let constant_value = self.get_constant(self.memory[i]).or_else(|| Some(Value::Raw)).unwrap();
-                println!("{:0>4} -- {:#?} -- {:#?}", i, self.memory[i], constant_value);
-            }
+            };
+            let text = format!("{:04} {:>4} {:<16}{}", instruction.offset, line_column, name, operand);
+            listing.push_str(text.trim_end());
+            listing.push('\n');
         }
-        println!("");
-        println!("Constants:");
-        println!("--------------------------------");
-        for i in 0..self.constants.len() {
-            println!("{:0>4} -- {:#?}", i, self.constants[i])
+        listing
+    }
+
+    // Emits `op` with `v`'s constant, sharing the slot of an identical
+    // number, string, bool or nil pushed before. Other values always get a
+    // slot of their own.
+    pub fn push_constant(&mut self, op: OpCode, v: Value) -> Result<u32, String> {
+        let key = ConstantKey::of(&v);
+        let index = match key.as_ref().and_then(|key| self.constant_slots.get(key)) {
+            Some(index) => {
+                self.deduplicated += 1;
+                *index
+            }
+            None => {
+                let index = self.constants.len() as u32;
+                self.constants.push(v);
+                if let Some(key) = key {
+                    self.constant_slots.insert(key, index);
+                }
+                index
+            }
+        };
+        self.push_constant_operand(op, index)?;
+        Ok(index)
+    }
+
+    // Emits `op` with the constant at `index`. A constant past u16::MAX is
+    // loaded with ConstantLong and its two-word operand; no other opcode has
+    // a long form, so they cannot name one.
+    pub fn push_constant_operand(&mut self, op: OpCode, index: u32) -> Result<(), String> {
+        match u16::try_from(index) {
+            Ok(index) => {
+                self.push(op);
+                self.push_raw(index);
+            }
+            Err(_) if op == OpCode::Constant => {
+                self.push(OpCode::ConstantLong);
+                self.push_raw(index as u16);
+                self.push_raw((index >> 16) as u16);
+            }
+            Err(_) => return Err("too many constants in one program".to_owned()),
         }
-        println!("");
+        Ok(())
+    }
+
+    // Emits `op` with the slot of the global called `name`, giving the name
+    // the next slot the first time it is seen.
+    pub fn push_global(&mut self, op: OpCode, name: &str) -> Result<(), String> {
+        let slot = match self.global_slots.get(name) {
+            Some(slot) => *slot,
+            None => {
+                let slot = u16::try_from(self.globals.len())
+                    .map_err(|_| "too many global variables in one program".to_owned())?;
+                let name: Rc<str> = name.into();
+                self.globals.push(name.clone());
+                self.global_slots.insert(name, slot);
+                slot
+            }
+        };
+        self.push(op);
+        self.push_raw(slot);
+        Ok(())
+    }
+
+    // Adds a constant slot, never shared, for `replace_constant` to fill.
+    pub fn reserve_constant(&mut self) -> u32 {
+        self.constants.push(Value::Nil);
+        (self.constants.len() - 1) as u32
     }
 
-    pub fn push_constant(&mut self, op: OpCode,v: Value) {
-        let mut index = self.constants.len();
-        let mut found = false;
-        for i in 0..self.constants.len() {
-            if self.constants[i] == v {
-                index = i;
-                found = true;
-                break;
+    // Swaps in a constant's final value once the compiler knows it.
+    pub fn replace_constant(&mut self, index: u32, v: Value) {
+        let old = std::mem::replace(&mut self.constants[index as usize], v);
+        if let Some(key) = ConstantKey::of(&old) {
+            if self.constant_slots.get(&key) == Some(&index) {
+                self.constant_slots.remove(&key);
             }
         }
-        if !found {
-            self.constants.push(v);
+    }
+
+    // Emits `op` with an index the caller already knows holds the right
+    // constant, counting it as a deduplicated hit.
+    pub fn push_constant_index(&mut self, op: OpCode, index: u32) -> Result<(), String> {
+        self.deduplicated += 1;
+        self.push_constant_operand(op, index)
+    }
+}
+
+// A constant as the deduplication index sees it. Numbers are keyed by their
+// bits, so -0.0 keeps a slot apart from 0.0, and a NaN shares only with a NaN
+// of the same bits, which behaves identically.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(Rc<str>),
+    Bool(bool),
+    Nil,
+}
+
+impl ConstantKey {
+    fn of(value: &Value) -> Option<ConstantKey> {
+        match value {
+            Value::Number(x) => Some(ConstantKey::Number(x.to_bits())),
+            Value::Object(Object::String(s)) => Some(ConstantKey::String(s.clone())),
+            Value::Bool(b) => Some(ConstantKey::Bool(*b)),
+            Value::Nil => Some(ConstantKey::Nil),
+            _ => None,
         }
-        self.push(op);
-        self.memory.push(index as u16);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub opcode: OpCode,
+    pub operand: Option<u32>,
+}
+
+impl Instruction {
+    pub fn size(&self) -> usize {
+        1 + self.opcode.operand_count()
+    }
+
+    pub fn jump_target(&self) -> Option<usize> {
+        let steps = self.operand? as usize;
+        match self.opcode {
+            OpCode::Jmp | OpCode::JmpFalse | OpCode::JmpTrue => Some(self.offset + self.size() + steps),
+            OpCode::Loop => Some(self.offset + self.size() - steps),
+            _ => None,
+        }
+    }
+}
+
+pub struct Instructions<'a> {
+    code: &'a [u16],
+    offset: usize,
+}
+
+// Stops at the end of the code, or at a word that is no opcode.
+impl Iterator for Instructions<'_> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Instruction> {
+        let raw = *self.code.get(self.offset)?;
+        let opcode = OpCode::try_from(raw).ok()?;
+        let word = |n: usize| self.code.get(self.offset + n).map(|word| u32::from(*word));
+        let operand = match opcode.operand_count() {
+            0 => None,
+            1 => word(1),
+            _ => word(1).zip(word(2)).map(|(low, high)| low | high << 16),
+        };
+        let instruction = Instruction {
+            offset: self.offset,
+            opcode,
+            operand,
+        };
+        self.offset += instruction.size();
+        Some(instruction)
     }
 }
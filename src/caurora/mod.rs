@@ -5,4 +5,13 @@ pub mod virtualmachine;
 pub mod errorlogger;
 pub mod scanner;
 pub mod compiler;
-pub mod token;
\ No newline at end of file
+pub mod token;
+pub mod stats;
+pub mod decompiler;
+pub mod natives;
+pub mod interner;
+pub mod json;
+pub mod bytecode;
+pub mod interpreter;
+pub mod random;
+pub mod peephole;
@@ -0,0 +1,455 @@
+use std::{
+    cell::RefCell,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    rc::Rc,
+};
+
+use super::{
+    random::Random,
+    values::{Module, Native, NativeFn, NativeFunction, Object, Value, VARIADIC},
+    virtualmachine::VM,
+};
+
+pub fn register_builtins(vm: &mut VM) {
+    vm.register_native("assert_eq", 2, assert_eq);
+    vm.register_native("split", 2, split);
+    vm.register_native("join", 2, join);
+    vm.register_native("len", 1, len);
+    vm.register_native("min", VARIADIC, min);
+    vm.register_native("max", VARIADIC, max);
+    vm.register_native("clamp", 3, clamp);
+    vm.register_native("to_hex", VARIADIC, to_hex);
+    vm.register_native("to_bin", VARIADIC, to_bin);
+    vm.register_native("parse_int", 2, parse_int);
+    vm.register_native("clock", 0, clock);
+    vm.register_native("sqrt", 1, sqrt);
+    vm.register_native("abs", 1, abs);
+    vm.register_native("floor", 1, floor);
+    vm.register_native("ceil", 1, ceil);
+    vm.register_native("round", 1, round);
+    vm.register_native("rand", 0, rand);
+    vm.register_native("rand_range", 2, rand_range);
+    vm.register_native("seed", 1, seed);
+    vm.register_native("substr", 3, substr);
+    vm.register_native("upper", 1, upper);
+    vm.register_native("lower", 1, lower);
+    vm.register_native("find", 2, find);
+    vm.register_native("type", 1, type_of);
+    vm.register_native("num", 1, num);
+    vm.register_native("input", VARIADIC, input);
+    vm.register_native("write", 1, write);
+    vm.register_native("exit", VARIADIC, exit);
+
+    let mut math = Module::natives(
+        "math",
+        &[
+            ("sqrt", 1, sqrt),
+            ("abs", 1, abs),
+            ("floor", 1, floor),
+            ("ceil", 1, ceil),
+            ("round", 1, round),
+            ("min", VARIADIC, min),
+            ("max", VARIADIC, max),
+            ("clamp", 3, clamp),
+            ("rand", 0, rand),
+            ("rand_range", 2, rand_range),
+            ("seed", 1, seed),
+        ],
+    );
+    math.members.push(("pi".to_owned(), Value::Number(std::f64::consts::PI)));
+    vm.define_module(math);
+
+    // `str` is both the string module and the conversion `str(x)`.
+    let strings = Module::natives(
+        "str",
+        &[
+            ("upper", 1, upper),
+            ("lower", 1, lower),
+            ("substr", 3, substr),
+            ("find", 2, find),
+            ("split", 2, split),
+            ("join", 2, join),
+            ("parse_int", 2, parse_int),
+        ],
+    )
+    .callable(Value::Object(Object::NativeFunction(Rc::new(NativeFunction {
+            name: "str".to_owned(),
+            arity: 1,
+            function: Native(str),
+        }))));
+    vm.define_module(strings);
+
+    vm.register_module("io", IO);
+}
+
+// Console input and output; the filesystem builtins join these in `io` when
+// they are registered.
+const IO: &[(&str, usize, NativeFn)] = &[("write", 1, write), ("input", VARIADIC, input)];
+
+fn number(args: &[Value], index: usize) -> Result<f64, String> {
+    match &args[index] {
+        Value::Number(x) => Ok(*x),
+        value => Err(format!("expected a number, got {}", value.type_name())),
+    }
+}
+
+fn string(args: &[Value], index: usize) -> Result<&str, String> {
+    match &args[index] {
+        Value::Object(Object::String(s)) => Ok(s),
+        value => Err(format!("expected a string, got {}", value.type_name())),
+    }
+}
+
+fn array(args: &[Value], index: usize) -> Result<Rc<RefCell<Vec<Value>>>, String> {
+    match &args[index] {
+        Value::Object(Object::Array(elements)) => Ok(elements.clone()),
+        value => Err(format!("expected an array, got {}", value.type_name())),
+    }
+}
+
+fn clock(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.elapsed()))
+}
+
+fn rand(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.random().next_f64()))
+}
+
+fn rand_range(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let lo = number(args, 0)?;
+    let hi = number(args, 1)?;
+    if lo > hi || lo.is_nan() || hi.is_nan() {
+        return Err(format!("lower bound {} is greater than upper bound {}", lo, hi));
+    }
+    Ok(Value::Number(lo + (hi - lo) * vm.random().next_f64()))
+}
+
+// Reseeds the generator so the numbers that follow repeat from run to run.
+fn seed(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    *vm.random() = Random::new(integer(args, 0)? as u64);
+    Ok(Value::Nil)
+}
+
+fn sqrt(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number(args, 0)?.sqrt()))
+}
+
+fn abs(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number(args, 0)?.abs()))
+}
+
+fn floor(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number(args, 0)?.floor()))
+}
+
+fn ceil(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number(args, 0)?.ceil()))
+}
+
+// Halves round away from zero, so `round(-2.5)` is -3.
+fn round(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(number(args, 0)?.round()))
+}
+
+// `min` and `max` take two or more numbers, or a single array of them.
+fn numbers(args: &[Value]) -> Result<Vec<f64>, String> {
+    let values = match args {
+        [Value::Object(Object::Array(elements))] => elements.borrow().clone(),
+        _ => args.to_vec(),
+    };
+    if values.len() < 2 {
+        return Err(format!("expected at least two numbers, got {}", values.len()));
+    }
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| match value {
+            Value::Number(x) => Ok(*x),
+            _ => Err(format!("argument {}: expected a number, got {}", i, value.type_name())),
+        })
+        .collect()
+}
+
+// NaN is contagious here, unlike `f64::min`, which would quietly drop it.
+fn extreme(args: &[Value], pick: fn(f64, f64) -> f64) -> Result<Value, String> {
+    let numbers = numbers(args)?;
+    if numbers.iter().any(|x| x.is_nan()) {
+        return Ok(Value::Number(f64::NAN));
+    }
+    Ok(Value::Number(numbers.into_iter().reduce(pick).unwrap()))
+}
+
+fn min(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    extreme(args, f64::min)
+}
+
+fn max(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    extreme(args, f64::max)
+}
+
+fn clamp(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let x = number(args, 0)?;
+    let lo = number(args, 1)?;
+    let hi = number(args, 2)?;
+    if lo > hi || lo.is_nan() || hi.is_nan() {
+        return Err(format!("lower bound {} is greater than upper bound {}", lo, hi));
+    }
+    Ok(Value::Number(if x.is_nan() { x } else { x.clamp(lo, hi) }))
+}
+
+fn integer(args: &[Value], index: usize) -> Result<i64, String> {
+    let x = number(args, index)?;
+    if !x.is_finite() || x.fract() != 0.0 || x.abs() >= 2f64.powi(63) {
+        return Err(format!("expected an integer, got {}", x));
+    }
+    Ok(x as i64)
+}
+
+// A whole number as a count, negatives giving zero and huge values
+// saturating.
+fn clamped(args: &[Value], index: usize) -> Result<usize, String> {
+    let x = number(args, index)?;
+    if x.is_nan() || (x.is_finite() && x.fract() != 0.0) {
+        return Err(format!("expected an integer, got {}", x));
+    }
+    Ok(x.max(0.0) as usize)
+}
+
+// Renders `n` in base 16 or 2, zero-padding the digits to the optional
+// width argument; negative numbers keep their sign in front.
+fn radix_string(args: &[Value], format: fn(u64) -> String) -> Result<Value, String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err(format!("expected a number and an optional width, got {} arguments", args.len()));
+    }
+    let n = integer(args, 0)?;
+    let width = match args.len() {
+        2 => integer(args, 1)?.max(0) as usize,
+        _ => 0,
+    };
+    let sign = if n < 0 { "-" } else { "" };
+    let digits = format(n.unsigned_abs());
+    Ok(Value::Object(Object::String(format!("{}{:0>width$}", sign, digits, width = width).into())))
+}
+
+fn to_hex(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    radix_string(args, |n| format!("{:x}", n))
+}
+
+fn to_bin(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    radix_string(args, |n| format!("{:b}", n))
+}
+
+// Unparseable input gives nil so scripts can test for it; a bad radix is
+// a mistake in the script and raises an error.
+fn parse_int(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string(args, 0)?;
+    let radix = integer(args, 1)?;
+    if !(2..=36).contains(&radix) {
+        return Err(format!("radix must be between 2 and 36, got {}", radix));
+    }
+    Ok(match i64::from_str_radix(text.trim(), radix as u32) {
+        Ok(n) => Value::Number(n as f64),
+        Err(_) => Value::Nil,
+    })
+}
+
+fn upper(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Object(Object::String(string(args, 0)?.to_uppercase().into())))
+}
+
+fn lower(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Object(Object::String(string(args, 0)?.to_lowercase().into())))
+}
+
+// Counts in characters. A start or count outside the string is clamped to it,
+// however far outside; only NaN or a fraction is an error.
+fn substr(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let s = string(args, 0)?;
+    let start = clamped(args, 1)?;
+    let count = clamped(args, 2)?;
+    let part: String = s.chars().skip(start).take(count).collect();
+    Ok(Value::Object(Object::String(part.into())))
+}
+
+// The character index of the first occurrence of the needle, or -1.
+fn find(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let haystack = string(args, 0)?;
+    let needle = string(args, 1)?;
+    let index = match haystack.find(needle) {
+        Some(byte) => haystack[..byte].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::Number(index))
+}
+
+// An empty string splits into an empty array rather than one empty element.
+fn split(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let text = string(args, 0)?;
+    let separator = string(args, 1)?;
+    if separator.is_empty() {
+        return Err("split separator must not be empty".to_owned());
+    }
+    let parts = match text {
+        "" => Vec::new(),
+        _ => text
+            .split(separator)
+            .map(|part| Value::Object(Object::String(part.into())))
+            .collect(),
+    };
+    Ok(Value::Object(Object::Array(Rc::new(RefCell::new(parts)))))
+}
+
+fn join(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let elements = array(args, 0)?;
+    let separator = string(args, 1)?;
+    let parts: Vec<String> = elements.borrow().iter().map(|e| e.to_string()).collect();
+    let size = parts.iter().map(String::len).sum::<usize>()
+        + separator.len() * parts.len().saturating_sub(1);
+    let mut joined = String::with_capacity(size);
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            joined.push_str(separator);
+        }
+        joined.push_str(part);
+    }
+    Ok(Value::Object(Object::String(joined.into())))
+}
+
+// The number of elements in an array or characters in a string.
+fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let length = match &args[0] {
+        Value::Object(Object::Array(elements)) => elements.borrow().len(),
+        Value::Object(Object::String(s)) => s.chars().count(),
+        value => return Err(format!("expected an array or a string, got {}", value.type_name())),
+    };
+    Ok(Value::Number(length as f64))
+}
+
+// The same text `join` shows for the value: strings come back unquoted.
+fn str(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Object(Object::String(args[0].to_string().into())))
+}
+
+// Parses a string as a number, giving nil when it is not one. Bools are
+// rejected rather than guessed at.
+fn num(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Number(x) => Ok(Value::Number(*x)),
+        Value::Object(Object::String(s)) => Ok(match s.trim().parse::<f64>() {
+            Ok(x) if x.is_finite() => Value::Number(x),
+            _ => Value::Nil,
+        }),
+        value => Err(format!("expected a number or a string, got {}", value.type_name())),
+    }
+}
+
+// Prints the value as `print` does, without a newline after it.
+fn write(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    vm.write(&args[0].to_string()).map_err(|e| e.to_string())?;
+    Ok(Value::Nil)
+}
+
+// Reads a line, first printing the prompt if one is given. Gives nil once
+// the input is exhausted.
+fn input(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let prompt = match args.len() {
+        0 => None,
+        1 => Some(string(args, 0)?),
+        n => return Err(format!("expected at most one argument, got {}", n)),
+    };
+    Ok(match vm.read_line(prompt).map_err(|e| e.to_string())? {
+        Some(line) => Value::Object(Object::String(line.into())),
+        None => Value::Nil,
+    })
+}
+
+// Filesystem access is kept apart so embedders can leave it out.
+pub fn register_file_builtins(vm: &mut VM) {
+    vm.register_native("read_file", 1, read_file);
+    vm.register_native("write_file", 2, write_file);
+    vm.register_native("append_file", 2, append_file);
+    vm.register_native("last_error", 0, last_error);
+
+    let files: &[(&str, usize, NativeFn)] = &[
+        ("read_file", 1, read_file),
+        ("write_file", 2, write_file),
+        ("append_file", 2, append_file),
+        ("last_error", 0, last_error),
+    ];
+    vm.register_module("io", &[IO, files].concat());
+}
+
+// Records how a file operation went for `last_error()`, giving back whether
+// it succeeded.
+fn file_result<T>(vm: &mut VM, result: io::Result<T>) -> Option<T> {
+    match result {
+        Ok(value) => {
+            vm.set_file_error(None);
+            Some(value)
+        }
+        Err(e) => {
+            vm.set_file_error(Some(e.to_string()));
+            None
+        }
+    }
+}
+
+fn read_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let contents = fs::read_to_string(string(args, 0)?);
+    Ok(match file_result(vm, contents) {
+        Some(contents) => Value::Object(Object::String(contents.into())),
+        None => Value::Nil,
+    })
+}
+
+fn write_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let written = fs::write(string(args, 0)?, string(args, 1)?);
+    Ok(Value::Bool(file_result(vm, written).is_some()))
+}
+
+fn append_file(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let path = string(args, 0)?;
+    let contents = string(args, 1)?;
+    let appended = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()));
+    Ok(Value::Bool(file_result(vm, appended).is_some()))
+}
+
+// The message from the last file builtin that failed, or nil if the last
+// one succeeded.
+fn last_error(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(match vm.file_error() {
+        Some(message) => Value::Object(Object::String(message.into())),
+        None => Value::Nil,
+    })
+}
+
+// Stops the script with the given status, 0 by default.
+fn exit(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let code = match args.len() {
+        0 => 0,
+        1 => integer(args, 0)?,
+        n => return Err(format!("expected at most one argument, got {}", n)),
+    };
+    let code = i32::try_from(code).map_err(|_| format!("exit code {} is out of range", code))?;
+    vm.request_exit(code);
+    Ok(Value::Nil)
+}
+
+fn type_of(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Object(Object::String(args[0].type_name().into())))
+}
+
+fn assert_eq(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if !args[0].equals(&args[1]) {
+        return Err(format!(
+            "values differ\n    left:  {}\n    right: {}",
+            args[0], args[1]
+        ));
+    }
+    Ok(Value::Nil)
+}
@@ -1,7 +1,28 @@
+// Declares the opcodes, numbered from zero in order, along with the
+// conversion from a code word back to its opcode, so the two cannot drift
+// apart.
+macro_rules! opcodes {
+    ($($name:ident,)*) => {
+        #[repr(u16)]
+        #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+        pub enum OpCode {
+            $($name,)*
+        }
 
-#[repr(u16)]
-#[derive(Debug,Clone, Copy,PartialEq, PartialOrd)]
-pub enum OpCode {
+        // Gives back the word when no opcode has it. The VM decodes every
+        // instruction with this, so it is a single bounds-checked lookup.
+        impl TryFrom<u16> for OpCode {
+            type Error = u16;
+
+            fn try_from(word: u16) -> Result<Self, u16> {
+                const ALL: &[OpCode] = &[$(OpCode::$name,)*];
+                ALL.get(word as usize).copied().ok_or(word)
+            }
+        }
+    };
+}
+
+opcodes! {
     Add,
     Subtract,
     Multiply,
@@ -17,9 +38,9 @@ pub enum OpCode {
     Negate,
     Print,
     Pop,
-    SetGlobalVar,
-    GetGlobalVar,
-    DefineGlobalVar,
+    SetGlobalSlot,
+    GetGlobalSlot,
+    DefineGlobalSlot,
     SetLocalVar,
     GetLocalVar,
     Jmp,
@@ -27,11 +48,35 @@ pub enum OpCode {
     JmpFalse,
     Loop,
     Panic,
-    PopStoreTmp,
     Call,
-    SetSP,
     Eof,
-    Return
+    Return,
+    AssertStack,
+    GetProperty,
+    Power,
+    Dup,
+    BuildArray,
+    IndexGet,
+    IndexSet,
+    Class,
+    Method,
+    SetProperty,
+    Inherit,
+    GetSuper,
+    GetUpvalue,
+    SetUpvalue,
+    Closure,
+    CaptureLocal,
+    CaptureUpvalue,
+    CloseUpvalue,
+    Assert,
+    ArgMissing,
+    TailCall,
+    PrintN,
+    // Drops the top n values, as leaving a scope with several locals does.
+    PopN,
+    // Constant for an index past u16::MAX, in two words, low word first.
+    ConstantLong,
 }
 
 impl OpCode {
@@ -41,4 +86,64 @@ impl OpCode {
         // field, so we can read the discriminant without offsetting the pointer.
         unsafe { *<*const _>::from(self).cast::<u16>() }
     }
-}
\ No newline at end of file
+
+    // Whether the operand indexes the constant table rather than being a
+    // count, slot or jump distance.
+    pub fn takes_constant(&self) -> bool {
+        matches!(
+            self,
+            OpCode::Constant
+                | OpCode::ConstantLong
+                | OpCode::SetLocalVar
+                | OpCode::GetLocalVar
+                | OpCode::GetProperty
+                | OpCode::SetProperty
+                | OpCode::Class
+                | OpCode::GetSuper
+                | OpCode::Method
+                | OpCode::Assert
+        )
+    }
+
+    // Whether the operand is a slot in the program's table of global names.
+    pub fn takes_global(&self) -> bool {
+        matches!(
+            self,
+            OpCode::DefineGlobalSlot | OpCode::SetGlobalSlot | OpCode::GetGlobalSlot
+        )
+    }
+
+    // How many words follow the opcode. Jumps take two, a 32-bit distance
+    // with its low word first, so no body is too long to jump over, and so
+    // does ConstantLong's index.
+    pub fn operand_count(&self) -> usize {
+        match self {
+            OpCode::Jmp | OpCode::JmpFalse | OpCode::JmpTrue | OpCode::Loop | OpCode::ConstantLong => 2,
+            OpCode::Constant
+            | OpCode::DefineGlobalSlot
+            | OpCode::SetGlobalSlot
+            | OpCode::GetGlobalSlot
+            | OpCode::SetLocalVar
+            | OpCode::GetLocalVar
+            | OpCode::Call
+            | OpCode::AssertStack
+            | OpCode::BuildArray
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Closure
+            | OpCode::CaptureLocal
+            | OpCode::CaptureUpvalue
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Class
+            | OpCode::GetSuper
+            | OpCode::Method
+            | OpCode::Assert
+            | OpCode::ArgMissing
+            | OpCode::TailCall
+            | OpCode::PrintN
+            | OpCode::PopN => 1,
+            _ => 0,
+        }
+    }
+}
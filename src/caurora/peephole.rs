@@ -0,0 +1,165 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    memoryslice::{Instruction, MemorySlice},
+    opcodes::OpCode,
+};
+
+// Rewrites redundant instruction sequences in chunk `chunk` from `start` on,
+// pass after pass until none is left:
+//
+// - a jump to a `Jmp` goes straight to where that one leads, and a `Jmp` to
+//   the next instruction is dropped;
+// - `True JmpFalse` and `False JmpTrue`, which never jump, lose the jump;
+// - a literal pushed only to be popped is dropped with its `Pop`;
+// - `Not Not` after an instruction that leaves a bool, and `Negate Negate`
+//   after one that leaves a number, are dropped. Anywhere else they turn a
+//   value into a bool or fail on a non-number, so they stay.
+//
+// Nothing a jump lands on is merged with what comes before it, since the
+// jump arrives with a different stack.
+pub fn optimize(memory: &mut MemorySlice, chunk: usize, start: usize) {
+    while pass(memory, chunk, start) {}
+}
+
+fn pass(memory: &mut MemorySlice, chunk: usize, start: usize) -> bool {
+    let Some(body) = memory.chunk(chunk) else {
+        return false;
+    };
+    let code: Vec<Instruction> = body.instructions_from(start).collect();
+    let end = code.last().map_or(start, |last| last.offset + last.size());
+    if end != body.len() {
+        return false;
+    }
+    let index: HashMap<usize, usize> = code
+        .iter()
+        .enumerate()
+        .map(|(i, instruction)| (instruction.offset, i))
+        .collect();
+    let mut targets: Vec<Option<usize>> = code.iter().map(Instruction::jump_target).collect();
+    if targets
+        .iter()
+        .flatten()
+        .any(|target| *target != end && !index.contains_key(target))
+    {
+        return false;
+    }
+    let landed_on: HashSet<usize> = targets.iter().flatten().copied().collect();
+
+    let mut keep = vec![true; code.len()];
+    let mut changed = false;
+    for i in 0..code.len() {
+        if !keep[i] {
+            continue;
+        }
+        let opcode = code[i].opcode;
+        if matches!(opcode, OpCode::Jmp | OpCode::JmpFalse | OpCode::JmpTrue) {
+            let mut target = targets[i].unwrap();
+            while let Some(&j) = index.get(&target) {
+                match code[j].opcode {
+                    OpCode::Jmp => target = targets[j].unwrap(),
+                    _ => break,
+                }
+            }
+            if targets[i] != Some(target) {
+                targets[i] = Some(target);
+                changed = true;
+            }
+            if opcode == OpCode::Jmp && target == code[i].offset + code[i].size() {
+                keep[i] = false;
+                changed = true;
+                continue;
+            }
+        }
+
+        let Some(next) = code.get(i + 1) else {
+            continue;
+        };
+        if !keep[i + 1] || landed_on.contains(&next.offset) {
+            continue;
+        }
+        let pair = (opcode, next.opcode);
+        let unused = is_literal(opcode) && next.opcode == OpCode::Pop;
+        let cancelling = matches!(
+            pair,
+            (OpCode::Not, OpCode::Not) | (OpCode::Negate, OpCode::Negate)
+        ) && i > 0
+            && keep[i - 1]
+            && !landed_on.contains(&code[i].offset)
+            && leaves(code[i - 1].opcode) == Some(opcode);
+        if matches!(
+            pair,
+            (OpCode::True, OpCode::JmpFalse) | (OpCode::False, OpCode::JmpTrue)
+        ) {
+            keep[i + 1] = false;
+        } else if unused || cancelling {
+            keep[i] = false;
+            keep[i + 1] = false;
+        } else {
+            continue;
+        }
+        changed = true;
+    }
+    if !changed {
+        return false;
+    }
+
+    let mut moved = HashMap::new();
+    let mut offset = start;
+    for (instruction, kept) in code.iter().zip(&keep) {
+        moved.insert(instruction.offset, offset);
+        if *kept {
+            offset += instruction.size();
+        }
+    }
+    moved.insert(end, offset);
+
+    let mut words = Vec::with_capacity(offset - start);
+    let mut lines = Vec::with_capacity(offset - start);
+    for (i, instruction) in code.iter().enumerate().filter(|(i, _)| keep[*i]) {
+        let at = moved[&instruction.offset];
+        let range = instruction.offset..instruction.offset + instruction.size();
+        lines.extend_from_slice(&body.lines()[range.clone()]);
+        match targets[i] {
+            Some(target) => {
+                let after = at + instruction.size();
+                let steps = match instruction.opcode {
+                    OpCode::Loop => after - moved[&target],
+                    _ => moved[&target] - after,
+                };
+                words.extend([
+                    instruction.opcode.repr(),
+                    steps as u16,
+                    (steps >> 16) as u16,
+                ]);
+            }
+            None => words.extend_from_slice(&body.code()[range]),
+        }
+    }
+    memory.rewrite(chunk, start, words, lines);
+    true
+}
+
+fn is_literal(opcode: OpCode) -> bool {
+    matches!(
+        opcode,
+        OpCode::Nil | OpCode::True | OpCode::False | OpCode::Constant | OpCode::ConstantLong
+    )
+}
+
+// The operator whose double application is a no-op on what `opcode` always
+// leaves: `Not` after a bool, `Negate` after a number.
+fn leaves(opcode: OpCode) -> Option<OpCode> {
+    match opcode {
+        OpCode::Not
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::True
+        | OpCode::False => Some(OpCode::Not),
+        OpCode::Negate | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::Power => {
+            Some(OpCode::Negate)
+        }
+        _ => None,
+    }
+}
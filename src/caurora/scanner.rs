@@ -1,79 +1,94 @@
-use std::collections::HashMap;
+use std::rc::Rc;
 
 use super::token::{Token, TokenType};
 
-pub struct Scanner<'a> {
-    source: &'a str,
+// Holds its own handle on the source, so a scanner can outlive whatever the
+// text was read from.
+pub struct Scanner {
+    source: Rc<str>,
     start: usize,
     current: usize,
+    // Where `current` is, and where the token being scanned began. Lines and
+    // columns both count from 1.
     line: usize,
-    keywords: HashMap<String, TokenType>,
+    column: usize,
+    start_line: usize,
+    start_column: usize,
+    source_id: usize,
     pub error_msg: String,
 }
 
-impl Scanner<'_> {
-    pub fn new(src: &'static str) -> Self {
-        let mut keywords = HashMap::<String, TokenType>::new();
-        keywords.insert("and".to_owned(), TokenType::And);
-        keywords.insert("class".to_owned(), TokenType::Class);
-        keywords.insert("else".to_owned(), TokenType::Else);
-        keywords.insert("false".to_owned(), TokenType::False);
-        keywords.insert("for".to_owned(), TokenType::For);
-        keywords.insert("function".to_owned(), TokenType::Fun);
-        keywords.insert("if".to_owned(), TokenType::If);
-        keywords.insert("nil".to_owned(), TokenType::Nil);
-        keywords.insert("or".to_owned(), TokenType::Or);
-        keywords.insert("print".to_owned(), TokenType::Print);
-        keywords.insert("return".to_owned(), TokenType::Return);
-        keywords.insert("super".to_owned(), TokenType::Super);
-        keywords.insert("this".to_owned(), TokenType::This);
-        keywords.insert("true".to_owned(), TokenType::True);
-        keywords.insert("var".to_owned(), TokenType::Var);
-        keywords.insert("while".to_owned(), TokenType::While);
+impl Scanner {
+    pub fn new(src: impl Into<Rc<str>>) -> Self {
         Scanner {
-            source: src,
+            source: src.into(),
             start: 0,
             current: 0,
-            line: 0,
+            line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+            source_id: 0,
             error_msg: "".to_owned(),
-            keywords,
         }
     }
 
+    // Tags every token with the id of the source it came from, so diagnostics
+    // in included files point at the right file.
+    pub fn with_source_id(mut self, source_id: usize) -> Self {
+        self.source_id = source_id;
+        self
+    }
+
+    pub fn source_id(&self) -> usize {
+        self.source_id
+    }
+
     fn make_token(&self, t: TokenType) -> Token {
         Token {
             tokentype: t,
             start: self.start,
             length: self.current - self.start,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            source: self.source_id,
         }
     }
 
+    // Offsets are in bytes so tokens can be sliced straight out of the source.
+    // Every line ending passes through here: `\n`, `\r\n` and a lone `\r`
+    // each end exactly one line, wherever they appear.
     fn advance(&mut self) -> char {
-        self.current += 1;
-        return self.source.chars().nth(self.current - 1).unwrap();
+        let c = self.peek();
+        self.current += c.len_utf8();
+        match c {
+            '\r' if self.peek() == '\n' => (),
+            '\n' | '\r' => {
+                self.line += 1;
+                self.column = 1;
+            }
+            _ => self.column += 1,
+        }
+        c
     }
 
     fn token_match(&mut self, expected: char) -> bool {
-        if self.at_end() {
-            return false;
-        }
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.at_end() || self.peek() != expected {
             return false;
         }
-        self.current += 1;
-        return true;
+        self.current += expected.len_utf8();
+        self.column += 1;
+        true
     }
 
     fn peek(&self) -> char {
-        if self.at_end() {
-            return '\0';
-        }
-        return self.source.chars().nth(self.current).unwrap();
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     pub fn scan_token(&mut self) -> Token {
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.at_end() {
             return self.make_token(TokenType::Eof);
@@ -85,12 +100,24 @@ impl Scanner<'_> {
             ')' => self.make_token(TokenType::RightParen),
             '{' => self.make_token(TokenType::LeftBrace),
             '}' => self.make_token(TokenType::RightBrace),
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
+            '-' => match self.token_match('-') {
+                true => self.make_token(TokenType::MinusMinus),
+                false => self.make_token(TokenType::Minus),
+            },
+            '+' => match self.token_match('+') {
+                true => self.make_token(TokenType::PlusPlus),
+                false => self.make_token(TokenType::Plus),
+            },
             ';' => self.make_token(TokenType::SemiColon),
-            '*' => self.make_token(TokenType::Star),
+            ':' => self.make_token(TokenType::Colon),
+            '*' => match self.token_match('*') {
+                true => self.make_token(TokenType::StarStar),
+                false => self.make_token(TokenType::Star),
+            },
             '!' => match self.token_match('=') {
                 true => self.make_token(TokenType::BangEqual),
                 false => self.make_token(TokenType::Bang),
@@ -109,26 +136,37 @@ impl Scanner<'_> {
             },
             '/' => match self.token_match('/') {
                 true => {
-                    while self.peek() != '\n' && !self.at_end() {
+                    while !matches!(self.peek(), '\n' | '\r') && !self.at_end() {
                         self.advance();
                     }
                     self.make_token(TokenType::WhiteSpace)
                 }
+                false if self.token_match('*') => self.block_comment(),
                 false => self.make_token(TokenType::Slash),
             },
-            ' ' | '\r' | '\t' => self.make_token(TokenType::WhiteSpace),
-            '\n' => {
-                self.line += 1;
+            ' ' | '\t' => self.make_token(TokenType::WhiteSpace),
+            '\r' => {
+                if self.peek() == '\n' {
+                    self.advance();
+                }
                 self.make_token(TokenType::NewLine)
             }
+            '\n' => self.make_token(TokenType::NewLine),
             '"' => self.read_string(),
+            // A `#!` line at the very top lets a script be run as a program.
+            '#' if self.start == 0 && self.peek() == '!' => {
+                while !matches!(self.peek(), '\n' | '\r') && !self.at_end() {
+                    self.advance();
+                }
+                self.make_token(TokenType::WhiteSpace)
+            }
             _ => {
                 if self.is_digit(c) {
                     self.number()
                 } else if self.is_alpha(c) {
                     self.identifier()
                 } else {
-                    self.error_msg = format!("line : {} , unexpected character {}", self.line, c);
+                    self.error_msg = format!("unexpected character '{}'", c);
                     self.make_token(TokenType::Error)
                 }
             }
@@ -136,21 +174,41 @@ impl Scanner<'_> {
     }
 
     fn at_end(&self) -> bool {
-        self.current >= self.source.chars().count()
+        self.current >= self.source.len()
     }
 
     fn is_digit(&self, c: char) -> bool {
-        return c >= '0' && c <= '9';
+        c.is_ascii_digit()
     }
 
+    // Malformed literals such as `0x`, `1e` or `100_` are still scanned as
+    // numbers; the compiler rejects them when it parses the lexeme.
     fn number(&mut self) -> Token {
-        while self.is_digit(self.peek()) {
+        if self.source[self.start..self.current] == *"0" && matches!(self.peek(), 'x' | 'X') {
+            self.advance();
+            while self.peek().is_ascii_hexdigit() || self.peek() == '_' {
+                self.advance();
+            }
+            return self.make_token(TokenType::Number);
+        }
+
+        while self.is_digit(self.peek()) || self.peek() == '_' {
             self.advance();
         }
 
         if self.peek() == '.' && self.is_digit(self.peek_next()) {
             self.advance();
-            while self.is_digit(self.peek()) {
+            while self.is_digit(self.peek()) || self.peek() == '_' {
+                self.advance();
+            }
+        }
+
+        if matches!(self.peek(), 'e' | 'E') {
+            self.advance();
+            if matches!(self.peek(), '+' | '-') {
+                self.advance();
+            }
+            while self.is_digit(self.peek()) || self.peek() == '_' {
                 self.advance();
             }
         }
@@ -158,44 +216,51 @@ impl Scanner<'_> {
     }
 
     fn peek_next(&self) -> char {
-        if (self.current + 1) >= self.source.chars().count() {
-            return '\0';
-        }
-        return self.source.chars().nth(self.current + 1).unwrap();
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
+    // Identifiers may use letters from any script, such as `café` or `π`.
+    // Keywords are all ASCII, so they are matched as before.
     fn is_alpha(&self, c: char) -> bool {
-        return (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c == '_');
+        c.is_alphabetic() || c == '_'
     }
 
     fn is_alphanumeric(&self, c: char) -> bool {
-        return self.is_alpha(c) || self.is_digit(c);
+        c.is_alphanumeric() || c == '_'
     }
 
     fn identifier(&mut self) -> Token {
         while self.is_alphanumeric(self.peek()) {
             self.advance();
         }
-        let text: String = self
-            .source
-            .chars()
-            .skip(self.start)
-            .take(self.current - self.start)
-            .collect();
-        let tokentype = self.keywords.get(&text).unwrap_or(&TokenType::Identifier);
-        self.make_token(tokentype.to_owned())
+        self.make_token(keyword(&self.source[self.start..self.current]))
+    }
+
+    // Block comments nest, so commenting out code that already holds a
+    // `/* ... */` does not end early at its `*/`.
+    fn block_comment(&mut self) -> Token {
+        let mut depth = 1;
+        while depth > 0 {
+            if self.at_end() {
+                self.error_msg = "unterminated block comment".to_owned();
+                return self.make_token(TokenType::Error);
+            }
+            match self.advance() {
+                '/' if self.token_match('*') => depth += 1,
+                '*' if self.token_match('/') => depth -= 1,
+                _ => (),
+            }
+        }
+        self.make_token(TokenType::WhiteSpace)
     }
 
     fn read_string(&mut self) -> Token {
         while self.peek() != '"' && !self.at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
             self.advance();
         }
 
         if self.at_end() {
-            self.error_msg = format!("Unterminated string at {}", self.line);
+            self.error_msg = "unterminated string".to_owned();
             return self.make_token(TokenType::Error);
         }
 
@@ -204,3 +269,33 @@ impl Scanner<'_> {
         self.make_token(TokenType::String)
     }
 }
+
+fn keyword(text: &str) -> TokenType {
+    match text {
+        "and" => TokenType::And,
+        "assert" => TokenType::Assert,
+        "case" => TokenType::Case,
+        "class" => TokenType::Class,
+        "default" => TokenType::Default,
+        "else" => TokenType::Else,
+        "false" => TokenType::False,
+        "for" => TokenType::For,
+        "function" => TokenType::Fun,
+        "if" => TokenType::If,
+        "import" => TokenType::Import,
+        "include" => TokenType::Include,
+        "nil" => TokenType::Nil,
+        "or" => TokenType::Or,
+        "print" => TokenType::Print,
+        "return" => TokenType::Return,
+        "super" => TokenType::Super,
+        "switch" => TokenType::Switch,
+        "test" => TokenType::Test,
+        "this" => TokenType::This,
+        "true" => TokenType::True,
+        "var" => TokenType::Var,
+        "const" => TokenType::Const,
+        "while" => TokenType::While,
+        _ => TokenType::Identifier,
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use super::{
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    values::{Object, Value},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileStats {
+    pub instructions: usize,
+    pub code_size: usize,
+    pub constants: usize,
+    pub deduplicated_constants: usize,
+    pub functions: usize,
+    pub max_scope_depth: usize,
+    pub opcodes: Vec<(OpCode, usize)>,
+}
+
+impl CompileStats {
+    // What can be read off finished bytecode alone. Scope depth is only
+    // known while compiling, so it is left at zero.
+    pub fn of_memory(memory: &MemorySlice) -> Self {
+        let opcodes = memory.opcode_counts();
+        CompileStats {
+            instructions: opcodes.iter().map(|(_, count)| count).sum(),
+            code_size: memory.get_memory_size(),
+            constants: memory.get_constants_size(),
+            deduplicated_constants: memory.get_deduplicated_count(),
+            functions: memory
+                .constants()
+                .iter()
+                .filter(|value| matches!(value, Value::Object(Object::Function(_))))
+                .count(),
+            max_scope_depth: 0,
+            opcodes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionStats {
+    pub instructions: u64,
+    pub peak_stack_depth: usize,
+}
+
+impl ExecutionStats {
+    pub fn record(&mut self, stack_depth: usize) {
+        self.instructions += 1;
+        if stack_depth > self.peak_stack_depth {
+            self.peak_stack_depth = stack_depth;
+        }
+    }
+}
+
+// Executions and time spent per opcode. An instruction's time runs until
+// the next one starts, so a call counts the native it runs but not the body
+// of a script function.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileData {
+    counts: Vec<u64>,
+    times: Vec<Duration>,
+    // The instruction running and when it started.
+    running: Option<(OpCode, Instant)>,
+}
+
+impl ProfileData {
+    pub fn record(&mut self, opcode: OpCode) {
+        let now = Instant::now();
+        self.finish_at(now);
+        let index = opcode as usize;
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+            self.times.resize(index + 1, Duration::ZERO);
+        }
+        self.counts[index] += 1;
+        self.running = Some((opcode, now));
+    }
+
+    // Charges the instruction that ran last with the time up to now.
+    pub fn finish(&mut self) {
+        self.finish_at(Instant::now());
+    }
+
+    fn finish_at(&mut self, now: Instant) {
+        if let Some((opcode, started)) = self.running.take() {
+            self.times[opcode as usize] += now - started;
+        }
+    }
+
+    pub fn count(&self, opcode: OpCode) -> u64 {
+        self.counts.get(opcode as usize).copied().unwrap_or(0)
+    }
+
+    pub fn time(&self, opcode: OpCode) -> Duration {
+        self.times.get(opcode as usize).copied().unwrap_or_default()
+    }
+
+    // Every opcode that ran, the most time first.
+    pub fn rows(&self) -> Vec<(OpCode, u64, Duration)> {
+        let mut rows: Vec<(OpCode, u64, Duration)> = self
+            .counts
+            .iter()
+            .zip(&self.times)
+            .enumerate()
+            .filter(|(_, (count, _))| **count > 0)
+            .filter_map(|(index, (count, time))| Some((OpCode::try_from(index as u16).ok()?, *count, *time)))
+            .collect();
+        rows.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)));
+        rows
+    }
+}
+
+pub fn report_profile(profile: &ProfileData) -> String {
+    let rows = profile.rows();
+    let total: Duration = rows.iter().map(|(_, _, time)| *time).sum();
+    let mut out = String::new();
+    writeln!(out, "== Profile ==").unwrap();
+    writeln!(out, "{:<22}{:>12}{:>12}{:>8}", "opcode", "count", "time (ms)", "%").unwrap();
+    writeln!(out, "------------------------------------------------------").unwrap();
+    for (opcode, count, time) in rows {
+        let share = if total.is_zero() { 0.0 } else { time.as_secs_f64() / total.as_secs_f64() * 100.0 };
+        writeln!(
+            out,
+            "{:<22}{:>12}{:>12.3}{:>7.1}%",
+            format!("{:?}", opcode),
+            count,
+            time.as_secs_f64() * 1000.0,
+            share
+        )
+        .unwrap();
+    }
+    out
+}
+
+pub fn report(compile: &CompileStats, execution: Option<&ExecutionStats>) -> String {
+    let mut out = String::new();
+    writeln!(out, "== Compile Stats ==").unwrap();
+    writeln!(out, "--------------------------------").unwrap();
+    writeln!(out, "{:<24}{}", "instructions", compile.instructions).unwrap();
+    writeln!(out, "{:<24}{}", "code size (words)", compile.code_size).unwrap();
+    writeln!(out, "{:<24}{}", "constants", compile.constants).unwrap();
+    writeln!(out, "{:<24}{}", "constants deduplicated", compile.deduplicated_constants).unwrap();
+    writeln!(out, "{:<24}{}", "functions", compile.functions).unwrap();
+    writeln!(out, "{:<24}{}", "max scope depth", compile.max_scope_depth).unwrap();
+    writeln!(out, "opcodes:").unwrap();
+    for (opcode, count) in &compile.opcodes {
+        writeln!(out, "  {:<22}{}", format!("{:?}", opcode), count).unwrap();
+    }
+    if let Some(execution) = execution {
+        writeln!(out, "== Execution Stats ==").unwrap();
+        writeln!(out, "--------------------------------").unwrap();
+        writeln!(out, "{:<24}{}", "instructions executed", execution.instructions).unwrap();
+        writeln!(out, "{:<24}{}", "peak stack depth", execution.peak_stack_depth).unwrap();
+    }
+    out
+}
+
+pub fn report_json(compile: &CompileStats, execution: Option<&ExecutionStats>) -> String {
+    let opcodes: Vec<String> = compile
+        .opcodes
+        .iter()
+        .map(|(opcode, count)| format!("\"{:?}\":{}", opcode, count))
+        .collect();
+    let mut out = format!(
+        "{{\"compile\":{{\"instructions\":{},\"code_size\":{},\"constants\":{},\"deduplicated_constants\":{},\"functions\":{},\"max_scope_depth\":{},\"opcodes\":{{{}}}}}",
+        compile.instructions,
+        compile.code_size,
+        compile.constants,
+        compile.deduplicated_constants,
+        compile.functions,
+        compile.max_scope_depth,
+        opcodes.join(",")
+    );
+    if let Some(execution) = execution {
+        write!(
+            out,
+            ",\"execution\":{{\"instructions\":{},\"peak_stack_depth\":{}}}",
+            execution.instructions, execution.peak_stack_depth
+        )
+        .unwrap();
+    }
+    out.push('}');
+    out
+}
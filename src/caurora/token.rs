@@ -3,11 +3,12 @@ use std::fmt;
 #[derive(Debug, Clone, PartialEq, PartialOrd, Copy)]
 pub enum TokenType{
   // Single-character tokens.
-  LeftParen, RightParen, LeftBrace, RightBrace,
-  Comma, Dot, Minus, Plus, SemiColon, Slash, Star,
+  LeftParen, RightParen, LeftBrace, RightBrace, LeftBracket, RightBracket,
+  Comma, Dot, Minus, Plus, SemiColon, Slash, Star, Colon,
 
   // One or two character tokens.
   Bang, BangEqual,
+  StarStar, PlusPlus, MinusMinus,
   Equal, EqualEqual,
   Greater, GreaterEqual,
   Less, LessEqual,
@@ -16,8 +17,8 @@ pub enum TokenType{
   Identifier, String, Number,
 
   // Keywords.
-  And, Class, Else, False, Fun, For, If, Nil, Or,
-  Print, Return, Super, This, True, Var, While,
+  And, Assert, Case, Class, Const, Default, Else, False, Fun, For, If, Nil, Or,
+  Import, Include, Print, Return, Super, Switch, Test, This, True, Var, While,
 
   Eof, Error, WhiteSpace, NewLine
 }
@@ -33,11 +34,15 @@ pub struct Token {
     pub tokentype: TokenType,
     pub start: usize,
     pub length: usize,
+    // Where the token begins, both counted from 1. A column is one
+    // character, so a tab or a multi-byte character is one column.
     pub line: usize,
+    pub column: usize,
+    pub source: usize,
 }
 
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Token Type: {},  Start: {}, Length: {}, Line: {}", self.tokentype, self.start, self.length, self.line)
+        write!(f, "Token Type: {},  Start: {}, Length: {}, Line: {}, Column: {}", self.tokentype, self.start, self.length, self.line, self.column)
     }
 }
\ No newline at end of file
@@ -1,10 +1,248 @@
-#[derive(Debug,Clone, PartialEq, PartialOrd)]
+use std::{cell::RefCell, cmp::Ordering, collections::HashMap, fmt, rc::Rc};
+
+use super::virtualmachine::VM;
+
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+// Arity of natives that accept any number of arguments and check them
+// themselves.
+pub const VARIADIC: usize = usize::MAX;
+
+// Wraps a native function pointer so values holding one can still derive
+// comparisons: natives are equal only when they are the same function.
+#[derive(Clone, Copy)]
+pub struct Native(pub NativeFn);
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::fn_addr_eq(self.0, other.0)
+    }
+}
+
+impl PartialOrd for Native {
+    fn partial_cmp(&self, _other: &Self) -> Option<Ordering> {
+        None
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Native({:p})", self.0 as *const ())
+    }
+}
+
+// A compiled function, whose body is chunk `chunk` of the program.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Function {
+    pub name: String,
+    pub chunk: usize,
+    // The most arguments it takes; parameters past `min_arity` have
+    // defaults.
+    pub arity: usize,
+    pub min_arity: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    pub function: Native,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Module {
+    pub name: String,
+    pub members: Vec<(String, Value)>,
+    // What calling the module itself runs, if anything.
+    pub call: Option<Value>,
+}
+
+impl Module {
+    pub fn natives(name: &str, members: &[(&str, usize, NativeFn)]) -> Self {
+        let members = members
+            .iter()
+            .map(|(member, arity, function)| {
+                (
+                    member.to_string(),
+                    Value::Object(Object::NativeFunction(Rc::new(NativeFunction {
+                        name: format!("{}.{}", name, member),
+                        arity: *arity,
+                        function: Native(*function),
+                    }))),
+                )
+            })
+            .collect();
+        Module {
+            name: name.to_owned(),
+            members,
+            call: None,
+        }
+    }
+
+    pub fn callable(mut self, function: Value) -> Self {
+        self.call = Some(function);
+        self
+    }
+
+    pub fn get(&self, member: &str) -> Option<&Value> {
+        self.members
+            .iter()
+            .find(|(name, _)| name == member)
+            .map(|(_, value)| value)
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<Rc<str>, Value>>,
+    pub superclass: RefCell<Option<Rc<Class>>>,
+}
+
+impl Class {
+    pub fn new(name: String) -> Self {
+        Class {
+            name,
+            methods: RefCell::new(HashMap::new()),
+            superclass: RefCell::new(None),
+        }
+    }
+
+    // Looks the method up here and then along the superclass chain, returning
+    // it with the class that defines it.
+    pub fn find_method(self: &Rc<Self>, name: &str) -> Option<(Value, Rc<Class>)> {
+        let mut class = self.clone();
+        loop {
+            if let Some(method) = class.methods.borrow().get(name) {
+                return Some((method.clone(), class.clone()));
+            }
+            let superclass = class.superclass.borrow().clone()?;
+            class = superclass;
+        }
+    }
+}
+
+// Classes and instances are compared by identity, so they have no ordering.
+impl PartialOrd for Class {
+    fn partial_cmp(&self, _other: &Self) -> Option<Ordering> {
+        None
+    }
+}
+
+impl PartialEq for Class {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: RefCell<HashMap<Rc<str>, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        Instance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl PartialOrd for Instance {
+    fn partial_cmp(&self, _other: &Self) -> Option<Ordering> {
+        None
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// Fields are left out: an instance may hold itself, directly or not.
+impl fmt::Debug for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Instance({})", self.class.name)
+    }
+}
+
+// A method read off an instance, remembering the instance it was read from
+// and the class that defines it, which is where `super` starts looking.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Value,
+    pub class: Rc<Class>,
+}
+
+// A captured variable: still in its slot on the stack while the function
+// declaring it runs, then moved in here when that slot goes away.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+// A function together with the variables it captured.
+pub struct Closure {
+    pub function: Value,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl PartialOrd for Closure {
+    fn partial_cmp(&self, _other: &Self) -> Option<Ordering> {
+        None
+    }
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// Upvalues are left out: a closure may capture itself.
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Closure({:?})", self.function)
+    }
+}
+
+#[derive(Debug,Clone, PartialOrd)]
 pub enum Object {
-    String(String),
-    Function{
-        name: String,
-        address: usize,
-        arity: usize,
+    // Shared, so reading a string or a name off the constants copies a
+    // pointer rather than the text.
+    String(Rc<str>),
+    // Functions sit behind an Rc like every other heap object, so a value
+    // is three words and copying one copies a pointer at most.
+    Function(Rc<Function>),
+    NativeFunction(Rc<NativeFunction>),
+    Module(Rc<Module>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    Closure(Rc<Closure>),
+}
+
+// Functions are equal only to themselves: a compiled function is identified by
+// its chunk and a native by its Rust function, whatever name it is bound to.
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Object::String(a), Object::String(b)) => a == b,
+            (Object::Function(a), Object::Function(b)) => a.chunk == b.chunk,
+            (Object::NativeFunction(a), Object::NativeFunction(b)) => a.function == b.function,
+            (Object::Module(a), Object::Module(b)) => a == b,
+            (Object::Array(a), Object::Array(b)) => a == b,
+            (Object::Class(a), Object::Class(b)) => a == b,
+            (Object::Instance(a), Object::Instance(b)) => a == b,
+            (Object::BoundMethod(a), Object::BoundMethod(b)) => a == b,
+            (Object::Closure(a), Object::Closure(b)) => a == b,
+            _ => false,
+        }
     }
 }
 
@@ -17,3 +255,109 @@ pub enum Value {
     Object(Object),
 }
 
+
+impl Value {
+    pub fn is_function(&self) -> bool {
+        matches!(
+            self,
+            Value::Object(
+                Object::Function(_)
+                    | Object::NativeFunction(_)
+                    | Object::BoundMethod(_)
+                    | Object::Closure(_)
+            )
+        )
+    }
+
+    // Only nil and false are falsey; 0 and "" count as true like any other
+    // value.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    // The name `type()` reports. Every kind of callable is a "function".
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::Nil => "nil",
+            Value::Raw => "raw",
+            Value::Bool(_) => "bool",
+            Value::Object(Object::String(_)) => "string",
+            Value::Object(Object::Array(_)) => "array",
+            Value::Object(Object::Module(_)) => "module",
+            Value::Object(Object::Class(_)) => "class",
+            Value::Object(Object::Instance(_)) => "instance",
+            Value::Object(
+                Object::Function(_)
+                | Object::NativeFunction(_)
+                | Object::BoundMethod(_)
+                | Object::Closure(_),
+            ) => "function",
+        }
+    }
+
+    // What `==` means: numbers, strings and bools by value, nil only to nil,
+    // functions by identity, and values of different types never.
+    pub fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) | (Value::Raw, Value::Raw) => true,
+            (Value::Object(a), Value::Object(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    // The compiled function behind a closure, or the value itself.
+    pub fn function(&self) -> &Value {
+        match self {
+            Value::Object(Object::Closure(closure)) => &closure.function,
+            value => value,
+        }
+    }
+}
+
+// The human-readable rendering `print` writes and builtins such as `join` use:
+// integral numbers without a trailing `.0` and strings without quotes.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(x) => write!(f, "{}", x),
+            Value::Nil => write!(f, "nil"),
+            Value::Raw => write!(f, "?"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Object(object) => write!(f, "{}", object),
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Object::String(s) => write!(f, "{}", s),
+            Object::Function(function) => write!(f, "<fn {}>", function.name),
+            Object::NativeFunction(native) => write!(f, "<native {}>", native.name),
+            Object::Module(module) => write!(f, "<module {}>", module.name),
+            Object::Closure(closure) => write!(f, "{}", closure.function),
+            Object::Class(class) => write!(f, "<class {}>", class.name),
+            Object::Instance(instance) => write!(f, "<{} instance>", instance.class.name),
+            Object::BoundMethod(bound) => match bound.method.function() {
+                Value::Object(Object::Function(function)) => write!(f, "<method {}>", function.name),
+                method => write!(f, "<method {}>", method),
+            },
+            Object::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match element {
+                        Value::Object(Object::String(s)) => write!(f, "\"{}\"", s)?,
+                        element => write!(f, "{}", element)?,
+                    }
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
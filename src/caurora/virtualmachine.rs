@@ -1,285 +1,1390 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, BufRead, Write},
+    rc::Rc,
+    time::Instant,
+};
 
 use crate::caurora::values::Object;
 
-use super::{errorlogger, memoryslice::MemorySlice, opcodes::OpCode, values::Value};
+use super::{
+    memoryslice::{Chunk, Instruction, MemorySlice},
+    natives,
+    opcodes::OpCode,
+    random::Random,
+    stats::{ExecutionStats, ProfileData},
+    values::{
+        BoundMethod, Class, Closure, Function, Instance, Module, Native, NativeFn, NativeFunction, Upvalue, Value,
+        VARIADIC,
+    },
+};
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InterpretResult {
     InterpretOk,
     InterpretCompileError,
     InterpretRuntimeError,
+    // The script called `exit(code)`.
+    InterpretExit(i32),
+    // The fuel the VM was given ran out. The VM is left as it was before the
+    // instruction it stopped at, so it can be inspected, or given more fuel
+    // and resumed with `interpret`.
+    InterpretOutOfFuel,
+    // A breakpoint was reached. The instruction it is set on has not run
+    // yet; `interpret` or `step` carries on from it.
+    InterpretPaused,
 }
 
-#[derive(Debug, Clone)]
-pub struct VM<'a> {
-    pub memory: &'a MemorySlice,
+impl InterpretResult {
+    // The process status for the result, following sysexits.h: 65 for input
+    // that does not compile, 70 for a failure while running.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InterpretResult::InterpretOk => 0,
+            InterpretResult::InterpretCompileError => 65,
+            InterpretResult::InterpretRuntimeError
+            | InterpretResult::InterpretOutOfFuel
+            | InterpretResult::InterpretPaused => 70,
+            InterpretResult::InterpretExit(code) => *code,
+        }
+    }
+}
+
+// Why a run stopped early: the message, the line and offset of the
+// instruction that failed, and the calls active at the time, innermost first,
+// as the function and the line it had reached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
     pub ip: usize,
-    pub stack: Vec<Value>,
-    pub globals: HashMap<String, Value>,
-    pub ip_stack: Vec<usize>,
-    pub sp: usize,
-    pub temp_val: Value,
+    pub trace: Vec<(String, usize)>,
 }
 
-impl VM<'_> {
-    fn advance_and_read(&mut self) -> u16 {
-        match self.memory.read_at_ip(self.ip) {
-            Some(op) => {
-                self.ip += 1;
-                op
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// How many calls deep a script may go before the VM stops it with a stack
+// overflow, unless told otherwise.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmOptions {
+    pub max_call_depth: Option<usize>,
+    pub fuel: Option<u64>,
+    pub profile: bool,
+    pub stats: bool,
+    pub builtins: bool,
+    // Whether the builtins that touch the filesystem are registered too.
+    pub files: bool,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            max_call_depth: Some(DEFAULT_MAX_CALL_DEPTH),
+            fuel: None,
+            profile: false,
+            stats: false,
+            builtins: true,
+            files: true,
+        }
+    }
+}
+
+// One active function call: the chunk and offset to resume the caller at,
+// and the stack index of the callee, which is slot zero of the frame with the
+// arguments above it.
+// A method call also records the class defining the method, for `super`, and
+// a closure call the closure, whose upvalues the function reads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    pub return_chunk: usize,
+    pub return_ip: usize,
+    pub base: usize,
+    pub class: Option<Rc<Class>>,
+    pub closure: Option<Rc<Closure>>,
+}
+
+pub struct VmBuilder<'a> {
+    memory: Option<&'a MemorySlice>,
+    output: Option<Box<dyn Write>>,
+    input: Option<Box<dyn BufRead>>,
+    trace: Option<Box<dyn Write>>,
+    globals: HashMap<Rc<str>, Value>,
+    // Whether `globals` came from an earlier VM and already hold the
+    // builtins.
+    carried: bool,
+    started: Option<Instant>,
+    random: Option<Random>,
+    options: VmOptions,
+}
+
+impl<'a> VmBuilder<'a> {
+    pub fn memory(mut self, memory: &'a MemorySlice) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub fn output(mut self, output: Box<dyn Write>) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    // Where `input()` reads lines from; stdin when not set.
+    pub fn input(mut self, input: Box<dyn BufRead>) -> Self {
+        self.input = Some(input);
+        self
+    }
+
+    // Where to log each instruction before it runs; nothing is traced when
+    // not set.
+    pub fn trace(mut self, trace: Box<dyn Write>) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    // Globals defined on top of the builtins, replacing any of the same name.
+    pub fn globals(mut self, globals: HashMap<Rc<str>, Value>) -> Self {
+        self.globals = globals;
+        self
+    }
+
+    // Globals an earlier VM left, builtins included, used in place of a fresh
+    // set of builtins.
+    pub fn carry_globals(mut self, globals: HashMap<Rc<str>, Value>) -> Self {
+        self.globals = globals;
+        self.carried = true;
+        self
+    }
+
+    // What `clock()` counts from; when the VM is built if not set.
+    pub fn started(mut self, started: Instant) -> Self {
+        self.started = Some(started);
+        self
+    }
+
+    // The generator `rand()` draws from; seeded from the time if not set.
+    pub fn random(mut self, random: Random) -> Self {
+        self.random = Some(random);
+        self
+    }
+
+    pub fn max_call_depth(mut self, depth: usize) -> Self {
+        self.options.max_call_depth = Some(depth);
+        self
+    }
+
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.options.fuel = Some(fuel);
+        self
+    }
+
+    // Counts and times every opcode run; see `VM::profile`.
+    pub fn profile(mut self, enabled: bool) -> Self {
+        self.options.profile = enabled;
+        self
+    }
+
+    pub fn stats(mut self, enabled: bool) -> Self {
+        self.options.stats = enabled;
+        self
+    }
+
+    pub fn builtins(mut self, enabled: bool) -> Self {
+        self.options.builtins = enabled;
+        self
+    }
+
+    pub fn files(mut self, enabled: bool) -> Self {
+        self.options.files = enabled;
+        self
+    }
+
+    pub fn options(mut self, options: VmOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn build(self) -> VM<'a> {
+        let memory = self.memory.expect("VmBuilder::build called without memory");
+        let mut vm = VM {
+            memory,
+            chunk: 0,
+            code: memory.chunk(0).map_or(&[], Chunk::code),
+            ip: 0,
+            instruction_start: 0,
+            stack: Vec::<Value>::new(),
+            globals: vec![None; memory.global_names().len()],
+            global_names: memory.global_names().to_vec(),
+            global_slots: memory
+                .global_names()
+                .iter()
+                .enumerate()
+                .map(|(slot, name)| (name.clone(), slot))
+                .collect(),
+            frames: Vec::<CallFrame>::new(),
+            open_upvalues: Vec::new(),
+            started: self.started.unwrap_or_else(Instant::now),
+            random: self.random.unwrap_or_else(Random::from_time),
+            stats: self.options.stats.then(ExecutionStats::default),
+            profile: self.options.profile.then(ProfileData::default),
+            error: None,
+            file_error: None,
+            exit_code: None,
+            output: self.output.unwrap_or_else(|| Box::new(io::stdout())),
+            input: self.input,
+            trace: self.trace,
+            max_call_depth: self.options.max_call_depth,
+            fuel: self.options.fuel,
+            breakpoints: HashSet::new(),
+            paused_at: None,
+        };
+        if self.options.builtins && !self.carried {
+            natives::register_builtins(&mut vm);
+            if self.options.files {
+                natives::register_file_builtins(&mut vm);
             }
+        }
+        for (name, value) in self.globals {
+            vm.define_global(&name, value);
+        }
+        vm
+    }
+}
+
+pub struct VM<'a> {
+    memory: &'a MemorySlice,
+    // The chunk running, and its code.
+    chunk: usize,
+    code: &'a [u16],
+    ip: usize,
+    // Where the running instruction begins, for the line an error reports.
+    instruction_start: usize,
+    stack: Vec<Value>,
+    // Globals by slot, None until defined. The program's slots come first,
+    // in the order of its global names; globals it never names, such as
+    // unused builtins, get slots after them.
+    globals: Vec<Option<Value>>,
+    global_names: Vec<Rc<str>>,
+    global_slots: HashMap<Rc<str>, usize>,
+    frames: Vec<CallFrame>,
+    // Upvalues whose variables are still on the stack.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    started: Instant,
+    random: Random,
+    stats: Option<ExecutionStats>,
+    profile: Option<ProfileData>,
+    error: Option<RuntimeError>,
+    // Why the last file builtin failed, for `last_error()`.
+    file_error: Option<String>,
+    // Set by `exit(code)`; the VM stops once the native returns.
+    exit_code: Option<i32>,
+    output: Box<dyn Write>,
+    input: Option<Box<dyn BufRead>>,
+    trace: Option<Box<dyn Write>>,
+    max_call_depth: Option<usize>,
+    fuel: Option<u64>,
+    // (chunk, offset) pairs `interpret` stops before running.
+    breakpoints: HashSet<(usize, usize)>,
+    // The breakpoint the VM last stopped at, which resuming runs past.
+    paused_at: Option<(usize, usize)>,
+}
+
+impl fmt::Debug for VM<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("chunk", &self.chunk)
+            .field("ip", &self.ip)
+            .field("stack", &self.stack)
+            .field("globals", &self.globals())
+            .field("frames", &self.frames)
+            .finish()
+    }
+}
+
+impl<'a> VM<'a> {
+    pub fn new(memory: &'a MemorySlice) -> Self {
+        VM::builder().memory(memory).build()
+    }
+
+    pub fn builder() -> VmBuilder<'a> {
+        VmBuilder {
+            memory: None,
+            output: None,
+            input: None,
+            trace: None,
+            globals: HashMap::new(),
+            carried: false,
+            started: None,
+            random: None,
+            options: VmOptions::default(),
+        }
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    // The chunk `ip` points into.
+    pub fn chunk(&self) -> usize {
+        self.chunk
+    }
+
+    // The instruction that runs next, decoded.
+    pub fn next_instruction(&self) -> Option<Instruction> {
+        self.memory.chunk(self.chunk)?.instruction_at(self.ip)
+    }
+
+    // How many calls deep the VM is; the script itself is depth zero.
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    // Every defined global by name.
+    pub fn globals(&self) -> HashMap<Rc<str>, Value> {
+        self.global_names
+            .iter()
+            .zip(&self.globals)
+            .filter_map(|(name, value)| Some((name.clone(), value.clone()?)))
+            .collect()
+    }
+
+    pub fn into_globals(self) -> HashMap<Rc<str>, Value> {
+        self.globals()
+    }
+
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        let slot = match self.global_slots.get(name) {
+            Some(slot) => *slot,
             None => {
-                errorlogger::log_error(&format!(
-                    "Advance: Invalid instruction pointer, position: {:#?}",
-                    self
-                ));
-                0
+                let name: Rc<str> = name.into();
+                self.global_names.push(name.clone());
+                self.global_slots.insert(name, self.globals.len());
+                self.globals.push(None);
+                self.globals.len() - 1
+            }
+        };
+        self.globals[slot] = Some(value);
+    }
+
+    pub fn stats(&self) -> Option<&ExecutionStats> {
+        self.stats.as_ref()
+    }
+
+    // Allows `fuel` more instructions from now on, after which `interpret`
+    // returns `InterpretOutOfFuel`.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    // The instructions left to run, or None when there is no budget.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Makes `interpret` pause before running the instruction at `offset` in
+    // chunk `chunk`.
+    pub fn add_breakpoint(&mut self, chunk: usize, offset: usize) {
+        self.breakpoints.insert((chunk, offset));
+    }
+
+    // Breaks on the first instruction of every run of code from source line
+    // `line`, in any chunk. False if no code comes from that line.
+    pub fn add_line_breakpoint(&mut self, line: usize) -> bool {
+        let mut found = false;
+        for (index, chunk) in self.memory.chunks().iter().enumerate() {
+            let mut previous = None;
+            for instruction in chunk.instructions() {
+                let current = chunk.get_line(instruction.offset);
+                if current == line && previous != Some(line) {
+                    self.breakpoints.insert((index, instruction.offset));
+                    found = true;
+                }
+                previous = Some(current);
             }
         }
+        found
     }
 
-    fn get_next_constant(&mut self) -> Value {
-        let read_index = self.advance_and_read();
-        match self.memory.get_constant(read_index) {
-            Some(op) => op,
-            None => {
-                errorlogger::log_error(&format!(
-                    "Constant: Invalid instruction pointer, position: {:#?}",
-                    self
-                ));
-                Value::Number(0.0)
+    pub fn remove_breakpoint(&mut self, chunk: usize, offset: usize) {
+        self.breakpoints.remove(&(chunk, offset));
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // Runs exactly one instruction, ignoring breakpoints. None while the
+    // program can go on; otherwise how it ended.
+    pub fn step(&mut self) -> Option<InterpretResult> {
+        let fuel = self.fuel;
+        if fuel == Some(0) {
+            return Some(InterpretResult::InterpretOutOfFuel);
+        }
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        // A budget of one instruction stops the run right after it.
+        self.fuel = Some(1);
+        let result = self.interpret();
+        self.fuel = fuel.map(|fuel| fuel.saturating_sub(1));
+        self.breakpoints = breakpoints;
+        self.paused_at = None;
+        match result {
+            InterpretResult::InterpretOutOfFuel => None,
+            result => Some(result),
+        }
+    }
+
+    pub fn profile(&self) -> Option<&ProfileData> {
+        self.profile.as_ref()
+    }
+
+    pub fn error(&self) -> Option<&RuntimeError> {
+        self.error.as_ref()
+    }
+}
+
+impl<'a> VM<'a> {
+    fn advance_and_read(&mut self) -> Result<u16, String> {
+        match self.code.get(self.ip) {
+            Some(op) => {
+                self.ip += 1;
+                Ok(*op)
             }
+            None => Err(format!("instruction pointer {} is past the end of the code", self.ip)),
+        }
+    }
+
+    // A two-word operand, low word first.
+    fn read_long(&mut self) -> Result<usize, String> {
+        let low = self.advance_and_read()? as usize;
+        let high = self.advance_and_read()? as usize;
+        Ok(low | high << 16)
+    }
+
+    // Constants are borrowed from the program, which outlives the VM, so
+    // only what is pushed onto the stack gets cloned.
+    fn get_next_constant(&mut self) -> Result<&'a Value, String> {
+        let read_index = self.advance_and_read()?;
+        self.constant(read_index as u32)
+    }
+
+    fn constant(&self, index: u32) -> Result<&'a Value, String> {
+        let memory = self.memory;
+        memory
+            .constants()
+            .get(index as usize)
+            .ok_or_else(|| format!("missing constant {}", index))
+    }
+
+    // Reads the constant naming the variable, property, class or method
+    // `opcode` works on.
+    fn read_name(&mut self, opcode: OpCode) -> Result<Rc<str>, String> {
+        match self.get_next_constant()? {
+            Value::Object(Object::String(name)) => Ok(name.clone()),
+            value => Err(format!("{:?}: expected a name, got {}", opcode, value)),
         }
     }
 
+    fn read_global_slot(&mut self, opcode: OpCode) -> Result<usize, String> {
+        let slot = self.advance_and_read()? as usize;
+        if slot >= self.memory.global_names().len() {
+            return Err(format!("{:?}: missing global slot {}", opcode, slot));
+        }
+        Ok(slot)
+    }
+
+    fn pop(&mut self) -> Result<Value, String> {
+        self.stack.pop().ok_or_else(|| "stack underflow".to_owned())
+    }
+
+    fn peek(&self) -> Result<&Value, String> {
+        self.stack.last().ok_or_else(|| "stack underflow".to_owned())
+    }
+
+    fn top(&mut self) -> Result<&mut Value, String> {
+        self.stack.last_mut().ok_or_else(|| "stack underflow".to_owned())
+    }
+
     pub fn interpret(&mut self) -> InterpretResult {
-        //println!("== Commands ==");
+        let result = self.run();
+        if let Some(profile) = self.profile.as_mut() {
+            profile.finish();
+        }
+        match result {
+            Ok(result) => result,
+            Err(message) => self.runtime_error(&message),
+        }
+    }
+
+    fn run(&mut self) -> Result<InterpretResult, String> {
         loop {
-            let opcode = unsafe { std::mem::transmute(self.advance_and_read()) };
+            if !self.breakpoints.is_empty() {
+                let here = (self.chunk, self.ip);
+                if self.paused_at.take() != Some(here) && self.breakpoints.contains(&here) {
+                    self.paused_at = Some(here);
+                    return Ok(InterpretResult::InterpretPaused);
+                }
+            }
+            // Without fuel this is the only cost of the budget.
+            if let Some(fuel) = self.fuel.as_mut() {
+                if *fuel == 0 {
+                    return Ok(InterpretResult::InterpretOutOfFuel);
+                }
+                *fuel -= 1;
+            }
+            if self.trace.is_some() {
+                self.trace_instruction();
+            }
+            if let Some(stats) = self.stats.as_mut() {
+                stats.record(self.stack.len());
+            }
+            self.instruction_start = self.ip;
+            let opcode = OpCode::try_from(self.advance_and_read()?)
+                .map_err(|word| format!("invalid opcode {} at {}", word, self.instruction_start))?;
+            if let Some(profile) = self.profile.as_mut() {
+                profile.record(opcode);
+            }
             match opcode {
                 OpCode::Constant => {
-                    let value = self.get_next_constant();
+                    let value = self.get_next_constant()?;
                     self.stack.push(value.clone());
-                    //println!("Setting Constant {:#?}", value);
                 }
-                OpCode::Negate => {
-                    let value = match self.stack.pop().unwrap() {
-                        Value::Number(x) => x,
-                        _ => panic!("Wrong Stack value for negate {:#?}", opcode),
-                    };
-                    self.stack.push(Value::Number(-value));
-                    //println!("Setting Negate {:#?}", -value);
+                OpCode::ConstantLong => {
+                    let index = self.read_long()? as u32;
+                    let value = self.constant(index)?;
+                    self.stack.push(value.clone());
+                }
+                // Operators replace their first operand on the stack with
+                // the result rather than popping it and pushing anew.
+                OpCode::Negate => match self.top()? {
+                    Value::Number(x) => *x = -*x,
+                    value => return Err(format!("cannot negate {}", value.type_name())),
+                },
+                OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::Power => {
+                    let b = self.pop()?;
+                    let a = self.top()?;
+                    *a = arithmetic(opcode, a, &b)?;
                 }
-                OpCode::Add => self.binary_op("+"),
-                OpCode::Subtract => self.binary_op("-"),
-                OpCode::Multiply => self.binary_op("*"),
-                OpCode::Divide => self.binary_op("/"),
                 OpCode::Nil => self.stack.push(Value::Nil),
                 OpCode::True => self.stack.push(Value::Bool(true)),
                 OpCode::False => self.stack.push(Value::Bool(false)),
                 OpCode::Not => {
-                    match self.stack.pop().unwrap() {
-                        Value::Bool(x) => match x {
-                            true => self.stack.push(Value::Bool(false)),
-                            false => self.stack.push(Value::Bool(true)),
-                        },
-                        Value::Nil => self.stack.push(Value::Bool(true)),
-                        _ => panic!("Wrong Stack value for Not operator {:#?}", opcode),
-                    };
+                    let value = self.top()?;
+                    *value = Value::Bool(value.is_falsey());
                 }
                 OpCode::Equal => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    self.stack.push(Value::Bool(a == b));
+                    let b = self.pop()?;
+                    let a = self.top()?;
+                    *a = Value::Bool(a.equals(&b));
                 }
-                OpCode::Greater => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    self.stack.push(Value::Bool(a > b));
+                OpCode::Greater | OpCode::Less => {
+                    let b = self.pop()?;
+                    let a = self.top()?;
+                    let wanted = match opcode {
+                        OpCode::Greater => Ordering::Greater,
+                        _ => Ordering::Less,
+                    };
+                    *a = Value::Bool(compare(a, &b)? == Some(wanted));
                 }
-                OpCode::Less => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    self.stack.push(Value::Bool(a < b));
+                OpCode::Print => {
+                    let value = self.pop()?;
+                    writeln!(self.output, "{}", value).unwrap();
+                }
+                // The values were pushed left to right, so the first is the
+                // deepest of the `count` on top.
+                OpCode::PrintN => {
+                    let count = self.advance_and_read()? as usize;
+                    if count > self.stack.len() {
+                        return Err("stack underflow".to_owned());
+                    }
+                    let values = self.stack.split_off(self.stack.len() - count);
+                    let line: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                    writeln!(self.output, "{}", line.join(" ")).unwrap();
                 }
-                OpCode::Print => println!("Vm Print ! {:#?}", self.stack.pop().unwrap()),
                 OpCode::Pop => {
                     self.stack.pop();
                 }
-                OpCode::SetSP => {
-                    self.sp = self.stack.len()
+                OpCode::PopN => {
+                    let count = self.advance_and_read()? as usize;
+                    let Some(height) = self.stack.len().checked_sub(count) else {
+                        return Err("stack underflow".to_owned());
+                    };
+                    self.stack.truncate(height);
                 }
-                OpCode::DefineGlobalVar => {
-                    let var_name = self.get_next_constant();
-                    match var_name {
-                        Value::Object(Object::String(var_name)) => {
-                            self.globals.insert(var_name, self.stack.pop().unwrap());
-                        }
-                        _ => panic!("Invalid Identifier name at {:#?}", var_name),
+                // Whether the caller left out the parameter in the given slot.
+                OpCode::ArgMissing => {
+                    let base = self.frames.last().map_or(0, |frame| frame.base);
+                    let slot = self.advance_and_read()? as usize;
+                    let missing = matches!(self.stack.get(base + slot), Some(Value::Raw));
+                    self.stack.push(Value::Bool(missing));
+                }
+                OpCode::Assert => {
+                    let message = self.get_next_constant()?;
+                    if self.pop()?.is_falsey() {
+                        return Err(match message {
+                            Value::Object(Object::String(message)) => {
+                                format!("assertion failed: {}", message)
+                            }
+                            _ => "assertion failed".to_owned(),
+                        });
                     }
                 }
-                OpCode::GetGlobalVar => {
-                    let var_name = self.get_next_constant();
-                    match var_name {
-                        Value::Object(Object::String(var_name)) => {
-                            self.stack.push(
-                                self.globals
-                                    .get(&var_name)
-                                    .expect(&format!("Identifier not defined ! {} ip: {}", var_name, self.ip))
-                                    .clone(),
-                            );
-                        }
-                        _ => panic!("Invalid Identifier name at {:#?}", var_name),
+                OpCode::Dup => {
+                    let value = self.peek()?.clone();
+                    self.stack.push(value);
+                }
+                OpCode::DefineGlobalSlot => {
+                    let slot = self.read_global_slot(opcode)?;
+                    self.globals[slot] = Some(self.pop()?);
+                }
+                OpCode::GetGlobalSlot => {
+                    let slot = self.read_global_slot(opcode)?;
+                    match &self.globals[slot] {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => return Err(format!("undefined variable '{}'", self.global_names[slot])),
                     }
                 }
-                OpCode::SetGlobalVar => {
-                    let var_name = self.get_next_constant();
-                    match var_name {
-                        Value::Object(Object::String(var_name)) => {
-                            let key = self.globals.get(&var_name);
-                            match key {
-                                Some(_) => {self.globals.insert(var_name, self.stack.last().unwrap().clone());},
-                                None => panic!("Identifier not defined ! {}", var_name)
-                            }
-                        }
-                        _ => panic!("Invalid Identifier name at {:#?}", var_name),
+                OpCode::SetGlobalSlot => {
+                    let slot = self.read_global_slot(opcode)?;
+                    if self.globals[slot].is_none() {
+                        return Err(format!("undefined variable '{}'", self.global_names[slot]));
                     }
+                    self.globals[slot] = Some(self.peek()?.clone());
                 }
                 OpCode::GetLocalVar => {
-                    let local_location = match self.get_next_constant() {
-                        Value::Number(x) => self.sp - x as usize,
-                        _ => panic!("Expected Number pointer for the local variable {:#?}", opcode)
-                    };
-                    //println!("getting local value of == {:#?}  id : {} stack : \n  {:#?} \n sp: {}", self.stack[local_location].clone(), local_location.clone(), self.stack, self.sp);
+                    let operand = self.get_next_constant()?;
+                    let local_location = self.local_slot(operand, "GetLocalVar")?;
                     self.stack.push(self.stack[local_location].clone())
                 }
                 OpCode::SetLocalVar => {
-                    let local_location = match self.get_next_constant() {
-                        Value::Number(x) => self.sp - x as usize,
-                        _ => panic!("Expected Number pointer for the local variable {:#?}", opcode)
-                    };
-                    //println!("setting local value of : {:#?}", self.stack[local_location].clone());
-                    self.stack[local_location] = self.stack.last().unwrap().clone()
+                    let operand = self.get_next_constant()?;
+                    let local_location = self.local_slot(operand, "SetLocalVar")?;
+                    self.stack[local_location] = self.peek()?.clone()
                 }
+                // Conditional jumps leave the condition on the stack for the
+                // code on either side to pop.
                 OpCode::JmpFalse => {
-                    let steps = self.advance_and_read();
-                    match self.stack.last().unwrap().clone() {
-                        Value::Bool(b) => {
-                            if b == false {
-                                self.ip += steps as usize;
-                            }
-                        }
-                        _ => panic!("Top of the stack is not a bool for jmp: {:#?} \n  stack: {:#?}", opcode, self.stack)
+                    let steps = self.read_long()?;
+                    if self.peek()?.is_falsey() {
+                        self.ip += steps;
                     }
                 }
                 OpCode::JmpTrue => {
-                    let steps = self.advance_and_read();
-                    match self.stack.last().unwrap().clone() {
-                        Value::Bool(b) => {
-                            if b == true {
-                                self.ip += steps as usize;
-                            }
-                        }
-                        _ => panic!("Top of the stack is not a bool for jmp: {:#?} \n  stack: {:#?}", opcode, self.stack)
+                    let steps = self.read_long()?;
+                    if !self.peek()?.is_falsey() {
+                        self.ip += steps;
                     }
                 }
                 OpCode::Jmp => {
-                    let steps = self.advance_and_read();
-                    self.ip += steps as usize;
+                    let steps = self.read_long()?;
+                    self.ip += steps;
                 }
                 OpCode::Loop => {
-                    let steps = self.advance_and_read();
-                    self.ip -= steps as usize;       
-                }
-                OpCode::PopStoreTmp => {
-                    self.temp_val = self.stack.pop().unwrap();
-                }
-                OpCode::Call => {
-                    match self.temp_val.clone() {
-                        Value::Object(Object::Function { name, address, arity }) => {
-                            //println!(" CALLING FUNCTION  {} with stack \n {:#?}", name.clone(),self.stack);
-                            let args_count = self.advance_and_read() as usize;
-                            if arity != args_count {
-                                panic!("Invalid number of sparamter call for function {}  stack: \n {:#?}", name, self.stack);
+                    let steps = self.read_long()?;
+                    self.ip = match self.ip.checked_sub(steps) {
+                        Some(ip) => ip,
+                        None => return Err(format!("Loop jumps {} back from {}, before the start", steps, self.ip)),
+                    };
+                }
+                OpCode::Call | OpCode::TailCall => {
+                    let args_count = self.advance_and_read()? as usize;
+                    let args_count = to_index(args_count as f64, self.stack.len(), "Call argument count")?;
+                    let mut base = self.stack.len() - args_count - 1;
+                    let callee = self.stack[base].clone();
+                    if opcode == OpCode::TailCall {
+                        base = self.reuse_frame(base);
+                    }
+                    self.call_value(callee, args_count, base)?;
+                    if let Some(code) = self.exit_code.take() {
+                        return Ok(InterpretResult::InterpretExit(code));
+                    }
+                }
+                OpCode::Return => {
+                    match self.frames.pop() {
+                        Some(frame) => {
+                            let result = self.pop()?;
+                            self.close_upvalues(frame.base);
+                            self.stack.truncate(frame.base);
+                            self.stack.push(result);
+                            self.enter(frame.return_chunk)?;
+                            self.ip = frame.return_ip;
+                        }
+                        None => return Err("return outside of a function".to_owned()),
+                    }
+                }
+                // Heights count from the frame's base, so the check holds
+                // inside function bodies too.
+                OpCode::AssertStack => {
+                    let expected = self.advance_and_read()? as usize;
+                    let base = self.frames.last().map_or(0, |frame| frame.base);
+                    let height = self.stack.len().saturating_sub(base);
+                    if cfg!(debug_assertions) && height != expected {
+                        return Err(format!(
+                            "stack discipline violated: expected height {}, found {} ({:+})",
+                            expected,
+                            height,
+                            height as isize - expected as isize
+                        ));
+                    }
+                }
+                OpCode::GetProperty => {
+                    let property = self.read_name(opcode)?;
+                    let member = match self.pop()? {
+                        // Fields shadow methods; a method is bound to the instance.
+                        Value::Object(Object::Instance(instance)) => {
+                            let field = instance.fields.borrow().get(&property).cloned();
+                            match field {
+                                Some(value) => value,
+                                None => match instance.class.find_method(&property) {
+                                    Some((method, class)) => Value::Object(Object::BoundMethod(Rc::new(BoundMethod {
+                                        receiver: Value::Object(Object::Instance(instance.clone())),
+                                        method,
+                                        class,
+                                    }))),
+                                    None => {
+                                        return Err(format!(
+                                            "undefined property '{}' on {} instance",
+                                            property, instance.class.name
+                                        ))
+                                    }
+                                },
                             }
-                            self.ip_stack.push(self.ip);
-                            self.ip = address;
-                            //println!("========= stack {:#?}", self.stack);
                         }
-                        _ => panic!("Cannot call the following type of objects \n {:?}", self.temp_val)
+                        Value::Object(Object::Module(module)) => match module.get(&property) {
+                            Some(member) => member.clone(),
+                            None => {
+                                return Err(format!(
+                                    "module '{}' has no member '{}'",
+                                    module.name, property
+                                ))
+                            }
+                        },
+                        value => {
+                            return Err(format!(
+                                "cannot read property '{}' of {}",
+                                property,
+                                value.type_name()
+                            ))
+                        }
+                    };
+                    self.stack.push(member);
+                }
+                OpCode::SetProperty => {
+                    let property = self.read_name(opcode)?;
+                    let value = self.pop()?;
+                    match self.pop()? {
+                        Value::Object(Object::Instance(instance)) => {
+                            instance.fields.borrow_mut().insert(property, value.clone());
+                        }
+                        target => {
+                            return Err(format!(
+                                "cannot set property '{}' on {}",
+                                property,
+                                target.type_name()
+                            ))
+                        }
                     }
+                    self.stack.push(value);
                 }
-                OpCode::Return => {
-                    //println!("Return");
-                    if self.ip_stack.len() > 0 {
-                        //println!(" Returnning start with value {:#?} and stack: \n {:#?}", self.temp_val ,self.stack);
-                        self.ip = self.ip_stack.pop().unwrap();
-                        self.stack.push(self.temp_val.clone());
-                        self.temp_val = Value::Nil;
-                        self.sp = self.stack.len() - 1;
-                        //println!("After return {:#?} sp : {}", self.stack, self.sp);
-                    } else {
-                        panic!("Must call return from inside of function IP: {}", self.ip);
+                OpCode::Class => {
+                    let class = Class::new(self.read_name(opcode)?.to_string());
+                    self.stack.push(Value::Object(Object::Class(Rc::new(class))));
+                }
+                // Pops a method and stores it on the class beneath it.
+                OpCode::Method => {
+                    let name = self.read_name(opcode)?;
+                    let method = self.pop()?;
+                    match self.stack.last() {
+                        Some(Value::Object(Object::Class(class))) => {
+                            class.methods.borrow_mut().insert(name, method);
+                        }
+                        _ => return Err(format!("method '{}' defined outside a class", name)),
                     }
                 }
+                // Pops the superclass and links the class beneath it to it.
+                OpCode::Inherit => {
+                    let superclass = self.pop()?;
+                    let (Some(Value::Object(Object::Class(class))), Value::Object(Object::Class(superclass))) =
+                        (self.stack.last(), &superclass)
+                    else {
+                        return Err(format!("superclass must be a class, got {}", superclass.type_name()));
+                    };
+                    *class.superclass.borrow_mut() = Some(superclass.clone());
+                }
+                // Binds `this`, which is on the stack, to the named method of the
+                // superclass of the class defining the running method.
+                OpCode::GetSuper => {
+                    let name = self.read_name(opcode)?;
+                    let receiver = self.pop()?;
+                    let frame_class = self.frames.last().and_then(|frame| frame.class.clone());
+                    let Some(superclass) = frame_class.as_ref().and_then(|class| class.superclass.borrow().clone()) else {
+                        return Err(format!("'super.{}' used outside of a subclass method", name));
+                    };
+                    let Some((method, class)) = superclass.find_method(&name) else {
+                        return Err(format!(
+                            "undefined method '{}' on superclass {}",
+                            name, superclass.name
+                        ));
+                    };
+                    self.stack.push(Value::Object(Object::BoundMethod(Rc::new(BoundMethod {
+                        receiver,
+                        method,
+                        class,
+                    }))));
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.advance_and_read()? as usize;
+                    let value = match &*self.current_upvalue(index)?.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.advance_and_read()? as usize;
+                    let value = self.peek()?.clone();
+                    match &mut *self.current_upvalue(index)?.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                // Wraps the function on the stack with the captures that follow.
+                OpCode::Closure => {
+                    let count = self.advance_and_read()? as usize;
+                    let function = self.pop()?;
+                    let base = self.frames.last().map_or(0, |frame| frame.base);
+                    let mut upvalues = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let local = self.advance_and_read()? == OpCode::CaptureLocal.repr();
+                        let index = self.advance_and_read()? as usize;
+                        let upvalue = if local {
+                            // A local function captures itself, in the slot
+                            // just above the stack.
+                            if base + index > self.stack.len() {
+                                return Err(format!(
+                                    "Closure: invalid local slot {} (expected an integer in 0..{})",
+                                    index,
+                                    self.stack.len() - base + 1
+                                ));
+                            }
+                            self.capture_upvalue(base + index)
+                        } else {
+                            self.current_upvalue(index)?
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = Closure { function, upvalues };
+                    self.stack.push(Value::Object(Object::Closure(Rc::new(closure))));
+                }
+                OpCode::CloseUpvalue => {
+                    self.peek()?;
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                OpCode::BuildArray => {
+                    let count = self.advance_and_read()? as usize;
+                    if count > self.stack.len() {
+                        return Err(format!(
+                            "BuildArray: invalid operand {} (stack holds {})",
+                            count,
+                            self.stack.len()
+                        ));
+                    }
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    self.stack
+                        .push(Value::Object(Object::Array(Rc::new(RefCell::new(elements)))));
+                }
+                OpCode::IndexGet => {
+                    let index = self.pop()?;
+                    let target = self.pop()?;
+                    let (elements, position) = array_element(&target, &index)?;
+                    let element = elements.borrow()[position].clone();
+                    self.stack.push(element);
+                }
+                OpCode::IndexSet => {
+                    let value = self.pop()?;
+                    let index = self.pop()?;
+                    let target = self.pop()?;
+                    let (elements, position) = array_element(&target, &index)?;
+                    elements.borrow_mut()[position] = value.clone();
+                    self.stack.push(value);
+                }
                 OpCode::Eof => {
                     //println!("Eof");
                     break;
                 }
-                _ => panic!("OpCode not implemented : {:#?}", opcode),
+                _ => return Err(format!("{:?} is not implemented", opcode)),
             }
         }
         //println!("== Commands End ==");
-        InterpretResult::InterpretOk
+        Ok(InterpretResult::InterpretOk)
+    }
+
+    // Calls the value in slot `base` with the `args_count` arguments above it.
+    // Calling a class makes an instance, which replaces the class in slot zero
+    // and is what `init` (if any) returns; a bound method puts its receiver
+    // there instead.
+    fn call_value(&mut self, callee: Value, args_count: usize, base: usize) -> Result<(), String> {
+        self.call_method(callee, args_count, base, None)
+    }
+
+    // `class` is the class defining the callee when it is a method.
+    fn call_method(
+        &mut self,
+        callee: Value,
+        args_count: usize,
+        base: usize,
+        class: Option<Rc<Class>>,
+    ) -> Result<(), String> {
+        let frame = CallFrame {
+            return_chunk: self.chunk,
+            return_ip: self.ip,
+            base,
+            class,
+            closure: None,
+        };
+        match callee {
+            Value::Object(Object::Function(_)) => self.push_frame(&callee, args_count, frame)?,
+            Value::Object(Object::Closure(closure)) => {
+                let function = closure.function.clone();
+                let closure = Some(closure);
+                self.push_frame(&function, args_count, CallFrame { closure, ..frame })?;
+            }
+            Value::Object(Object::NativeFunction(native)) => {
+                let NativeFunction { name, arity, function } = &*native;
+                let arity = *arity;
+                if arity != VARIADIC && arity != args_count {
+                    return Err(format!(
+                        "{}: expected {} arguments but got {}",
+                        name, arity, args_count
+                    ));
+                }
+                let args = self.stack.split_off(base + 1);
+                self.stack.pop();
+                let value = (function.0)(self, &args).map_err(|message| format!("{}: {}", name, message))?;
+                self.stack.push(value);
+            }
+            Value::Object(Object::Class(class)) => {
+                let instance = Instance::new(class.clone());
+                self.stack[base] = Value::Object(Object::Instance(Rc::new(instance)));
+                match class.find_method("init") {
+                    Some((init, holder)) => return self.call_method(init, args_count, base, Some(holder)),
+                    None if args_count > 0 => {
+                        return Err(format!(
+                            "{} has no init but was called with {} arguments",
+                            class.name, args_count
+                        ))
+                    }
+                    None => (),
+                }
+            }
+            Value::Object(Object::BoundMethod(bound)) => {
+                self.stack[base] = bound.receiver.clone();
+                return self.call_method(bound.method.clone(), args_count, base, Some(bound.class.clone()));
+            }
+            Value::Object(Object::Module(module)) if module.call.is_some() => {
+                let function = module.call.clone().unwrap();
+                self.stack[base] = function.clone();
+                return self.call_method(function, args_count, base, None);
+            }
+            callee => return Err(format!("cannot call {}", callee.type_name())),
+        }
+        Ok(())
+    }
+
+    fn push_frame(&mut self, function: &Value, args_count: usize, frame: CallFrame) -> Result<(), String> {
+        let Value::Object(Object::Function(function)) = function else {
+            return Err(format!("cannot call {}", function.type_name()));
+        };
+        let Function { name, chunk, arity, min_arity } = &**function;
+        if args_count < *min_arity || args_count > *arity {
+            let expected = if min_arity == arity {
+                arity.to_string()
+            } else {
+                format!("{} to {}", min_arity, arity)
+            };
+            return Err(format!(
+                "{}: expected {} arguments but got {}",
+                name, expected, args_count
+            ));
+        }
+        if self.max_call_depth.is_some_and(|max| self.frames.len() >= max) {
+            return Err(format!(
+                "stack overflow, max call depth {} exceeded",
+                self.frames.len()
+            ));
+        }
+        // Left-out arguments are marked as missing for their defaults.
+        for _ in args_count..*arity {
+            self.stack.push(Value::Raw);
+        }
+        self.enter(*chunk)?;
+        self.frames.push(frame);
+        self.ip = 0;
+        Ok(())
+    }
+
+    // Switches to running chunk `chunk`.
+    fn enter(&mut self, chunk: usize) -> Result<(), String> {
+        let memory = self.memory;
+        let Some(body) = memory.chunk(chunk) else {
+            return Err(format!("missing chunk {}", chunk));
+        };
+        self.chunk = chunk;
+        self.code = body.code();
+        Ok(())
+    }
+
+    // Returns the upvalue for stack slot `slot`, sharing the one already open
+    // on it so every closure over a variable sees the same variable.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let open = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| *upvalue.borrow() == Upvalue::Open(slot));
+        if let Some(upvalue) = open {
+            return upvalue.clone();
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    // Ends the running frame ahead of a call in tail position: the callee and
+    // arguments at `call` slide down into the frame's slots and the call will
+    // return straight to the frame's caller. Returns the call's new base.
+    fn reuse_frame(&mut self, call: usize) -> usize {
+        let Some(frame) = self.frames.pop() else {
+            return call;
+        };
+        self.close_upvalues(frame.base);
+        let callee_and_args = self.stack.split_off(call);
+        self.stack.truncate(frame.base);
+        self.stack.extend(callee_and_args);
+        // The caller's chunk was running when it called this frame.
+        self.chunk = frame.return_chunk;
+        self.code = self.memory.chunk(self.chunk).map_or(&[], Chunk::code);
+        self.ip = frame.return_ip;
+        frame.base
+    }
+
+    // Moves the variables in slots `from` and up off the stack into the
+    // upvalues that captured them.
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(slot) if slot >= from => {
+                    *upvalue = Upvalue::Closed(stack[slot].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn current_upvalue(&self, index: usize) -> Result<Rc<RefCell<Upvalue>>, String> {
+        self.frames
+            .last()
+            .and_then(|frame| frame.closure.as_ref())
+            .and_then(|closure| closure.upvalues.get(index).cloned())
+            .ok_or_else(|| format!("no upvalue {} in the running function", index))
+    }
+
+    // Seconds since the VM was built, or since the start it was given, which
+    // is what `clock()` reports.
+    pub fn elapsed(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    // The generator behind `rand()`; `seed(n)` replaces it.
+    pub fn random(&mut self) -> &mut Random {
+        &mut self.random
+    }
+
+    // Makes `interpret` stop with `InterpretExit(code)` as soon as the
+    // running native returns.
+    pub fn request_exit(&mut self, code: i32) {
+        self.exit_code = Some(code);
+    }
+
+    pub fn file_error(&self) -> Option<&str> {
+        self.file_error.as_deref()
+    }
+
+    pub fn set_file_error(&mut self, error: Option<String>) {
+        self.file_error = error;
     }
 
-    fn binary_op(&mut self, op: &str) {
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
+    // Writes to the same output as `print`, without a newline, and flushes so
+    // the text shows up straight away.
+    pub fn write(&mut self, text: &str) -> io::Result<()> {
+        write!(self.output, "{}", text)?;
+        self.output.flush()
+    }
+
+    // One line of input without its line ending, or None at end of input.
+    // The prompt goes through the same output as `print`.
+    pub fn read_line(&mut self, prompt: Option<&str>) -> io::Result<Option<String>> {
+        if let Some(prompt) = prompt {
+            self.write(prompt)?;
+        }
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Some(input) => input.read_line(&mut line)?,
+            None => io::stdin().read_line(&mut line)?,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    pub fn register_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.define_global(
+            name,
+            Value::Object(Object::NativeFunction(Rc::new(NativeFunction {
+                name: name.to_owned(),
+                arity,
+                function: Native(function),
+            }))),
+        );
+    }
 
-        if std::mem::discriminant(&a) != std::mem::discriminant(&b) {
-            panic!(
-                "left and right operands of {} not the same left : {:#?}, right {:#?}",
-                op, a, b
-            );
+    pub fn register_module(&mut self, name: &str, members: &[(&str, usize, NativeFn)]) {
+        self.define_module(Module::natives(name, members));
+    }
+
+    pub fn define_module(&mut self, module: Module) {
+        let name = module.name.clone();
+        self.define_global(&name, Value::Object(Object::Module(Rc::new(module))));
+    }
+
+    // Continues execution at `entry`, the offset in the script returned by
+    // `Compiler::compile_incremental` for code appended to this VM's program.
+    pub fn resume_at(&mut self, entry: usize) -> InterpretResult {
+        if let Err(message) = self.enter(0) {
+            return self.runtime_error(&message);
         }
-        match (a.clone(), b.clone()) {
-            (Value::Number(x), Value::Number(y)) => match op {
-                "+" => self.stack.push(Value::Number(x + y)),
-                "-" => self.stack.push(Value::Number(x - y)),
-                "*" => self.stack.push(Value::Number(x * y)),
-                "/" => self.stack.push(Value::Number(x / y)),
-                _ => errorlogger::log_error(&format!("Invalid Binary Operation {:#?}", &self)),
-            },
-            (Value::Object(Object::String(mut x)), Value::Object(Object::String(y))) => match op {
-                "+" => {
-                    x.push_str(&y);
-                    self.stack.push(Value::Object(Object::String(x)));
-                }
-                _ => errorlogger::log_error(&format!("Invalid Binary Operation {:#?}", &self)),
-            },
-            _ => panic!(
-                "left and right operands of {} not the same left : {:#?}, right {:#?}",
-                op, a, b
-            ),
+        self.ip = entry;
+        self.interpret()
+    }
+
+    // Runs the zero-argument function in chunk `chunk` as if it had been
+    // called from the script's final `Eof`, so returning from it ends
+    // interpretation.
+    pub fn run_function(&mut self, chunk: usize) -> InterpretResult {
+        // Nil stands in for the callee in slot zero.
+        self.stack.push(Value::Nil);
+        self.frames.push(CallFrame {
+            return_chunk: 0,
+            return_ip: self.memory.chunk(0).map_or(0, |script| script.len().saturating_sub(1)),
+            base: self.stack.len() - 1,
+            class: None,
+            closure: None,
+        });
+        if let Err(message) = self.enter(chunk) {
+            return self.runtime_error(&message);
         }
+        self.ip = 0;
+        self.interpret()
+    }
+
+    // Logs the instruction at ip with its operand and the top of the stack,
+    // deepest first.
+    fn trace_instruction(&mut self) {
+        const SHOWN: usize = 3;
+        let Some(trace) = self.trace.as_mut() else {
+            return;
+        };
+        let Some(instruction) = self.memory.chunk(self.chunk).and_then(|chunk| chunk.instruction_at(self.ip)) else {
+            return;
+        };
+        let operand = instruction.operand.map(|o| o.to_string()).unwrap_or_default();
+        let top = self.stack.len().saturating_sub(SHOWN);
+        let values: Vec<String> = self.stack[top..]
+            .iter()
+            .map(|value| match value {
+                Value::Object(Object::String(s)) => format!("{:?}", s),
+                value => value.to_string(),
+            })
+            .collect();
+        let more = if top > 0 { "..., " } else { "" };
+        let name = format!("{:?}", instruction.opcode);
+        writeln!(
+            trace,
+            "{:04} {:<16}{:>5} [{}{}]",
+            instruction.offset,
+            name,
+            operand,
+            more,
+            values.join(", ")
+        )
+        .unwrap();
     }
 
+    // Resolves a local-variable operand to a stack index, so corrupt bytecode
+    // surfaces as a runtime error instead of a wild or underflowing index.
+    fn local_slot(&self, operand: &Value, operation: &str) -> Result<usize, String> {
+        let base = self.frames.last().map_or(0, |frame| frame.base);
+        let slot = match operand {
+            Value::Number(x) => to_index(*x, self.stack.len() - base, operation)?,
+            value => return Err(format!("{}: expected a numeric slot, got {}", operation, value.type_name())),
+        };
+        Ok(base + slot)
+    }
+
+    // Records the error against the current line, with the calls that led to
+    // it, and unwinds every call, so an embedder can go on running code on
+    // this VM with its globals intact.
+    fn runtime_error(&mut self, message: &str) -> InterpretResult {
+        // A frame's caller is paused just after its call instruction.
+        let callers = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| (frame.return_chunk, frame.return_ip.saturating_sub(1)));
+        let trace: Vec<(String, usize)> = std::iter::once((self.chunk, self.instruction_start))
+            .chain(callers)
+            .map(|(chunk, offset)| match self.memory.chunk(chunk) {
+                Some(chunk) => (chunk.name().to_owned(), chunk.get_line(offset)),
+                None => ("script".to_owned(), 0),
+            })
+            .collect();
+        self.error = Some(RuntimeError {
+            message: message.to_owned(),
+            line: trace[0].1,
+            ip: self.instruction_start,
+            trace,
+        });
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        InterpretResult::InterpretRuntimeError
+    }
+
+    // Dumps the stack and globals to stdout.
     pub fn debug(&self) {
-        println!("");
-        println!("");
+        println!();
+        println!();
         println!("== Stack ==");
         println!("--------------------------------");
         for i in 0..self.stack.len() {
             println!("{:0>4} -- {:#?}", i, self.stack[i])
         }
-        println!("");
-        println!("");
+        println!();
+        println!();
         println!("== Globals ==");
         println!("--------------------------------");
-        println!("{:#?}", self.globals);
+        println!("{:#?}", self.globals());
+    }
+}
+
+// `+`, `-`, `*`, `/` or `**` on two values. The compiler folds literal
+// operands with it too, so a folded result is the one the VM would compute.
+// Dividing by zero is an error rather than giving inf or NaN. Adding
+// anything to a string joins the two as text, the way `str` renders them.
+pub fn arithmetic(opcode: OpCode, a: &Value, b: &Value) -> Result<Value, String> {
+    let result = match (a, b) {
+        (Value::Number(x), Value::Number(y)) => match opcode {
+            OpCode::Add => x + y,
+            OpCode::Subtract => x - y,
+            OpCode::Multiply => x * y,
+            OpCode::Divide if *y == 0.0 => return Err(format!("division by zero: {} / {}", x, y)),
+            OpCode::Divide => x / y,
+            _ => x.powf(*y),
+        },
+        (Value::Object(Object::String(_)), _) | (_, Value::Object(Object::String(_)))
+            if opcode == OpCode::Add =>
+        {
+            return Ok(Value::Object(Object::String(format!("{}{}", a, b).into())));
+        }
+        _ => {
+            let op = match opcode {
+                OpCode::Add => "+",
+                OpCode::Subtract => "-",
+                OpCode::Multiply => "*",
+                OpCode::Divide => "/",
+                _ => "**",
+            };
+            return Err(format!(
+                "cannot apply {} to {} and {}",
+                op,
+                a.type_name(),
+                b.type_name()
+            ));
+        }
+    };
+    Ok(Value::Number(result))
+}
+
+// Orders two operands of `<` or `>`. Only numbers against numbers and
+// strings against strings, character by character, can be ordered.
+pub fn compare(a: &Value, b: &Value) -> Result<Option<Ordering>, String> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => Ok(x.partial_cmp(y)),
+        (Value::Object(Object::String(x)), Value::Object(Object::String(y))) => {
+            Ok(Some(x.chars().cmp(y.chars())))
+        }
+        _ => Err(format!("cannot order {} and {}", a.type_name(), b.type_name())),
+    }
+}
+
+// Converts a number used as an index or count into a usize, rejecting values
+// that are not finite, integral and within `0..limit`.
+fn to_index(value: f64, limit: usize, operation: &str) -> Result<usize, String> {
+    if !value.is_finite() || value.fract() != 0.0 || value < 0.0 || value >= limit as f64 {
+        return Err(format!(
+            "{}: invalid operand {} (expected an integer in 0..{})",
+            operation, value, limit
+        ));
+    }
+    Ok(value as usize)
+}
+
+// Resolves `target[index]` to the array and an in-bounds position. Negative
+// indexes count back from the end, so `a[-1]` is the last element.
+fn array_element<'a>(
+    target: &'a Value,
+    index: &Value,
+) -> Result<(&'a RefCell<Vec<Value>>, usize), String> {
+    let elements = match target {
+        Value::Object(Object::Array(elements)) => elements.as_ref(),
+        value => return Err(format!("cannot index {}", value.type_name())),
+    };
+    let index = match index {
+        Value::Number(x) if x.is_finite() && x.fract() == 0.0 => *x,
+        Value::Number(x) => return Err(format!("array index must be an integer, got {}", x)),
+        value => return Err(format!("array index must be an integer, got {}", value.type_name())),
+    };
+    let length = elements.borrow().len();
+    let position = if index < 0.0 { index + length as f64 } else { index };
+    if position < 0.0 || position >= length as f64 {
+        return Err(format!(
+            "index {} out of range for array of length {}",
+            index, length
+        ));
     }
+    Ok((elements, position as usize))
 }
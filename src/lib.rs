@@ -0,0 +1,7 @@
+// The interpreter as a library. Every stage is public for tools that want to
+// drive it themselves, and `run_source` and `Interpreter` cover the common
+// case of running scripts from another program.
+mod caurora;
+
+pub use caurora::*;
+pub use caurora::interpreter::{run_source, CauroraError, Interpreter, InterpreterBuilder};
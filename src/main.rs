@@ -1,55 +1,355 @@
-use std::{env, fs, io, process::exit, collections::HashMap};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    process::exit,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
-use caurora::{memoryslice::MemorySlice, values::{Value}, compiler::Compiler};
+use caurora::{
+    bytecode, decompiler, errorlogger, json,
+    scanner::Scanner,
+    stats::{self, CompileStats},
+    token::TokenType,
+    values::Value,
+    virtualmachine::{InterpretResult, RuntimeError},
+    CauroraError, Interpreter,
+};
 
-use crate::caurora::virtualmachine::VM;
-
-use chrono::prelude::*;
+#[derive(Debug, Clone, Default)]
+struct Options {
+    path: String,
+    stats: bool,
+    stats_json: bool,
+    decompile: bool,
+    disassemble: bool,
+    trace: bool,
+    time: bool,
+    test: bool,
+    no_fold: bool,
+    optimize: bool,
+    profile: bool,
+    max_call_depth: Option<usize>,
+    dump_globals: bool,
+    dump_path: Option<String>,
+    // Set by `aurora compile`: where to save the bytecode instead of running.
+    compile_to: Option<String>,
+}
 
-mod caurora;
+impl Options {
+    // Dumping globals to stdout keeps stdout for the JSON alone, so the
+    // script's own output is sent to stderr instead.
+    fn quiet_stdout(&self) -> bool {
+        self.dump_globals && self.dump_path.is_none()
+    }
+}
 
 fn main() {
-    const N: usize = 1_000_000;
-    let start = Local::now().timestamp() as f64;
-    std::thread::Builder::new()
-        .stack_size(1024 * N)
-        .spawn(||{
-    match env::args().len() {
-        2 => run_file(env::args().nth(1).unwrap()).unwrap(),
-        _ => {
-            println!("Usage: aurora [script]");
-            exit(1);
-        }
-    }
-    }).unwrap().join().unwrap();
-    let end = Local::now().timestamp() as f64;
-    println!("Time: {}", end - start);
-}
-
-fn run_file(path: String) -> Result<(), io::Error> {
-    let mut script = Box::leak(fs::read_to_string(path)?.into_boxed_str());
-    run(&script[..]);
-    return Ok(());
-}
-
-fn run(script: & 'static str) -> () {
-    let mut main_memory = MemorySlice::new();
-
-    let mut scanner = caurora::scanner::Scanner::new(script);
-
-    let mut cmplr = Compiler::new(&script, main_memory, scanner);
-    main_memory = cmplr.compile();
-
-    //main_memory.debug("Main");
-    let mut vm = VM {
-        memory: &main_memory,
-        ip: 0,
-        stack: Vec::<Value>::new(),
-        globals: HashMap::<String, Value>::new(),
-        ip_stack: Vec::<usize>::new(),
-        sp: 0,
-        temp_val: Value::Nil,
+    let Some(options) = parse_args(env::args().skip(1).collect()) else {
+        println!("Usage: aurora [--stats | --stats-json | --decompile | --disassemble | --trace | --time | --test | --no-fold | --optimize | --profile | --max-call-depth=N | --dump-globals[=path]] [script]");
+        println!("       aurora compile script.aur [-o script.aurb]");
+        println!("       aurora [run] script.aurb");
+        exit(1);
     };
-    vm.interpret();
-    // vm.debug();
+    if options.path.is_empty() {
+        repl();
+        return;
+    }
+    if let Err(message) = run_file(&options) {
+        eprintln!("aurora: {}", message);
+        exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Option<Options> {
+    let mut options = Options::default();
+    let mut args = args.into_iter().peekable();
+    let compile = args.peek().is_some_and(|arg| arg == "compile");
+    if compile || args.peek().is_some_and(|arg| arg == "run") {
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" if compile => options.compile_to = Some(args.next()?),
+            "--stats" => options.stats = true,
+            "--stats-json" => options.stats_json = true,
+            "--decompile" => options.decompile = true,
+            "--disassemble" => options.disassemble = true,
+            "--trace" => options.trace = true,
+            "--time" => options.time = true,
+            "--test" => options.test = true,
+            "--no-fold" => options.no_fold = true,
+            "--optimize" => options.optimize = true,
+            "--profile" => options.profile = true,
+            "--dump-globals" => options.dump_globals = true,
+            _ if arg.starts_with("--max-call-depth=") => {
+                options.max_call_depth = Some(arg["--max-call-depth=".len()..].parse().ok()?);
+            }
+            _ if arg.starts_with("--dump-globals=") => {
+                options.dump_globals = true;
+                options.dump_path = Some(arg["--dump-globals=".len()..].to_owned());
+            }
+            _ if arg.starts_with("--") || !options.path.is_empty() => return None,
+            _ => options.path = arg,
+        }
+    }
+    // Without a script there is nothing for the flags to act on.
+    let flagged = options.stats
+        || options.stats_json
+        || options.decompile
+        || options.disassemble
+        || options.trace
+        || options.time
+        || options.test
+        || options.no_fold
+        || options.optimize
+        || options.profile
+        || options.max_call_depth.is_some()
+        || options.dump_globals
+        || compile;
+    if options.path.is_empty() && flagged {
+        return None;
+    }
+    if compile && options.compile_to.is_none() {
+        let saved = Path::new(&options.path).with_extension("aurb");
+        options.compile_to = Some(saved.to_string_lossy().into_owned());
+    }
+    Some(options)
+}
+
+fn run_file(options: &Options) -> Result<(), String> {
+    let bytes = fs::read(&options.path)
+        .map_err(|e| format!("could not read '{}': {}", options.path, e))?;
+    let mut interpreter = interpreter(options);
+    let (memory, compile_stats) = if bytes.starts_with(bytecode::MAGIC) {
+        let memory = bytecode::decode(&bytes).map_err(|e| format!("'{}': {}", options.path, e))?;
+        let compile_stats = CompileStats::of_memory(&memory);
+        (memory, compile_stats)
+    } else {
+        let script = decode_source(&options.path, bytes)?;
+        let compile_start = Instant::now();
+        let compiled = interpreter.compile(&script);
+        for warning in interpreter.warnings() {
+            errorlogger::log_warning(&warning.to_string());
+        }
+        let (memory, _) = compiled.unwrap_or_else(|error| fail(error));
+        if options.time {
+            report_time("compile", compile_start.elapsed());
+        }
+        if let Some(path) = &options.compile_to {
+            let bytes = bytecode::encode(&memory)?;
+            return fs::write(path, bytes).map_err(|e| format!("could not write '{}': {}", path, e));
+        }
+        (memory, interpreter.compile_stats().clone())
+    };
+
+    if options.decompile {
+        print!("{}", decompiler::decompile(&memory));
+        return Ok(());
+    }
+
+    if options.disassemble {
+        print!("{}", memory.disassemble());
+        return Ok(());
+    }
+
+    let run_start = Instant::now();
+    let result = interpreter.execute(memory, 0);
+    if options.time {
+        report_time("run", run_start.elapsed());
+    }
+    if let Some(profile) = interpreter.profile() {
+        eprint!("{}", stats::report_profile(profile));
+    }
+    if let Err(error) = result {
+        fail(error);
+    }
+
+    if options.test {
+        run_tests(&mut interpreter);
+        return Ok(());
+    }
+    if options.dump_globals {
+        dump_globals(interpreter.globals(), options.dump_path.as_deref());
+    }
+    if options.stats {
+        eprint!("{}", stats::report(&compile_stats, interpreter.execution_stats()));
+    }
+    if options.stats_json {
+        eprintln!("{}", stats::report_json(&compile_stats, interpreter.execution_stats()));
+    }
+    Ok(())
+}
+
+// Scripts must be UTF-8; a leading byte order mark is accepted and dropped.
+fn decode_source(path: &str, mut bytes: Vec<u8>) -> Result<String, String> {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        bytes.drain(..3);
+    }
+    String::from_utf8(bytes).map_err(|e| {
+        format!(
+            "'{}' is not valid UTF-8 (invalid byte sequence at offset {})",
+            path,
+            e.utf8_error().valid_up_to()
+        )
+    })
+}
+
+fn interpreter(options: &Options) -> Interpreter {
+    let mut builder = Interpreter::builder()
+        .path(&options.path)
+        .fold(!options.no_fold)
+        .optimize(options.optimize)
+        .profile(options.profile)
+        .stats(options.stats || options.stats_json);
+    if options.quiet_stdout() {
+        builder = builder.output(Box::new(io::stderr()));
+    }
+    if options.trace {
+        builder = builder.trace(Box::new(io::stderr()));
+    }
+    if let Some(depth) = options.max_call_depth {
+        builder = builder.max_call_depth(depth);
+    }
+    builder.build()
+}
+
+// Reports why the script stopped and exits with the status for it.
+fn fail(error: CauroraError) -> ! {
+    match error {
+        CauroraError::Compile(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            exit(InterpretResult::InterpretCompileError.exit_code());
+        }
+        CauroraError::Runtime(error) => {
+            report_runtime_error(&error);
+            exit(InterpretResult::InterpretRuntimeError.exit_code());
+        }
+        CauroraError::Exit(code) => exit(code),
+        CauroraError::OutOfFuel => {
+            eprintln!("aurora: {}", error);
+            exit(InterpretResult::InterpretOutOfFuel.exit_code());
+        }
+    }
+}
+
+// Reads statements from stdin and runs each as soon as it is complete, all on
+// one interpreter, so functions and variables defined earlier stay usable. A
+// `{` left open continues the entry on the next line.
+fn repl() {
+    let stdin = io::stdin();
+    let mut interpreter = Interpreter::builder().path("<repl>").echo(true).build();
+    let mut entry = String::new();
+    loop {
+        print!("{}", if entry.is_empty() { "> " } else { "... " });
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                println!();
+                return;
+            }
+            Ok(_) => entry.push_str(&line),
+        }
+        if open_braces(&entry) > 0 {
+            continue;
+        }
+        let source = std::mem::take(&mut entry);
+        match interpreter.run(&source) {
+            Err(CauroraError::Exit(code)) => exit(code),
+            Err(error) => eprintln!("{}", error),
+            Ok(()) => (),
+        }
+    }
+}
+
+// How many more `{` than `}` the entry has so far.
+fn open_braces(source: &str) -> usize {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0usize;
+    loop {
+        match scanner.scan_token().tokentype {
+            TokenType::Eof | TokenType::Error => return depth,
+            TokenType::LeftBrace => depth += 1,
+            TokenType::RightBrace => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+}
+
+// An error inside a function is followed by the calls that led to it,
+// innermost first.
+fn report_runtime_error(error: &RuntimeError) {
+    eprintln!("Runtime error on {}", error);
+    if error.trace.len() > 1 {
+        // Deep recursion repeats one call over and over; it is shown once
+        // with a count.
+        let mut calls = error.trace.iter().peekable();
+        while let Some(call @ (function, line)) = calls.next() {
+            eprintln!("    at {} (line {})", function, line);
+            let mut repeats = 0;
+            while calls.next_if_eq(&call).is_some() {
+                repeats += 1;
+            }
+            if repeats > 0 {
+                eprintln!("    ... the same call {} more times", repeats);
+            }
+        }
+    }
+}
+
+// Timings go to stderr so they never mix with the script's output.
+fn report_time(phase: &str, elapsed: Duration) {
+    eprintln!("{}: {:.3} ms", phase, elapsed.as_secs_f64() * 1000.0);
+}
+
+// Writes the globals the script defined, leaving out untouched builtins.
+fn dump_globals(globals: &HashMap<Rc<str>, Value>, path: Option<&str>) {
+    let builtins = Interpreter::new();
+    let defined: HashMap<Rc<str>, Value> = globals
+        .iter()
+        .filter(|(name, value)| builtins.global(name) != Some(*value))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    let dump = json::globals_json(&defined);
+    match path {
+        Some(path) => {
+            if let Err(e) = fs::write(path, dump + "\n") {
+                eprintln!("aurora: could not write '{}': {}", path, e);
+                exit(1);
+            }
+        }
+        None => println!("{}", dump),
+    }
+}
+
+// Runs every test block on the globals the script's top level defined.
+fn run_tests(interpreter: &mut Interpreter) {
+    let (mut total, mut failed) = (0, 0);
+    interpreter.run_tests(|name, result| {
+        total += 1;
+        match result {
+            Ok(()) | Err(CauroraError::Exit(0)) => println!("PASS {}", name),
+            Err(CauroraError::Exit(code)) => {
+                failed += 1;
+                println!("FAIL {}\n    exited with code {}", name, code);
+            }
+            Err(CauroraError::Runtime(error)) => {
+                failed += 1;
+                println!("FAIL {}\n    {}", name, error);
+            }
+            Err(error) => {
+                failed += 1;
+                println!("FAIL {}\n    {}", name, error);
+            }
+        }
+    });
+    println!("{} passed, {} failed", total - failed, failed);
+    if failed > 0 {
+        exit(1);
+    }
 }
@@ -0,0 +1,160 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+use caurora::opcodes::OpCode;
+
+fn aurora(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_caurora"))
+        .args(args)
+        .output()
+        .expect("could not run caurora")
+}
+
+// Compiles the example to a bytecode file of its own in the temp directory.
+fn compile(example: &str) -> PathBuf {
+    let saved = env::temp_dir().join(format!("aurora-{}-{}.aurb", std::process::id(), example));
+    let script = format!("examples/{}.aur", example);
+    let output = aurora(&["compile", &script, "-o", saved.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    saved
+}
+
+// Runs a corrupted copy of a compiled example, expecting a clean error.
+fn load_error(example: &str, corrupt: impl Fn(&mut Vec<u8>)) -> String {
+    let saved = compile(example);
+    let mut bytes = fs::read(&saved).unwrap();
+    corrupt(&mut bytes);
+    fs::write(&saved, bytes).unwrap();
+    let output = aurora(&[saved.to_str().unwrap()]);
+    fs::remove_file(saved).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn precompiled_programs_run_like_their_source() {
+    for example in ["test10", "test11", "test94", "test95"] {
+        let saved = compile(example);
+        let direct = aurora(&[&format!("examples/{}.aur", example)]);
+        let loaded = aurora(&["run", saved.to_str().unwrap()]);
+        fs::remove_file(saved).unwrap();
+        assert_eq!(loaded.stdout, direct.stdout, "{}", example);
+        assert!(loaded.status.success());
+    }
+}
+
+#[test]
+fn tests_blocks_survive_compilation() {
+    let saved = compile("test93");
+    let output = aurora(&["--test", saved.to_str().unwrap()]);
+    fs::remove_file(saved).unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().contains("5 passed, 0 failed"));
+}
+
+#[test]
+fn truncated_files_are_rejected() {
+    let error = load_error("test11", |bytes| bytes.truncate(bytes.len() / 2));
+    assert!(error.contains("truncated"), "{}", error);
+}
+
+#[test]
+fn other_versions_are_rejected() {
+    let error = load_error("test11", |bytes| bytes[4] = 99);
+    assert!(error.contains("bytecode version 99 is not supported"), "{}", error);
+}
+
+#[test]
+fn unknown_opcodes_are_rejected() {
+    // The script's code follows the magic, the version, the chunk count,
+    // the script's name and the code's own length.
+    let error = load_error("test11", |bytes| {
+        bytes[24] = 0xff;
+        bytes[25] = 0xff;
+    });
+    assert!(error.contains("invalid opcode 65535 at 0"), "{}", error);
+}
+
+// Compiles a small script and runs it with its one number constant, the slot
+// of the function's parameter, replaced by `slot`.
+fn run_with_slot(slot: f64) -> Output {
+    let script = env::temp_dir().join(format!("aurora-{}-slot-{}.aur", std::process::id(), slot));
+    let saved = script.with_extension("aurb");
+    fs::write(&script, "function show(a) {\n    print a;\n}\nshow(\"x\");\n").unwrap();
+    let compiled = aurora(&["compile", script.to_str().unwrap(), "-o", saved.to_str().unwrap()]);
+    assert!(compiled.status.success());
+    let bytes = fs::read(&saved).unwrap();
+    let one = 1f64.to_le_bytes();
+    let at = bytes.windows(8).position(|window| window == one).unwrap();
+    let mut corrupt = bytes.clone();
+    corrupt[at..at + 8].copy_from_slice(&slot.to_le_bytes());
+    fs::write(&saved, corrupt).unwrap();
+    let output = aurora(&[saved.to_str().unwrap()]);
+    fs::remove_file(script).unwrap();
+    fs::remove_file(saved).unwrap();
+    output
+}
+
+#[test]
+fn bad_local_slots_are_runtime_errors() {
+    let output = run_with_slot(1.0);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "x\n");
+    for slot in [1.5, 9.0, -1.0, f64::NAN] {
+        let output = run_with_slot(slot);
+        assert_eq!(output.status.code(), Some(70));
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            format!(
+                "Runtime error on line 2: GetLocalVar: invalid operand {} (expected an integer in 0..2)\n    \
+                 at show (line 2)\n    at script (line 4)\n",
+                slot
+            )
+        );
+    }
+}
+
+#[test]
+fn truncated_files_name_where_they_end() {
+    let saved = compile("test11");
+    let bytes = fs::read(&saved).unwrap();
+    fs::write(&saved, &bytes[..20]).unwrap();
+    let output = aurora(&[saved.to_str().unwrap()]);
+    fs::remove_file(&saved).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        format!("aurora: '{}': bytecode is truncated at byte 20\n", saved.display())
+    );
+}
+
+// A closure capturing a local slot past the frame's top is refused before
+// anything reads the slot.
+#[test]
+fn bad_capture_slots_are_runtime_errors() {
+    let script = env::temp_dir().join(format!("aurora-{}-capture.aur", std::process::id()));
+    let saved = script.with_extension("aurb");
+    let source = "function outer(a) {\n    function inner() {\n        return a;\n    }\n    return inner;\n}\n";
+    fs::write(&script, format!("{}print outer(1)();\n", source)).unwrap();
+    let compiled = aurora(&["compile", script.to_str().unwrap(), "-o", saved.to_str().unwrap()]);
+    assert!(compiled.status.success());
+    let mut bytes = fs::read(&saved).unwrap();
+    let capture: Vec<u8> = [OpCode::Closure.repr(), 1, OpCode::CaptureLocal.repr(), 1]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    let at = bytes.windows(capture.len()).position(|window| window == capture).unwrap();
+    bytes[at + 6..at + 8].copy_from_slice(&255u16.to_le_bytes());
+    fs::write(&saved, bytes).unwrap();
+    let output = aurora(&[saved.to_str().unwrap()]);
+    fs::remove_file(script).unwrap();
+    fs::remove_file(saved).unwrap();
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Runtime error on line 4: Closure: invalid local slot 255 (expected an integer in 0..3)\n    \
+         at outer (line 4)\n    at script (line 7)\n"
+    );
+}
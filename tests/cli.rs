@@ -0,0 +1,161 @@
+use std::process::{Command, Output};
+
+fn aurora(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_caurora"))
+        .args(args)
+        .output()
+        .expect("could not run caurora")
+}
+
+#[test]
+fn a_plain_run_prints_only_the_script_output() {
+    let output = aurora(&["examples/test11.aur"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn time_reports_compile_and_run_on_stderr() {
+    let output = aurora(&["--time", "examples/test11.aur"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let phases: Vec<&str> = stderr.lines().map(|line| line.split(':').next().unwrap()).collect();
+    assert_eq!(phases, ["compile", "run"]);
+    assert!(stderr.lines().all(|line| line.ends_with(" ms")));
+}
+
+#[test]
+fn runtime_errors_name_the_line_they_happen_on() {
+    let output = aurora(&["examples/test99.aur"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "small small small large \n132\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Runtime error on line 24: cannot apply + to nil and number\n"
+    );
+}
+
+#[test]
+fn saved_bytecode_keeps_the_lines() {
+    let saved = std::env::temp_dir().join(format!("aurora-lines-{}.aurb", std::process::id()));
+    let saved = saved.to_str().unwrap();
+    assert!(aurora(&["compile", "examples/test99.aur", "-o", saved]).status.success());
+    let output = aurora(&[saved]);
+    std::fs::remove_file(saved).unwrap();
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Runtime error on line 24: cannot apply + to nil and number\n"
+    );
+}
+
+#[test]
+fn errors_inside_calls_print_a_stack_trace() {
+    let output = aurora(&["examples/test100.aur"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "checking\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Runtime error on line 7: undefined variable 'missing'\n    at b (line 7)\n    at a (line 11)\n    at script (line 14)\n"
+    );
+}
+
+#[test]
+fn stack_overflows_fold_the_repeated_calls() {
+    let output = aurora(&["--max-call-depth=50", "examples/test102.aur"]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Runtime error on line 4: stack overflow, max call depth 50 exceeded\n    at down (line 4)\n    ... the same call 49 more times\n    at script (line 7)\n"
+    );
+}
+
+#[test]
+fn a_shebang_line_is_skipped() {
+    let output = aurora(&["examples/test103.aur"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "42\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn exit_status_tells_how_the_script_ended() {
+    assert_eq!(aurora(&["examples/test11.aur"]).status.code(), Some(0));
+    assert_eq!(aurora(&["examples/test29.aur"]).status.code(), Some(65));
+    assert_eq!(aurora(&["examples/test99.aur"]).status.code(), Some(70));
+    assert_eq!(aurora(&["examples/test64.aur"]).status.code(), Some(3));
+    assert_eq!(aurora(&["--test", "examples/test99.aur"]).status.code(), Some(70));
+}
+
+#[test]
+fn profile_prints_a_table_after_the_run() {
+    let output = aurora(&["--profile", "examples/test11.aur"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("== Profile ==\n"));
+    assert!(stderr.lines().any(|line| line.starts_with("Eof ")));
+}
+
+// Writes `bytes` to a script of its own in the temp directory and runs it.
+fn run_bytes(name: &str, bytes: &[u8]) -> (String, Output) {
+    let script = std::env::temp_dir().join(format!("aurora-{}-{}.aur", name, std::process::id()));
+    std::fs::write(&script, bytes).unwrap();
+    let output = aurora(&[script.to_str().unwrap()]);
+    std::fs::remove_file(&script).unwrap();
+    (script.to_str().unwrap().to_owned(), output)
+}
+
+#[test]
+fn a_utf8_byte_order_mark_is_skipped() {
+    let (_, output) = run_bytes("bom", b"\xef\xbb\xbfprint \"caf\xc3\xa9\";\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "caf\u{e9}\n");
+    assert!(output.stderr.is_empty());
+    assert_eq!(output.status.code(), Some(0));
+
+    // Columns on the first line do not count the mark.
+    let (path, output) = run_bytes("bom-error", b"\xef\xbb\xbfprint 1 +;\n");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        format!("{}:1:10: expect expression\n", path)
+    );
+}
+
+#[test]
+fn files_that_are_not_utf8_are_rejected() {
+    let (path, output) = run_bytes("latin1", b"print \"caf\xe9\";\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        format!("aurora: '{}' is not valid UTF-8 (invalid byte sequence at offset 10)\n", path)
+    );
+}
+
+#[test]
+fn missing_files_name_the_path() {
+    let output = aurora(&["examples/no-such-script.aur"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "aurora: could not read 'examples/no-such-script.aur': No such file or directory (os error 2)\n"
+    );
+}
+
+#[test]
+fn arity_mismatches_stop_the_script_without_a_panic() {
+    let output = aurora(&["examples/test69.aur"]);
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "[1, 2]\n");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Runtime error on line 6: pair: expected 2 arguments but got 3\n"
+    );
+}
+
+#[test]
+fn import_cycles_show_every_path_as_written_from_the_script() {
+    let output = aurora(&["examples/test60.aur"]);
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "examples/import/cycle_b.aur:1:8: import cycle: examples/test60.aur -> examples/import/cycle_a.aur \
+         -> examples/import/cycle_b.aur -> examples/import/cycle_a.aur\n"
+    );
+}
@@ -0,0 +1,142 @@
+use std::process::Command;
+
+use caurora::{compiler::Compiler, memoryslice::MemorySlice, scanner::Scanner};
+
+fn errors(source: &str) -> Vec<String> {
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    compiler.set_path("bad.aur");
+    match compiler.compile() {
+        Ok(_) => panic!("expected {:?} not to compile", source),
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+#[test]
+fn a_missing_semicolon_names_what_was_found() {
+    assert_eq!(
+        errors("var a = 1;\nprint a\nprint 2;"),
+        ["bad.aur:3:1: expect ';' after value, found 'print'"]
+    );
+    assert_eq!(
+        errors("var a = 1;\nprint a"),
+        ["bad.aur:2:8: expect ';' after value, found end of file"]
+    );
+}
+
+#[test]
+fn malformed_scripts_report_an_error_instead_of_panicking() {
+    let cases = [
+        ("var x = 1;\n\n1 + 2 = x;", "bad.aur:3:7: invalid assignment target"),
+        ("print (1 + 2;", "bad.aur:1:13: expect ')' after expression, found ';'"),
+        ("var a = [1 2];", "bad.aur:1:12: expect ',' or ']' after array element, found '2'"),
+        ("function f(a {\n}", "bad.aur:1:14: expect ')' after parameters, found '{'"),
+        ("var = 3;", "bad.aur:1:5: expect identifier after var, found '='"),
+        ("print ;", "bad.aur:1:7: expect value after 'print'"),
+        ("print 1 +", "bad.aur:1:10: expect expression"),
+    ];
+    for (source, expected) in cases {
+        assert_eq!(errors(source), [expected], "{:?}", source);
+    }
+}
+
+#[test]
+fn each_mistake_is_reported_once_in_a_single_pass() {
+    let source = "var a = 1\nprint a;\nvar b = (2 + ;\nfunction f(x {\n  return x;\n}\nprint b c d e;\n";
+    assert_eq!(
+        errors(source),
+        [
+            "bad.aur:2:1: expect ';' after value, found 'print'",
+            "bad.aur:3:14: expect expression",
+            "bad.aur:4:14: expect ')' after parameters, found '{'",
+            "bad.aur:7:9: expect ';' after value, found 'c'",
+        ]
+    );
+}
+
+#[test]
+fn every_line_ending_style_counts_one_line() {
+    let source = "var s = \"two\nlines\";\n/* a\n   comment */\nprint s\nprint 1;\n@";
+    for ending in ["\n", "\r\n", "\r"] {
+        assert_eq!(
+            errors(&source.replace('\n', ending)),
+            [
+                "bad.aur:6:1: expect ';' after value, found 'print'",
+                "bad.aur:7:1: unexpected character '@'\n    @\n    ^",
+            ],
+            "{:?}",
+            ending
+        );
+    }
+}
+
+#[test]
+fn scan_errors_show_the_line_with_a_caret() {
+    assert_eq!(
+        errors("var a = 1;\nprint a + \"open;\nprint a;"),
+        ["bad.aur:2:11: unterminated string\n    print a + \"open;\n              ^"]
+    );
+    assert_eq!(
+        errors("var a = 1;\n\tprint a ? 2;"),
+        ["bad.aur:2:10: unexpected character '?'\n    \tprint a ? 2;\n    \t        ^"]
+    );
+}
+
+#[test]
+fn only_a_leading_shebang_is_skipped() {
+    assert_eq!(errors("#!/usr/bin/env aurora\nprint 1\n"), ["bad.aur:3:1: expect ';' after value, found end of file"]);
+    assert_eq!(errors(" #!/usr/bin/env aurora\nprint 1;")[0], "bad.aur:1:2: unexpected character '#'\n     #!/usr/bin/env aurora\n     ^");
+    assert_eq!(errors("print 1;\n#!x")[0], "bad.aur:2:1: unexpected character '#'\n    #!x\n    ^");
+}
+
+#[test]
+fn recovery_stops_at_the_end_of_a_block() {
+    let source = "if (true) {\n  print 1 2;\n}\nprint 3";
+    assert_eq!(
+        errors(source),
+        [
+            "bad.aur:2:11: expect ';' after value, found '2'",
+            "bad.aur:4:8: expect ';' after value, found end of file",
+        ]
+    );
+}
+
+#[test]
+fn the_error_keeps_the_expected_and_found_tokens() {
+    let source = "print 1";
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    let error = compiler.compile().unwrap_err().remove(0);
+    assert_eq!(error.line, 1);
+    assert_eq!(error.message, "expect ';' after value");
+    assert_eq!(error.expected, Some(caurora::token::TokenType::SemiColon));
+    assert_eq!(error.found.as_deref(), Some("end of file"));
+}
+
+#[test]
+fn the_cli_exits_65_without_running_anything() {
+    let output = Command::new(env!("CARGO_BIN_EXE_caurora"))
+        .arg("examples/test29.aur")
+        .output()
+        .expect("could not run caurora");
+    assert_eq!(output.status.code(), Some(65));
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "examples/test29.aur:4:9: expect ';' after value, found 'a'\n"
+    );
+}
+
+#[test]
+fn naming_a_constant_past_a_u16_is_reported() {
+    let mut source: String = (0..33_000).map(|i| format!("print \"s{}\" + {};\n", i, i)).collect();
+    source.push_str("var point = nil;\nprint point.x;\n");
+    assert_eq!(errors(&source), ["bad.aur:33002:13: too many constants in one program"]);
+}
+
+#[test]
+fn comparisons_cannot_be_chained() {
+    assert_eq!(errors("var x = 5;\nprint 0 < x < 10;"), ["bad.aur:2:13: comparisons cannot be chained"]);
+    assert_eq!(errors("print 1 == 1 != false;"), ["bad.aur:1:14: comparisons cannot be chained"]);
+    // Parentheses make the left comparison an operand of its own.
+    let source = "print (1 == 1) != false;";
+    assert!(Compiler::new(source, MemorySlice::new(), Scanner::new(source)).compile().is_ok());
+}
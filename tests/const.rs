@@ -0,0 +1,57 @@
+use caurora::{compiler::Compiler, memoryslice::MemorySlice, scanner::Scanner, CauroraError, Interpreter};
+
+fn errors(source: &str) -> Vec<String> {
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    match compiler.compile() {
+        Ok(_) => panic!("expected {:?} not to compile", source),
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+#[test]
+fn constants_read_like_variables() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter
+        .run("const PI = 3;\nfunction area(r) { return PI * r * r; }\n{\n    const two = 2;\n    print area(two);\n}")
+        .unwrap();
+    assert_eq!(interpreter.take_output(), "12\n");
+}
+
+#[test]
+fn assigning_to_a_constant_is_a_compile_error() {
+    assert_eq!(errors("const PI = 3;\n\nPI = 4;"), ["<script>:3:1: cannot assign to constant 'PI'"]);
+    assert_eq!(errors("{\n  const n = 1;\n  n++;\n}"), ["<script>:3:3: cannot assign to constant 'n'"]);
+    assert_eq!(errors("{\n  const n = 1;\n  print --n;\n}"), ["<script>:3:11: cannot assign to constant 'n'"]);
+    assert_eq!(
+        errors("{\n  const n = 1;\n  function f() {\n    n = 2;\n  }\n}"),
+        ["<script>:4:5: cannot assign to constant 'n'"]
+    );
+    // Code above a global constant's declaration is held to it as well.
+    assert_eq!(
+        errors("function reset() {\n  LIMIT = 0;\n}\nconst LIMIT = 10;"),
+        ["<script>:2:3: cannot assign to constant 'LIMIT'"]
+    );
+    assert_eq!(errors("const A = 1;\nvar A = 2;"), ["<script>:2:5: cannot redeclare constant 'A'"]);
+    assert_eq!(errors("const A;"), ["<script>:1:8: expect '=' after constant name, found ';'"]);
+}
+
+#[test]
+fn a_var_in_an_inner_scope_may_shadow_a_constant() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter
+        .run("const x = 1;\n{\n    var x = 2;\n    x = 3;\n    print x;\n}\nprint x;")
+        .unwrap();
+    assert_eq!(interpreter.take_output(), "3\n1\n");
+}
+
+#[test]
+fn later_runs_cannot_assign_to_earlier_constants() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run("const answer = 42;").unwrap();
+    let Err(CauroraError::Compile(errors)) = interpreter.run("answer = 0;") else {
+        panic!("expected the assignment not to compile");
+    };
+    assert_eq!(errors[0].message, "cannot assign to constant 'answer'");
+    interpreter.run("print answer;").unwrap();
+    assert_eq!(interpreter.take_output(), "42\n");
+}
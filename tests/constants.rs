@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    scanner::Scanner,
+    values::{Object, Value},
+    virtualmachine::{InterpretResult, VM},
+};
+
+fn compile(source: &str) -> MemorySlice {
+    Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap()
+}
+
+// Two lines per constant pair, every string and number distinct.
+fn generated(lines: usize) -> String {
+    (0..lines)
+        .map(|i| format!("print \"string {}\";\nprint {};\n", i, i))
+        .collect()
+}
+
+fn time_to_compile(source: &str) -> Duration {
+    (0..3)
+        .map(|_| {
+            let start = Instant::now();
+            compile(source);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+#[test]
+fn identical_literals_share_one_constant() {
+    let memory = compile("print \"same\"; print \"same\"; print 2; print 2; print \"other\";");
+    let strings = memory
+        .constants()
+        .iter()
+        .filter(|constant| **constant == Value::Object(Object::String("same".into())))
+        .count();
+    let twos = memory.constants().iter().filter(|constant| **constant == Value::Number(2.0)).count();
+    assert_eq!((strings, twos), (1, 1));
+    assert_eq!(memory.get_deduplicated_count(), 2);
+}
+
+#[test]
+fn numbers_are_shared_by_their_bits() {
+    let mut memory = MemorySlice::new();
+    let zero = memory.push_constant(OpCode::Constant, Value::Number(0.0)).unwrap();
+    let negative_zero = memory.push_constant(OpCode::Constant, Value::Number(-0.0)).unwrap();
+    assert_ne!(zero, negative_zero);
+    let nan = memory.push_constant(OpCode::Constant, Value::Number(f64::NAN)).unwrap();
+    assert_eq!(memory.push_constant(OpCode::Constant, Value::Number(f64::NAN)), Ok(nan));
+    assert_eq!(memory.push_constant(OpCode::Constant, Value::Number(-0.0)), Ok(negative_zero));
+    assert_eq!(memory.get_constants_size(), 3);
+}
+
+// Eight times the distinct constants should take about eight times as long to
+// compile; a pool searched linearly would take about sixty-four.
+#[test]
+fn compile_time_grows_linearly_with_distinct_constants() {
+    let small = generated(2_000);
+    let large = generated(16_000);
+    assert_eq!(compile(&large).get_constants_size(), 2 * 16_000);
+    let ratio = time_to_compile(&large).as_secs_f64() / time_to_compile(&small).as_secs_f64();
+    assert!(ratio < 24.0, "8x the constants took {:.1}x as long to compile", ratio);
+}
+
+// Past u16::MAX constants, loading one takes ConstantLong and its two-word
+// index, while an opcode with no long form refuses the index.
+#[test]
+fn constants_past_a_u16_are_loaded_with_a_long_index() {
+    let mut memory = MemorySlice::new();
+    for i in 0..70_000 {
+        memory.push_constant(OpCode::Constant, Value::Number(i as f64)).unwrap();
+        memory.push(OpCode::Pop);
+    }
+    let last = memory.push_constant(OpCode::Constant, Value::Number(69_999.0)).unwrap();
+    assert_eq!(last, 69_999);
+    memory.push_global(OpCode::DefineGlobalSlot, "last").unwrap();
+    memory.push(OpCode::Eof);
+
+    let instructions: Vec<_> = memory.instructions().collect();
+    let long = instructions.iter().filter(|i| i.opcode == OpCode::ConstantLong).count();
+    assert_eq!(long, 70_000 - 65_536 + 1);
+    let load = instructions[instructions.len() - 3];
+    assert_eq!((load.opcode, load.operand), (OpCode::ConstantLong, Some(69_999)));
+    assert!(memory.disassemble().contains("ConstantLong    69999 69999\n"));
+
+    let mut vm = VM::new(&memory);
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    assert_eq!(vm.globals().get("last"), Some(&Value::Number(69_999.0)));
+
+    let name = Value::Object(Object::String("field".into()));
+    assert_eq!(
+        memory.push_constant(OpCode::GetProperty, name),
+        Err("too many constants in one program".to_owned())
+    );
+}
@@ -0,0 +1,136 @@
+use caurora::{
+    compiler::{CompileError, Compiler},
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    scanner::Scanner,
+    Interpreter,
+};
+
+fn compile(source: &str) -> (MemorySlice, Vec<String>) {
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    let memory = compiler.compile().unwrap();
+    let warnings = compiler.warnings().iter().map(CompileError::to_string).collect();
+    (memory, warnings)
+}
+
+fn run(source: &str) -> String {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(source).unwrap();
+    interpreter.take_output()
+}
+
+fn count(memory: &MemorySlice, opcode: OpCode) -> usize {
+    memory.instructions().filter(|instruction| instruction.opcode == opcode).count()
+}
+
+#[test]
+fn code_after_a_return_is_dropped() {
+    let source = "
+function first(x) {
+    return x;
+    print \"dead\";
+    x = x + 1;
+    print x;
+}
+print first(1);
+";
+    let (memory, warnings) = compile(source);
+    assert_eq!(warnings, ["<script>:4:5: unreachable code"]);
+    assert_eq!(count(&memory, OpCode::Print), 1);
+    assert_eq!(count(&memory, OpCode::Add), 0);
+    assert!(!memory.disassemble().contains("\"dead\""));
+    assert_eq!(run(source), "1\n");
+}
+
+#[test]
+fn locals_declared_in_dropped_code_keep_later_slots_in_place() {
+    let source = "
+function pick(x) {
+    var before = \"before\";
+    if (x) {
+        return \"early\";
+        var dead = \"dead\";
+        print dead;
+    }
+    if (false) {
+        var skipped = 1;
+    }
+    var after = \"after\";
+    {
+        var inner = \"inner\";
+        print before + \" \" + after + \" \" + inner;
+    }
+    return after;
+}
+print pick(true);
+print pick(false);
+";
+    let (memory, warnings) = compile(source);
+    assert_eq!(warnings, ["<script>:6:9: unreachable code", "<script>:9:16: unreachable code"]);
+    assert_eq!(count(&memory, OpCode::Print), 3);
+    assert_eq!(run(source), "early\nbefore after inner\nafter\n");
+}
+
+#[test]
+fn a_branch_a_literal_condition_rules_out_is_dropped() {
+    let source = "
+if (false) {
+    print \"never\";
+} else {
+    print \"always\";
+}
+if (1 < 2) print \"folded\"; else print \"not folded\";
+if (nil) print \"nil\";
+";
+    let (memory, warnings) = compile(source);
+    assert_eq!(warnings.len(), 3);
+    assert_eq!(count(&memory, OpCode::Print), 2);
+    assert_eq!(count(&memory, OpCode::JmpFalse) + count(&memory, OpCode::Jmp), 0);
+    assert_eq!(run(source), "always\nfolded\n");
+}
+
+#[test]
+fn an_if_whose_branches_both_return_ends_the_block() {
+    let source = "
+function sign(x) {
+    if (x < 0) {
+        return -1;
+    } else {
+        return 1;
+    }
+    print \"dead\";
+}
+function inner() {
+    function helper() { return 1; }
+    return helper;
+    function dropped() { return 2; }
+}
+print sign(-5);
+print inner()();
+";
+    let (memory, warnings) = compile(source);
+    assert_eq!(warnings, ["<script>:8:5: unreachable code", "<script>:13:5: unreachable code"]);
+    let chunks: Vec<&str> = memory.chunks().iter().map(|chunk| chunk.name()).collect();
+    assert_eq!(chunks, ["script", "sign", "inner", "helper"]);
+    assert_eq!(run(source), "-1\n1\n");
+}
+
+#[test]
+fn dropped_code_is_still_checked_for_errors() {
+    let source = "function f() {\n    return 1;\n    print ;\n}";
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    let errors = compiler.compile().unwrap_err();
+    assert_eq!(errors[0].to_string(), "<script>:3:11: expect value after 'print'");
+
+    let (_, warnings) = compile("function f(x) {\n    if (x) return 1;\n    return 2;\n}\nwhile (false) {}");
+    assert!(warnings.is_empty(), "{:?}", warnings);
+}
+
+#[test]
+fn a_hoisted_function_in_a_dropped_branch_stays_undefined() {
+    let source = "if (false) function f() { print \"dead\"; }\nfunction g() { print \"g\"; }\ng();\nprint f;";
+    assert_eq!(run(source), "g\nnil\n");
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    let error = interpreter.run("if (false) function f() { print \"dead\"; }\nfunction g() {}\nf();").unwrap_err();
+    assert_eq!(error.to_string(), "Runtime error on line 3: cannot call nil");
+}
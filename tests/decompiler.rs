@@ -0,0 +1,130 @@
+use std::process::Command;
+
+use caurora::{
+    compiler::Compiler, decompiler::decompile, memoryslice::MemorySlice, scanner::Scanner,
+    Interpreter,
+};
+
+fn decompiled(source: &str) -> String {
+    let memory = Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap();
+    decompile(&memory)
+}
+
+fn output(source: &str) -> String {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(source).unwrap();
+    interpreter.take_output()
+}
+
+// Decompiles the script and checks the result compiles back to a program
+// that prints the same, and decompiles to itself.
+fn round_trip(source: &str) -> String {
+    let text = decompiled(source);
+    assert_eq!(output(&text), output(source), "decompiled as:\n{}", text);
+    assert_eq!(decompiled(&text), text);
+    text
+}
+
+#[test]
+fn branches_and_loops_keep_their_shape() {
+    let text = round_trip(
+        "var total = 0;
+        for (var i = 0; i < 5; i = i + 1) {
+            if (i == 2) {
+                total = total + 10;
+            } else {
+                total = total + i;
+            }
+        }
+        var n = 3;
+        while (n > 0) {
+            print n;
+            n = n - 1;
+        }
+        if (total >= 18 and n != 1) print \"done\";
+        print total;",
+    );
+    assert!(text.contains("for (; (local_0 < 5); local_0 = (local_0 + 1)) {\n"));
+    assert!(text.contains("    if (local_0 == 2) {\n"));
+    assert!(text.contains("    } else {\n"));
+    assert!(text.contains("while (n > 0) {\n"));
+    assert!(text.contains("if ((total >= 18) and (n != 1)) {\n    print \"done\";\n}\n"));
+}
+
+#[test]
+fn functions_and_closures_name_what_they_capture() {
+    let text = round_trip(
+        "function counter() {
+            var count = 0;
+            function next() {
+                count = count + 1;
+                return count;
+            }
+            return next;
+        }
+        function adder(n) {
+            function add(x) {
+                return x + n;
+            }
+            return add;
+        }
+        var next = counter();
+        next();
+        print next();
+        print adder(5)(10);",
+    );
+    assert!(text.contains("function counter() {\n    var local_1 = 0;\n    function next() {\n"));
+    assert!(text.contains("        local_1 = (local_1 + 1);\n"));
+    assert!(text.contains("    return next;\n"));
+    assert!(text.contains("function adder(local_1) {\n    function add(local_1_1) {\n"));
+    assert!(text.contains("        return (local_1_1 + local_1);\n"));
+}
+
+#[test]
+fn classes_switches_and_arrays_round_trip() {
+    let text = round_trip(
+        "class Shape {
+            init(name) { this.name = name; }
+            describe() { return this.name; }
+        }
+        class Square < Shape {
+            describe() { return \"square \" + super.describe(); }
+        }
+        var items = [Square(\"a\"), 2, \"three\"];
+        switch (len(items)) {
+            case 2: print \"two\";
+            case 3: print items[0].describe();
+            default: print \"many\";
+        }
+        items[1] = items[1] * 4;
+        print items[1];",
+    );
+    assert!(text.contains("class Square < Shape {\n    describe() {\n"));
+    assert!(text.contains("switch (len(items)) {\n    case 2:\n"));
+    assert!(text.contains("    default:\n"));
+    assert!(text.contains("items[1] = (items[1] * 4);\n"));
+}
+
+#[test]
+fn saved_bytecode_decompiles_from_the_command_line() {
+    let saved = std::env::temp_dir().join(format!("aurora-decompile-{}.aurb", std::process::id()));
+    let saved = saved.to_str().unwrap();
+    let bin = env!("CARGO_BIN_EXE_caurora");
+    let compiled = Command::new(bin)
+        .args(["compile", "examples/test11.aur", "-o", saved])
+        .output()
+        .unwrap();
+    assert!(compiled.status.success());
+    let from_bytecode = Command::new(bin).args(["--decompile", saved]).output().unwrap();
+    let from_source = Command::new(bin)
+        .args(["--decompile", "examples/test11.aur"])
+        .output()
+        .unwrap();
+    std::fs::remove_file(saved).unwrap();
+    assert!(from_bytecode.status.success());
+    let text = String::from_utf8(from_bytecode.stdout).unwrap();
+    assert_eq!(text, String::from_utf8(from_source.stdout).unwrap());
+    assert!(text.contains("function fib(local_1) {\n"));
+}
@@ -0,0 +1,37 @@
+use std::{fs, process::Command};
+
+// Runs the binary with `--disassemble` and checks the listing against the
+// `.txt` golden file next to the script.
+fn disassemble(script: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_caurora"))
+        .args(["--disassemble", script])
+        .output()
+        .expect("could not run caurora");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// Release builds leave out the stack checks, so they have a listing of
+// their own.
+#[test]
+fn listing_matches_golden_file() {
+    let golden = if cfg!(debug_assertions) {
+        "tests/golden/disassemble.txt"
+    } else {
+        "tests/golden/disassemble.release.txt"
+    };
+    let expected = fs::read_to_string(golden).unwrap();
+    assert_eq!(disassemble("tests/golden/disassemble.aur"), expected);
+}
+
+#[test]
+fn disassembling_does_not_run_the_script() {
+    // Running it would write "loading..." before anything else.
+    let listing = disassemble("examples/test97.aur");
+    let mut chunks = listing.split("\n== ");
+    assert!(chunks.next().unwrap().ends_with("Eof\n"));
+    for chunk in chunks {
+        let (_, code) = chunk.split_once(" ==\n").unwrap();
+        assert!(code.lines().all(|line| line[..4].bytes().all(|b| b.is_ascii_digit())));
+    }
+}
@@ -0,0 +1,139 @@
+use std::{collections::BTreeMap, iter::Peekable, process::Command, str::Chars};
+
+use caurora::{
+    values::{Object, Value},
+    Interpreter,
+};
+
+// Just enough of JSON to read back what `--dump-globals` writes.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+fn parse(text: &str) -> Json {
+    let mut chars = text.trim_end().chars().peekable();
+    let json = parse_value(&mut chars);
+    assert_eq!(chars.next(), None, "trailing text in {}", text);
+    json
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Json {
+    match chars.next().expect("unexpected end of JSON") {
+        'n' => word(chars, "ull", Json::Null),
+        't' => word(chars, "rue", Json::Bool(true)),
+        'f' => word(chars, "alse", Json::Bool(false)),
+        '"' => Json::String(parse_string(chars)),
+        '[' => {
+            let mut elements = Vec::new();
+            while chars.peek() != Some(&']') {
+                elements.push(parse_value(chars));
+                if chars.peek() == Some(&',') {
+                    chars.next();
+                }
+            }
+            chars.next();
+            Json::Array(elements)
+        }
+        '{' => {
+            let mut members = BTreeMap::new();
+            while chars.next() == Some('"') {
+                let key = parse_string(chars);
+                assert_eq!(chars.next(), Some(':'));
+                members.insert(key, parse_value(chars));
+                if chars.peek() == Some(&',') {
+                    chars.next();
+                }
+            }
+            Json::Object(members)
+        }
+        first => {
+            let mut number = first.to_string();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                number.push(c);
+            }
+            Json::Number(number.parse().unwrap())
+        }
+    }
+}
+
+fn word(chars: &mut Peekable<Chars>, rest: &str, json: Json) -> Json {
+    let read: String = chars.take(rest.len()).collect();
+    assert_eq!(read, rest);
+    json
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> String {
+    let mut s = String::new();
+    loop {
+        match chars.next().expect("unterminated string") {
+            '"' => return s,
+            '\\' => match chars.next().unwrap() {
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'u' => {
+                    let code: String = chars.take(4).collect();
+                    s.push(char::from_u32(u32::from_str_radix(&code, 16).unwrap()).unwrap());
+                }
+                c => s.push(c),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+// The JSON the dump should hold for a global the library's interpreter ends
+// up with.
+fn expected(value: &Value) -> Json {
+    match value {
+        Value::Number(x) => Json::Number(*x),
+        Value::Nil => Json::Null,
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Object(Object::String(s)) => Json::String(s.to_string()),
+        Value::Object(Object::Array(elements)) => Json::Array(elements.borrow().iter().map(expected).collect()),
+        Value::Object(Object::Function(function)) => tagged("$function", &function.name),
+        Value::Object(Object::NativeFunction(native)) => tagged("$native", &native.name),
+        value => panic!("no JSON expected for {}", value),
+    }
+}
+
+fn tagged(tag: &str, name: &str) -> Json {
+    Json::Object(BTreeMap::from([(tag.to_owned(), Json::String(name.to_owned()))]))
+}
+
+#[test]
+fn the_dump_reads_back_as_the_globals_the_script_defined() {
+    let output = Command::new(env!("CARGO_BIN_EXE_caurora"))
+        .args(["--dump-globals", "examples/test24.aur"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "side output\n");
+    let Json::Object(globals) = parse(&String::from_utf8(output.stdout).unwrap()) else {
+        panic!("the dump is not a JSON object");
+    };
+
+    assert_eq!(globals["count"], Json::Number(3.0));
+    assert_eq!(globals["name"], Json::String("back\\slash".to_owned()));
+    assert_eq!(
+        globals["flags"],
+        Json::Array(vec![Json::String("a".to_owned()), Json::String("b".to_owned())])
+    );
+    assert_eq!(globals["nothing"], Json::Null);
+    assert_eq!(globals["ratio"], Json::Number(0.25));
+    assert_eq!(globals["add"], tagged("$function", "add"));
+    assert_eq!(globals["root"], tagged("$native", "math.sqrt"));
+    assert!(!globals.contains_key("sqrt"));
+
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(&std::fs::read_to_string("examples/test24.aur").unwrap()).unwrap();
+    for (name, json) in &globals {
+        assert_eq!(json, &expected(interpreter.global(name).unwrap()), "{}", name);
+    }
+}
@@ -0,0 +1,35 @@
+use std::{
+    fs,
+    process::{Command, Stdio},
+};
+
+// Every test block in the examples has to pass under `--test`.
+#[test]
+fn example_test_blocks_pass() {
+    let mut examples: Vec<_> = fs::read_dir("examples")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "aur"))
+        .filter(|path| {
+            let source = fs::read_to_string(path).unwrap_or_default();
+            source.lines().any(|line| line.trim_start().starts_with("test \""))
+        })
+        .collect();
+    examples.sort();
+    assert!(!examples.is_empty());
+    for example in examples {
+        let output = Command::new(env!("CARGO_BIN_EXE_caurora"))
+            .arg("--test")
+            .arg(&example)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}:\n{}{}",
+            example.display(),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    scanner::Scanner,
+    values::Value,
+    CauroraError, Interpreter,
+};
+
+fn compile(source: &str, fold: bool) -> MemorySlice {
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    compiler.set_fold(fold);
+    compiler.compile().unwrap()
+}
+
+fn run(source: &str, fold: bool) -> Result<String, CauroraError> {
+    let mut interpreter = Interpreter::builder().capture_output().fold(fold).build();
+    interpreter.run(source)?;
+    Ok(interpreter.take_output())
+}
+
+// The opcodes of the program, without the stack checks only debug builds
+// emit.
+fn opcodes(memory: &MemorySlice) -> Vec<OpCode> {
+    memory
+        .instructions()
+        .map(|instruction| instruction.opcode)
+        .filter(|opcode| *opcode != OpCode::AssertStack)
+        .collect()
+}
+
+#[test]
+fn literal_arithmetic_becomes_one_constant() {
+    let memory = compile("print 2 * 3 + 4;", true);
+    assert_eq!(
+        opcodes(&memory),
+        [OpCode::Constant, OpCode::Print, OpCode::Eof]
+    );
+    let load = memory.instructions().next().unwrap();
+    assert_eq!(memory.get_constant(load.operand.unwrap()), Some(Value::Number(10.0)));
+    assert!(memory.disassemble().starts_with("0000    1 Constant            4 10\n"));
+}
+
+// Every line prints the same whether its operators run at compile time or in
+// the VM.
+#[test]
+fn folded_programs_print_what_unfolded_ones_do() {
+    let source = "
+print 60 * 60 * 24;
+print -5 + 3;
+print !true;
+print !nil;
+print \"a\" + \"b\";
+print \"n = \" + 1.5;
+print 1 / 3;
+print 0.1 + 0.2;
+print 2 ** 0.5;
+print -2 ** 2;
+print 2 ** 3 ** 2;
+print -0 * 1;
+print 1e308 * 10;
+print 10 - 4 - 3;
+print 1 == 1.0;
+print \"a\" != \"b\";
+print nil == false;
+print 3 >= 3;
+print 2 <= 1;
+print \"apple\" < \"banana\";
+print (1 + 2) * (3 + 4);
+";
+    let folded = compile(source, true);
+    let unfolded = compile(source, false);
+    assert!(folded.get_memory_size() < unfolded.get_memory_size());
+    for arithmetic in [OpCode::Add, OpCode::Multiply, OpCode::Power, OpCode::Not, OpCode::Negate] {
+        assert!(!opcodes(&folded).contains(&arithmetic), "{:?} left in", arithmetic);
+    }
+    assert_eq!(run(source, true).unwrap(), run(source, false).unwrap());
+}
+
+#[test]
+fn failing_operations_are_left_for_the_vm() {
+    for source in ["print 1 / 0;", "print -\"a\";", "print \"a\" - 1;", "print nil < 1;"] {
+        let memory = compile(source, true);
+        assert_eq!(opcodes(&memory), opcodes(&compile(source, false)), "{}", source);
+        assert!(matches!(run(source, true), Err(CauroraError::Runtime(_))), "{}", source);
+    }
+}
+
+#[test]
+fn folding_stops_at_variables_calls_and_jumps() {
+    let source = "var x = 2;\nprint x * 3 + 4;\nprint len(\"ab\") + 1;\nprint false or 1 + 2;\nprint (true and 3) + 1;";
+    let memory = compile(source, true);
+    let counts = memory.opcode_counts();
+    let count = |opcode| counts.iter().find(|(op, _)| *op == opcode).map_or(0, |(_, n)| *n);
+    assert_eq!(count(OpCode::Multiply), 1);
+    assert_eq!(count(OpCode::Add), 3);
+    assert_eq!(count(OpCode::JmpTrue), 1);
+    assert_eq!(count(OpCode::JmpFalse), 1);
+    assert_eq!(run(source, true).unwrap(), "10\n3\n3\n4\n");
+}
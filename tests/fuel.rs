@@ -0,0 +1,55 @@
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    scanner::Scanner,
+    values::Value,
+    virtualmachine::{InterpretResult, VM},
+    CauroraError, Interpreter,
+};
+
+fn compile(source: &str) -> MemorySlice {
+    Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap()
+}
+
+#[test]
+fn an_endless_loop_stops_when_the_fuel_runs_out() {
+    let memory = compile("var i = 0;\nwhile (true) {\n    i = i + 1;\n}");
+    let mut vm = VM::builder().memory(&memory).fuel(1_000).stats(true).build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOutOfFuel);
+    assert_eq!(vm.fuel(), Some(0));
+    assert_eq!(vm.stats().unwrap().instructions, 1_000);
+    assert!(vm.error().is_none());
+    let Some(Value::Number(counted)) = vm.globals().get("i").cloned() else {
+        panic!("i is not defined");
+    };
+    assert!(counted > 0.0);
+
+    // More fuel picks up where it stopped.
+    vm.set_fuel(1_000);
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOutOfFuel);
+    assert_eq!(vm.stats().unwrap().instructions, 2_000);
+    assert!(matches!(vm.globals().get("i"), Some(Value::Number(i)) if *i > counted));
+}
+
+#[test]
+fn a_script_within_its_fuel_runs_as_usual() {
+    let memory = compile("function sum(n) {\n    var total = 0;\n    for (var i = 1; i <= n; i = i + 1) total = total + i;\n    return total;\n}\nvar total = sum(100);");
+    let mut vm = VM::builder().memory(&memory).fuel(100_000).build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    assert_eq!(vm.globals().get("total"), Some(&Value::Number(5050.0)));
+    assert!(vm.fuel().unwrap() > 0);
+
+    let mut vm = VM::new(&memory);
+    assert_eq!(vm.fuel(), None);
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+}
+
+#[test]
+fn each_interpreter_run_gets_the_same_fuel() {
+    let mut interpreter = Interpreter::builder().capture_output().fuel(10_000).build();
+    assert!(matches!(interpreter.run("var spins = 0;\nwhile (true) spins = spins + 1;"), Err(CauroraError::OutOfFuel)));
+    interpreter.run("print spins > 0;").unwrap();
+    assert_eq!(interpreter.take_output(), "true\n");
+}
@@ -0,0 +1,96 @@
+use std::{collections::HashMap, io};
+
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    scanner::Scanner,
+    values::{Object, Value},
+    virtualmachine::{InterpretResult, VM},
+    CauroraError, Interpreter,
+};
+
+fn compile(source: &str) -> MemorySlice {
+    Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap()
+}
+
+fn run(source: &str) -> Result<String, CauroraError> {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(source)?;
+    Ok(interpreter.take_output())
+}
+
+#[test]
+fn globals_are_read_and_written_by_slot() {
+    let memory = compile("var total = 1;\nvar step = 2;\ntotal = total + step;\nprint total;");
+    assert_eq!(memory.global_names(), ["total".into(), "step".into()]);
+    let slots: Vec<(OpCode, u32)> = memory
+        .instructions()
+        .filter(|instruction| instruction.opcode.takes_global())
+        .map(|instruction| (instruction.opcode, instruction.operand.unwrap()))
+        .collect();
+    assert_eq!(
+        slots,
+        [
+            (OpCode::DefineGlobalSlot, 0),
+            (OpCode::DefineGlobalSlot, 1),
+            (OpCode::GetGlobalSlot, 0),
+            (OpCode::GetGlobalSlot, 1),
+            (OpCode::SetGlobalSlot, 0),
+            (OpCode::GetGlobalSlot, 0),
+        ]
+    );
+    let names = memory
+        .constants()
+        .iter()
+        .filter(|constant| matches!(constant, Value::Object(Object::String(_))))
+        .count();
+    assert_eq!(names, 0);
+}
+
+#[test]
+fn a_global_is_undefined_until_its_definition_runs() {
+    let source = "function show() { print later; }\nshow();\nvar later = 1;";
+    match run(source) {
+        Err(CauroraError::Runtime(error)) => {
+            assert_eq!(error.message, "undefined variable 'later'");
+            assert_eq!(error.line, 1);
+        }
+        result => panic!("expected a runtime error, got {:?}", result),
+    }
+    let source = "function show() { print later; }\nvar later = 1;\nshow();\nlater = 2;\nshow();";
+    assert_eq!(run(source).unwrap(), "1\n2\n");
+}
+
+#[test]
+fn slots_carry_over_between_interpreter_runs() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run("var first = 1;").unwrap();
+    interpreter.run("var second = first + 1; first = 10;").unwrap();
+    interpreter.run("print first + second; print len(\"abc\");").unwrap();
+    assert_eq!(interpreter.take_output(), "12\n3\n");
+    assert_eq!(interpreter.global("second"), Some(&Value::Number(2.0)));
+}
+
+#[test]
+fn globals_given_to_a_vm_sit_alongside_the_builtins() {
+    let memory = compile("print len(\"abc\") + answer;");
+    let globals = HashMap::from([("answer".into(), Value::Number(39.0))]);
+    let mut vm = VM::builder()
+        .memory(&memory)
+        .output(Box::new(io::sink()))
+        .globals(globals.clone())
+        .build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    assert!(vm.globals().contains_key("len"));
+
+    let mut vm = VM::builder()
+        .memory(&memory)
+        .output(Box::new(io::sink()))
+        .carry_globals(globals)
+        .build();
+    assert!(!vm.globals().contains_key("len"));
+    assert_eq!(vm.interpret(), InterpretResult::InterpretRuntimeError);
+}
@@ -0,0 +1,59 @@
+use caurora::{compiler::Compiler, memoryslice::MemorySlice, scanner::Scanner, Interpreter};
+
+fn output(source: &str) -> String {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(source).unwrap();
+    interpreter.take_output()
+}
+
+fn errors(source: &str) -> Vec<String> {
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    match compiler.compile() {
+        Ok(_) => panic!("expected {:?} not to compile", source),
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+#[test]
+fn globals_step_before_or_after_they_are_read() {
+    assert_eq!(
+        output("var n = 5;\nprint n++, n;\nprint ++n, n;\nprint n--, n;\nprint --n, n;"),
+        "5 6\n7 7\n7 6\n5 5\n"
+    );
+}
+
+#[test]
+fn locals_step_before_or_after_they_are_read() {
+    assert_eq!(
+        output("{\n    var n = 5;\n    print n++, n;\n    print ++n, n;\n    print n--, n;\n    print --n, n;\n}"),
+        "5 6\n7 7\n7 6\n5 5\n"
+    );
+    assert_eq!(output("for (var i = 0; i < 3; i++) print i;"), "0\n1\n2\n");
+}
+
+#[test]
+fn upvalues_step_in_the_closure_and_outside_it() {
+    assert_eq!(
+        output(
+            "function counter() {
+                var n = 0;
+                function next() { return n++; }
+                function back() { return --n; }
+                return [next, back];
+            }
+            var c = counter();
+            print c[0](), c[0](), c[0](), c[1](), c[0]();"
+        ),
+        "0 1 2 2 2\n"
+    );
+}
+
+#[test]
+fn only_variables_can_be_stepped() {
+    assert_eq!(errors("5++;"), ["<script>:1:2: '++' can only be applied to a variable"]);
+    assert_eq!(errors("--(n);"), ["<script>:1:1: '--' can only be applied to a variable"]);
+    assert_eq!(
+        errors("var a = [1];\na[0]++;"),
+        ["<script>:2:5: '++' can only be applied to a variable"]
+    );
+}
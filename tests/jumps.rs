@@ -0,0 +1,62 @@
+use caurora::{
+    compiler::Compiler, memoryslice::MemorySlice, opcodes::OpCode, scanner::Scanner, Interpreter,
+};
+
+// Enough statements that their code is longer than a u16 can count.
+fn long_body(statements: usize) -> String {
+    "    count = count + 1;\n".repeat(statements)
+}
+
+fn run(source: &str) -> String {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(source).unwrap();
+    interpreter.take_output()
+}
+
+fn longest_jump(source: &str) -> usize {
+    let memory = Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap();
+    memory
+        .instructions()
+        .filter(|i| matches!(i.opcode, OpCode::Jmp | OpCode::JmpFalse | OpCode::Loop))
+        .map(|i| i.operand.unwrap() as usize)
+        .max()
+        .unwrap()
+}
+
+#[test]
+fn an_if_body_longer_than_a_u16_is_jumped_over() {
+    let source = format!(
+        "var count = 0;\nvar run = false;\nif (run) {{\n{}}} else {{\n    print \"skipped\";\n}}\nprint count;",
+        long_body(12_000)
+    );
+    assert!(longest_jump(&source) > u16::MAX as usize);
+    assert_eq!(run(&source), "skipped\n0\n");
+}
+
+#[test]
+fn a_loop_body_longer_than_a_u16_loops_back() {
+    let source = format!(
+        "var count = 0;\nvar i = 0;\nwhile (i < 3) {{\n{}    i = i + 1;\n}}\nprint count;",
+        long_body(12_000)
+    );
+    assert!(longest_jump(&source) > u16::MAX as usize);
+    assert_eq!(run(&source), "36000\n");
+}
+
+// A body is compiled into a chunk of its own, so there is nothing to jump
+// over however long it is.
+#[test]
+fn a_function_longer_than_a_u16_is_not_jumped_over() {
+    let source = format!(
+        "var count = 0;\nfunction bump() {{\n{}}}\nprint count;\nbump();\nprint count;",
+        long_body(12_000)
+    );
+    let memory = Compiler::new(&source, MemorySlice::new(), Scanner::new(source.as_str()))
+        .compile()
+        .unwrap();
+    assert!(memory.chunks()[1].len() > u16::MAX as usize);
+    assert!(memory.chunks()[0].instructions().all(|i| i.opcode != OpCode::Jmp));
+    assert_eq!(run(&source), "0\n12000\n");
+}
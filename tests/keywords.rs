@@ -0,0 +1,94 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use caurora::{scanner::Scanner, token::TokenType};
+
+// Counts the allocations made on the current thread, so a test can check a
+// stretch of code makes none.
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn token_types(source: &str) -> Vec<TokenType> {
+    let mut scanner = Scanner::new(source);
+    let mut types = Vec::new();
+    loop {
+        match scanner.scan_token().tokentype {
+            TokenType::Eof => return types,
+            TokenType::WhiteSpace => (),
+            tokentype => types.push(tokentype),
+        }
+    }
+}
+
+#[test]
+fn every_keyword_has_its_token_type() {
+    let source = "and assert case class const default else false for function if import include \
+                  nil or print return super switch test this true var while fun functions";
+    assert_eq!(
+        token_types(source),
+        [
+            TokenType::And,
+            TokenType::Assert,
+            TokenType::Case,
+            TokenType::Class,
+            TokenType::Const,
+            TokenType::Default,
+            TokenType::Else,
+            TokenType::False,
+            TokenType::For,
+            TokenType::Fun,
+            TokenType::If,
+            TokenType::Import,
+            TokenType::Include,
+            TokenType::Nil,
+            TokenType::Or,
+            TokenType::Print,
+            TokenType::Return,
+            TokenType::Super,
+            TokenType::Switch,
+            TokenType::Test,
+            TokenType::This,
+            TokenType::True,
+            TokenType::Var,
+            TokenType::While,
+            TokenType::Identifier,
+            TokenType::Identifier,
+        ]
+    );
+}
+
+#[test]
+fn scanning_keywords_and_identifiers_allocates_nothing() {
+    let line = "function f(x) { if (x and true) { return nil; } var y = x; while (y) print y; }\n";
+    let source = line.repeat(1_000);
+    let before = ALLOCATIONS.with(Cell::get);
+    // Taking its own handle on the text is the scanner's one allocation.
+    let mut scanner = Scanner::new(source.as_str());
+    assert_eq!(ALLOCATIONS.with(Cell::get), before + 1);
+    let before = ALLOCATIONS.with(Cell::get);
+    let mut tokens = 0;
+    while scanner.scan_token().tokentype != TokenType::Eof {
+        tokens += 1;
+    }
+    assert_eq!(ALLOCATIONS.with(Cell::get), before);
+    assert!(tokens > 30_000);
+}
@@ -0,0 +1,137 @@
+use caurora::{run_source, values::Value, CauroraError, Interpreter};
+
+#[test]
+fn scripts_run_and_report_errors() {
+    assert_eq!(run_source("var x = 1 + 2;"), Ok(()));
+    assert_eq!(run_source("exit(0);"), Ok(()));
+    assert_eq!(run_source("exit(4);"), Err(CauroraError::Exit(4)));
+    match run_source("print missing;") {
+        Err(CauroraError::Runtime(error)) => {
+            assert_eq!(error.message, "undefined variable 'missing'");
+            assert_eq!(error.line, 1);
+        }
+        result => panic!("expected a runtime error, got {:?}", result),
+    }
+    match run_source("print 1,;") {
+        Err(CauroraError::Compile(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].message, "expect value after ','");
+        }
+        result => panic!("expected a compile error, got {:?}", result),
+    }
+}
+
+#[test]
+fn globals_go_in_and_output_comes_out() {
+    let mut interpreter = Interpreter::builder()
+        .global("width", Value::Number(3.0))
+        .capture_output()
+        .build();
+    interpreter.run("var area = width * 4; print area;").unwrap();
+    assert_eq!(interpreter.take_output(), "12\n");
+    assert_eq!(interpreter.global("area"), Some(&Value::Number(12.0)));
+    assert_eq!(interpreter.take_output(), "");
+}
+
+#[test]
+fn later_runs_see_earlier_definitions() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter
+        .run("function twice(x) { return 2 * x; } var base = 5;")
+        .unwrap();
+    assert!(interpreter.run("print twice(;").is_err());
+    interpreter.run("print twice(base), len(\"abc\");").unwrap();
+    assert_eq!(interpreter.take_output(), "10 3\n");
+}
+
+#[test]
+fn sources_only_need_to_live_through_the_call() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    for round in 0..50 {
+        let definition = format!("var value_{} = {};", round, round * 2);
+        interpreter.run(&definition).unwrap();
+        drop(definition);
+        let report = format!("print value_{} + 1;", round);
+        interpreter.run(&report).unwrap();
+    }
+    let output = interpreter.take_output();
+    assert_eq!(output.lines().count(), 50);
+    assert_eq!(output.lines().last(), Some("99"));
+}
+
+#[test]
+fn compiling_and_executing_can_be_separate_steps() {
+    let mut interpreter = Interpreter::builder().capture_output().stats(true).build();
+    let (memory, entry) = interpreter
+        .compile("function f() { return 1; print 2; }\nprint f();")
+        .unwrap();
+    assert_eq!(interpreter.warnings().len(), 1);
+    assert_eq!(interpreter.compile_stats().functions, 1);
+    assert_eq!(interpreter.take_output(), "");
+    interpreter.execute(memory, entry).unwrap();
+    assert_eq!(interpreter.take_output(), "1\n");
+    assert!(interpreter.execution_stats().unwrap().instructions > 0);
+}
+
+#[test]
+fn test_blocks_run_on_the_globals_the_script_left() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter
+        .run("var n = 2;\ntest \"passes\" { assert_eq(n, 2); n = 3; }\ntest \"fails\" { assert_eq(n, 3); }")
+        .unwrap();
+    let mut results = Vec::new();
+    interpreter.run_tests(|name, result| results.push((name.to_owned(), result.map_err(|e| e.to_string()))));
+    assert_eq!(
+        results,
+        [
+            ("passes".to_owned(), Ok(())),
+            (
+                "fails".to_owned(),
+                Err("Runtime error on line 3: assert_eq: values differ\n    left:  2\n    right: 3".to_owned())
+            ),
+        ]
+    );
+}
+
+#[test]
+fn the_clock_keeps_counting_across_runs() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter
+        .run("var i = 0; while (i < 20000) i = i + 1; var first = clock();")
+        .unwrap();
+    interpreter.run("print clock() >= first;").unwrap();
+    assert_eq!(interpreter.take_output(), "true\n");
+}
+
+#[test]
+fn a_seed_holds_for_later_runs() {
+    let mut first = Interpreter::builder().capture_output().build();
+    first.run("seed(1);").unwrap();
+    first.run("print rand();").unwrap();
+    first.run("print rand();").unwrap();
+    let mut second = Interpreter::builder().capture_output().build();
+    second.run("seed(1); print rand(); print rand();").unwrap();
+    let output = first.take_output();
+    assert_eq!(output, second.take_output());
+    let draws: Vec<&str> = output.lines().collect();
+    assert_ne!(draws[0], draws[1]);
+}
+
+#[test]
+fn hosts_can_leave_out_the_file_builtins() {
+    let mut interpreter = Interpreter::builder().files(false).capture_output().build();
+    assert_eq!(interpreter.global("read_file"), None);
+    assert!(interpreter.global("len").is_some());
+    assert!(interpreter.run("read_file(\"x\");").is_err());
+
+    let interpreter = Interpreter::builder().builtins(false).build();
+    assert_eq!(interpreter.global("len"), None);
+}
+
+#[test]
+fn last_error_holds_across_runs() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run("read_file(\"/nonexistent/x\");").unwrap();
+    interpreter.run("print last_error() != nil;").unwrap();
+    assert_eq!(interpreter.take_output(), "true\n");
+}
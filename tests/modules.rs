@@ -0,0 +1,124 @@
+use std::{
+    cell::RefCell,
+    io::{self, Cursor, Write},
+    rc::Rc,
+};
+
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    scanner::Scanner,
+    values::{Object, Value},
+    virtualmachine::{InterpretResult, VM},
+    Interpreter,
+};
+
+fn output(source: &str) -> String {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(source).unwrap();
+    interpreter.take_output()
+}
+
+fn compile(source: &str) -> MemorySlice {
+    Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap()
+}
+
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+fn hello(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Object(Object::String("hello from the host".into())))
+}
+
+#[test]
+fn math_holds_the_numeric_builtins() {
+    assert_eq!(
+        output(
+            "print math.sqrt(9), math.abs(-2), math.floor(1.5), math.ceil(1.5), math.round(2.5);
+            print math.min(3, 1, 2), math.max([3, 1, 2]), math.clamp(12, 0, 10);
+            math.seed(7);
+            var r = math.rand_range(2, 4);
+            print r >= 2 and r < 4, math.rand() < 1, math.pi > 3.14;"
+        ),
+        "3 2 1 2 3\n1 3 10\ntrue true true\n"
+    );
+}
+
+#[test]
+fn str_holds_the_string_builtins() {
+    assert_eq!(
+        output(
+            "print str.upper(\"ab\"), str.lower(\"AB\"), str.substr(\"aurora\", 1, 3);
+            print str.find(\"aurora\", \"ro\"), str.parse_int(\"ff\", 16);
+            print str.join(str.split(\"a,b,c\", \",\"), \"-\"), str(12) + \"!\";"
+        ),
+        "AB ab uro\n2 255\na-b-c 12!\n"
+    );
+}
+
+#[test]
+fn io_reads_and_writes_through_the_vm() {
+    let source = "io.write(\"name? \"); var name = io.input(); io.write(\"hi \" + name); print io.input();";
+    let memory = compile(source);
+    let output = Shared::default();
+    let mut vm = VM::builder()
+        .memory(&memory)
+        .output(Box::new(output.clone()))
+        .input(Box::new(Cursor::new("ada\n")))
+        .build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    assert_eq!(output.text(), "name? hi adanil\n");
+}
+
+#[test]
+fn io_has_the_file_builtins_only_when_files_are_allowed() {
+    let path = std::env::temp_dir().join(format!("aurora-io-{}.txt", std::process::id()));
+    let path = path.to_str().unwrap();
+    assert_eq!(
+        output(&format!(
+            "print io.write_file({0:?}, \"one\"), io.append_file({0:?}, \" two\");
+            print io.read_file({0:?}), io.last_error();",
+            path
+        )),
+        "true true\none two nil\n"
+    );
+    std::fs::remove_file(path).unwrap();
+
+    let memory = compile("print io.write; print io.read_file;");
+    let output = Shared::default();
+    let mut vm = VM::builder()
+        .memory(&memory)
+        .output(Box::new(output.clone()))
+        .files(false)
+        .build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretRuntimeError);
+    assert_eq!(output.text(), "<native io.write>\n");
+}
+
+#[test]
+fn hosts_can_add_their_own_module() {
+    let memory = compile("print app.hello(); print app.hello;");
+    let output = Shared::default();
+    let mut vm = VM::builder().memory(&memory).output(Box::new(output.clone())).build();
+    vm.register_module("app", &[("hello", 0, hello)]);
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    assert_eq!(output.text(), "hello from the host\n<native app.hello>\n");
+}
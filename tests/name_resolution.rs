@@ -0,0 +1,27 @@
+use caurora::Interpreter;
+
+// A function with `count` locals, each read back several times by name.
+fn many_locals(count: usize) -> String {
+    let mut source = String::from("function sum() {\n");
+    for i in 0..count {
+        source.push_str(&format!("    var local_{} = {};\n", i, i));
+    }
+    source.push_str("    var total = 0;\n");
+    for _ in 0..4 {
+        for i in 0..count {
+            source.push_str(&format!("    total = total + local_{};\n", i));
+        }
+    }
+    source.push_str("    return total;\n}\nprint sum();\n");
+    source
+}
+
+#[test]
+fn hundreds_of_locals_resolve_to_their_own_slots() {
+    let count = 400;
+    let source = many_locals(count);
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(&source).unwrap();
+    let expected = 4 * count * (count - 1) / 2;
+    assert_eq!(interpreter.take_output(), format!("{}\n", expected));
+}
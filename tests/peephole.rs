@@ -0,0 +1,140 @@
+use caurora::{
+    compiler::Compiler, memoryslice::MemorySlice, opcodes::OpCode, scanner::Scanner, Interpreter,
+};
+
+fn compile(source: &str, optimize: bool) -> MemorySlice {
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    compiler.set_optimize(optimize);
+    compiler.compile().unwrap()
+}
+
+fn run(source: &str, optimize: bool) -> String {
+    let mut interpreter = Interpreter::builder().capture_output().optimize(optimize).build();
+    interpreter.run(source).unwrap();
+    interpreter.take_output()
+}
+
+// The opcodes, less the stack checks debug builds add between statements.
+fn opcodes(memory: &MemorySlice) -> Vec<OpCode> {
+    memory
+        .instructions()
+        .map(|instruction| instruction.opcode)
+        .filter(|opcode| *opcode != OpCode::AssertStack)
+        .collect()
+}
+
+fn count(memory: &MemorySlice, opcode: OpCode) -> usize {
+    memory.instructions().filter(|instruction| instruction.opcode == opcode).count()
+}
+
+// Compiles the source both ways, checks both print the same, and gives back
+// the two programs.
+fn both(source: &str) -> (MemorySlice, MemorySlice) {
+    assert_eq!(run(source, true), run(source, false), "{}", source);
+    (compile(source, false), compile(source, true))
+}
+
+#[test]
+fn double_not_is_dropped_after_a_bool() {
+    let (before, after) = both("var a = 1;\nvar b = 2;\nprint !!(a < b);\nprint !(a != b);");
+    assert_eq!((count(&before, OpCode::Not), count(&after, OpCode::Not)), (4, 0));
+    assert!(opcodes(&after).windows(2).any(|pair| pair == [OpCode::Less, OpCode::Print]));
+
+    // `!!a` turns a number into a bool, so it stays.
+    let (before, after) = both("var a = 1;\nprint !!a;");
+    assert_eq!((count(&before, OpCode::Not), count(&after, OpCode::Not)), (2, 2));
+}
+
+#[test]
+fn double_negate_is_dropped_after_a_number() {
+    let (_, after) = both("var a = 3;\nvar b = 4;\nprint -(-(a * b));");
+    assert_eq!(count(&after, OpCode::Negate), 0);
+
+    // `-(-a)` must still fail when `a` is not a number.
+    let (_, after) = both("var a = 3;\nprint -(-a);");
+    assert_eq!(count(&after, OpCode::Negate), 2);
+}
+
+#[test]
+fn jumps_to_a_jump_go_straight_to_its_destination() {
+    let source = "
+function pick(a, b) {
+    if (a)
+        if (b) print 1;
+        else print 2;
+    else print 3;
+    print 4;
+}
+pick(true, false);
+";
+    let (before, after) = both(source);
+    let lands_on_jmp = |memory: &MemorySlice| {
+        memory.chunks().iter().any(|chunk| {
+            chunk.instructions().filter_map(|i| i.jump_target()).any(|target| {
+                chunk.instruction_at(target).is_some_and(|i| i.opcode == OpCode::Jmp)
+            })
+        })
+    };
+    assert!(lands_on_jmp(&before));
+    assert!(!lands_on_jmp(&after));
+    assert_eq!(run(source, true), "2\n4\n");
+}
+
+#[test]
+fn a_jump_that_cannot_be_taken_is_dropped() {
+    let source = "print true and false;\nprint false or true;";
+    let (_, after) = both(source);
+    assert_eq!(
+        opcodes(&after),
+        [OpCode::False, OpCode::Print, OpCode::True, OpCode::Print, OpCode::Eof]
+    );
+
+    let source = "
+function first_multiple(n) {
+    var i = 1;
+    while (true) {
+        if (i * 7 > n) { return i; }
+        i = i + 1;
+    }
+}
+print first_multiple(5);
+";
+    let (before, after) = both(source);
+    assert_eq!((count(&before, OpCode::JmpFalse), count(&after, OpCode::JmpFalse)), (2, 1));
+    assert!(after.get_memory_size() < before.get_memory_size());
+}
+
+// Functions, methods, closures and tests are optimized in their own chunks,
+// which keep their indices as the code in them shrinks.
+#[test]
+fn bodies_are_optimized_in_their_own_chunks() {
+    let source = "
+print true and true;
+class Counter {
+    init() { this.count = 0; }
+    bump() { this.count = this.count + 1; return this.count; }
+}
+function adder(n) {
+    function add(x) { return !!(x < n) or x + n; }
+    return add;
+}
+var counter = Counter();
+counter.bump();
+print counter.bump();
+print adder(10)(3);
+print adder(10)(30);
+test \"bumps\" { assert counter.bump() == 3; }
+";
+    let (before, after) = both(source);
+    assert!(after.get_memory_size() < before.get_memory_size());
+    assert_eq!(before.get_tests(), after.get_tests());
+    let sizes = |memory: &MemorySlice| memory.chunks().iter().map(|chunk| chunk.len()).collect::<Vec<_>>();
+    let (before, after) = (sizes(&before), sizes(&after));
+    assert!(before.iter().zip(&after).filter(|(before, after)| after < before).count() > 1);
+    assert_eq!(run(source, true), "true\n2\ntrue\n40\n");
+
+    let mut interpreter = Interpreter::builder().capture_output().optimize(true).build();
+    interpreter.run("print true and true;\nfunction twice(x) { return -(-(x * 2)); }").unwrap();
+    interpreter.run("print false or false;\nprint twice(21);").unwrap();
+    assert_eq!(interpreter.take_output(), "true\nfalse\n42\n");
+}
@@ -0,0 +1,37 @@
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    scanner::Scanner,
+    virtualmachine::{InterpretResult, VM},
+};
+
+fn compile(source: &str) -> MemorySlice {
+    Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap()
+}
+
+#[test]
+fn a_loop_is_counted_once_per_pass() {
+    let memory = compile("var i = 0;\nwhile (i < 250) {\n    i = i + 1;\n}");
+    let mut vm = VM::builder().memory(&memory).profile(true).build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    let profile = vm.profile().unwrap();
+    assert_eq!(profile.count(OpCode::Loop), 250);
+    assert_eq!(profile.count(OpCode::Add), 250);
+    assert_eq!(profile.count(OpCode::Less), 251);
+    assert_eq!(profile.count(OpCode::Eof), 1);
+    assert_eq!(profile.count(OpCode::Multiply), 0);
+
+    let rows = profile.rows();
+    assert!(rows.windows(2).all(|pair| pair[0].2 >= pair[1].2));
+    assert!(rows.iter().all(|(_, count, _)| *count > 0));
+}
+
+#[test]
+fn profiling_is_off_unless_asked_for() {
+    let memory = compile("print 1;");
+    let vm = VM::new(&memory);
+    assert!(vm.profile().is_none());
+}
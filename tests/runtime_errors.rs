@@ -0,0 +1,173 @@
+use caurora::{
+    compiler::Compiler,
+    memoryslice::{Chunk, MemorySlice},
+    opcodes::OpCode,
+    scanner::Scanner,
+    values::{Object, Value},
+    virtualmachine::{InterpretResult, VM},
+    CauroraError, Interpreter,
+};
+
+fn runtime_error(source: &str) -> (usize, String) {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    match interpreter.run(source) {
+        Err(CauroraError::Runtime(error)) => (error.line, error.message),
+        result => panic!("expected {:?} to fail at runtime, got {:?}", source, result),
+    }
+}
+
+#[test]
+fn each_kind_of_failure_has_its_own_message() {
+    let cases = [
+        ("var a = 1;\nprint missing;", 2, "undefined variable 'missing'"),
+        ("undeclared = 3;", 1, "undefined variable 'undeclared'"),
+        ("print 1 - \"a\";", 1, "cannot apply - to number and string"),
+        ("print -\"a\";", 1, "cannot negate string"),
+        ("var x = 3;\n\nx();", 3, "cannot call number"),
+        ("function f(a) {}\nf();", 2, "f: expected 1 arguments but got 0"),
+        ("var n = 4;\nprint n.size;", 2, "cannot read property 'size' of number"),
+        ("print [1, 2][2];", 1, "index 2 out of range for array of length 2"),
+        ("var n = [1, 2, 3];\nn.size = 3;", 2, "cannot set property 'size' on array"),
+        ("var B = \"b\";\nclass A < B {}", 2, "superclass must be a class, got string"),
+        ("print \"abc\"[0];", 1, "cannot index string"),
+        ("print [1][\"0\"];", 1, "array index must be an integer, got string"),
+        ("print [1][0.5];", 1, "array index must be an integer, got 0.5"),
+    ];
+    for (source, line, message) in cases {
+        assert_eq!(runtime_error(source), (line, message.to_owned()), "{:?}", source);
+    }
+}
+
+#[test]
+fn calls_with_the_wrong_arguments_name_the_function_and_counts() {
+    let cases = [
+        ("function f(a) {\n    return a;\n}\nf(1, 2);", 4, "f: expected 1 arguments but got 2"),
+        ("function g(a, b = 2) {}\n\ng(1, 2, 3);", 3, "g: expected 1 to 2 arguments but got 3"),
+        ("print sqrt(1, 2);", 1, "sqrt: expected 1 arguments but got 2"),
+        ("var s = \"f\";\ns(1);", 2, "cannot call string"),
+    ];
+    for (source, line, message) in cases {
+        assert_eq!(runtime_error(source), (line, message.to_owned()), "{:?}", source);
+    }
+}
+
+#[test]
+fn builtins_name_the_type_they_were_given() {
+    let cases = [
+        ("print sqrt(\"a\");", "sqrt: expected a number, got string"),
+        ("print upper(3);", "upper: expected a string, got number"),
+        ("print join(nil, \",\");", "join: expected an array, got nil"),
+        ("print max(1, [2]);", "max: argument 1: expected a number, got array"),
+        ("print len(true);", "len: expected an array or a string, got bool"),
+        ("print num(nil);", "num: expected a number or a string, got nil"),
+    ];
+    for (source, message) in cases {
+        assert_eq!(runtime_error(source), (1, message.to_owned()), "{:?}", source);
+    }
+}
+
+#[test]
+fn assert_eq_shows_values_as_print_does() {
+    let (_, message) = runtime_error("assert_eq(2 + 2, [4, \"four\"]);");
+    assert_eq!(message, "assert_eq: values differ\n    left:  4\n    right: [4, \"four\"]");
+}
+
+#[test]
+fn the_error_carries_the_calls_that_led_to_it() {
+    let mut interpreter = Interpreter::new();
+    let source = "function inner() {\n  return nil + 1;\n}\nfunction outer() {\n  return inner() + 1;\n}\nouter();";
+    let Err(CauroraError::Runtime(error)) = interpreter.run(source) else {
+        panic!("expected a runtime error");
+    };
+    assert_eq!(error.message, "cannot apply + to nil and number");
+    let trace: Vec<(&str, usize)> = error.trace.iter().map(|(name, line)| (name.as_str(), *line)).collect();
+    assert_eq!(trace, [("inner", 2), ("outer", 5), ("script", 7)]);
+}
+
+#[test]
+fn runtime_errors_count_lines_like_the_scanner() {
+    for ending in ["\n", "\r\n", "\r"] {
+        let source = ["var a = \"x", "y\";", "", "print a - 1;"].join(ending);
+        assert_eq!(runtime_error(&source), (4, "cannot apply - to string and number".to_owned()), "{:?}", ending);
+    }
+}
+
+#[test]
+fn unbounded_recursion_overflows_the_call_stack() {
+    let (line, message) = runtime_error("function down(n) {\n  return down(n + 1) + 1;\n}\ndown(0);");
+    assert_eq!((line, message.as_str()), (2, "stack overflow, max call depth 1024 exceeded"));
+}
+
+// Runs hand-written code, as a corrupt bytecode file could hold, and gives
+// back the error it stopped with.
+fn run_code(code: &[u16], constants: Vec<Value>) -> String {
+    let script = Chunk::new("script".to_owned(), code.to_vec(), Vec::new());
+    let memory = MemorySlice::from_parts(vec![script], constants, Vec::new(), Vec::new());
+    let mut vm = VM::new(&memory);
+    assert_eq!(vm.interpret(), InterpretResult::InterpretRuntimeError);
+    vm.error().unwrap().message.clone()
+}
+
+#[test]
+fn corrupt_programs_fail_without_panicking() {
+    let eof = OpCode::Eof.repr();
+    let nil = OpCode::Nil.repr();
+    assert_eq!(run_code(&[OpCode::Add.repr(), eof], Vec::new()), "stack underflow");
+    assert_eq!(run_code(&[nil, u16::MAX, eof], Vec::new()), "invalid opcode 65535 at 1");
+    assert_eq!(run_code(&[nil], Vec::new()), "instruction pointer 1 is past the end of the code");
+    assert_eq!(run_code(&[nil, OpCode::Return.repr()], Vec::new()), "return outside of a function");
+    assert_eq!(
+        run_code(&[OpCode::Loop.repr(), 5, 0, eof], Vec::new()),
+        "Loop jumps 5 back from 3, before the start"
+    );
+    assert_eq!(run_code(&[OpCode::Constant.repr(), 7, eof], Vec::new()), "missing constant 7");
+    assert_eq!(
+        run_code(&[nil, OpCode::DefineGlobalSlot.repr(), 0, eof], Vec::new()),
+        "DefineGlobalSlot: missing global slot 0"
+    );
+    let name = vec![Value::Object(Object::String("Point".into()))];
+    assert_eq!(
+        run_code(&[nil, nil, OpCode::Method.repr(), 0, eof], name),
+        "method 'Point' defined outside a class"
+    );
+}
+
+#[test]
+fn only_debug_builds_enforce_stack_checks() {
+    let code = [OpCode::Nil.repr(), OpCode::AssertStack.repr(), 0, OpCode::Eof.repr()];
+    let script = Chunk::new("script".to_owned(), code.to_vec(), Vec::new());
+    let memory = MemorySlice::from_parts(vec![script], Vec::new(), Vec::new(), Vec::new());
+    let mut vm = VM::new(&memory);
+    if cfg!(debug_assertions) {
+        assert_eq!(vm.interpret(), InterpretResult::InterpretRuntimeError);
+        assert_eq!(vm.error().unwrap().message, "stack discipline violated: expected height 0, found 1 (+1)");
+    } else {
+        assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    }
+}
+
+#[test]
+fn function_bodies_check_their_stack_too() {
+    let source = "function f(a) {\n    var b = a;\n    print b;\n}\nf(1);";
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    let memory = compiler.compile().unwrap();
+    let body = memory.chunk(1).unwrap();
+    let heights: Vec<Option<u32>> = body
+        .instructions()
+        .filter(|instruction| instruction.opcode == OpCode::AssertStack)
+        .map(|instruction| instruction.operand)
+        .collect();
+    // The callee, `a` and `b`; release builds emit no checks.
+    let expected: &[Option<u32>] = if cfg!(debug_assertions) { &[Some(3), Some(3)] } else { &[] };
+    assert_eq!(heights, expected);
+    let mut vm = VM::builder().memory(&memory).output(Box::new(std::io::sink())).build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+}
+
+#[test]
+fn a_failed_run_leaves_the_vm_usable() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    assert!(interpreter.run("var total = 2; print total * nil;").is_err());
+    interpreter.run("print total + 1;").unwrap();
+    assert_eq!(interpreter.take_output(), "3\n");
+}
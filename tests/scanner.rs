@@ -0,0 +1,58 @@
+use caurora::{scanner::Scanner, token::TokenType};
+
+// Every token of the source apart from whitespace, as its type and text.
+fn tokens(source: &str) -> Vec<(TokenType, String)> {
+    let mut scanner = Scanner::new(source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        match token.tokentype {
+            TokenType::Eof => return tokens,
+            TokenType::WhiteSpace | TokenType::NewLine => (),
+            tokentype => {
+                let text = source[token.start..token.start + token.length].to_owned();
+                tokens.push((tokentype, text));
+            }
+        }
+    }
+}
+
+#[test]
+fn multi_byte_text_keeps_token_offsets_on_char_boundaries() {
+    let source = "// ünïcödé comment ✓\nvar name = \"naïve ✓ 日本\"; /* ü */ print name;";
+    assert_eq!(
+        tokens(source),
+        [
+            (TokenType::Var, "var".to_owned()),
+            (TokenType::Identifier, "name".to_owned()),
+            (TokenType::Equal, "=".to_owned()),
+            (TokenType::String, "\"naïve ✓ 日本\"".to_owned()),
+            (TokenType::SemiColon, ";".to_owned()),
+            (TokenType::Print, "print".to_owned()),
+            (TokenType::Identifier, "name".to_owned()),
+            (TokenType::SemiColon, ";".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn columns_count_characters_from_the_start_of_the_line() {
+    let source = "var s = \"日本\";\tprint s;\n  s = /* ü */ 1;";
+    let mut scanner = Scanner::new(source);
+    let mut columns = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        match token.tokentype {
+            TokenType::Eof => {
+                columns.push((token.line, token.column));
+                break;
+            }
+            TokenType::WhiteSpace | TokenType::NewLine => (),
+            _ => columns.push((token.line, token.column)),
+        }
+    }
+    assert_eq!(
+        columns,
+        [(1, 1), (1, 5), (1, 7), (1, 9), (1, 13), (1, 15), (1, 21), (1, 22), (2, 3), (2, 5), (2, 15), (2, 16), (2, 17)]
+    );
+}
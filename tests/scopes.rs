@@ -0,0 +1,149 @@
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    scanner::Scanner,
+    virtualmachine::{InterpretResult, VM},
+    Interpreter,
+};
+
+fn compile(source: &str) -> MemorySlice {
+    Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap()
+}
+
+fn output(source: &str) -> String {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(source).unwrap();
+    interpreter.take_output()
+}
+
+// The scope-closing instructions, in order, with their operands.
+fn scope_exits(memory: &MemorySlice) -> Vec<(OpCode, Option<u32>)> {
+    memory
+        .instructions()
+        .filter(|i| matches!(i.opcode, OpCode::Pop | OpCode::PopN | OpCode::CloseUpvalue))
+        .map(|i| (i.opcode, i.operand))
+        .collect()
+}
+
+const NESTED: &str = "
+{
+    var a = 1;
+    var b = 2;
+    {
+        var c = 3;
+        var d = 4;
+        var e = 5;
+        {
+            var f = 6;
+            print a + b + c + d + e + f;
+        }
+        print a + e;
+    }
+    print a + b;
+}
+";
+
+#[test]
+fn leaving_a_block_pops_its_locals_at_once() {
+    assert_eq!(
+        scope_exits(&compile(NESTED)),
+        [
+            (OpCode::Pop, None),
+            (OpCode::PopN, Some(3)),
+            (OpCode::PopN, Some(2)),
+        ]
+    );
+    assert_eq!(output(NESTED), "21\n6\n3\n");
+}
+
+#[test]
+fn captured_locals_split_the_pops() {
+    let source = "
+function make() {
+    var kept = \"kept\";
+    var made;
+    {
+        var a = 1;
+        var b = 2;
+        var seen = \"seen\";
+        function show() { return kept + \" \" + seen; }
+        var c = 3;
+        var d = 4;
+        made = show;
+    }
+    return made;
+}
+print make()();
+";
+    let exits = scope_exits(&compile(source));
+    assert!(
+        exits.windows(3).any(|w| w
+            == [
+                (OpCode::PopN, Some(3)),
+                (OpCode::CloseUpvalue, None),
+                (OpCode::PopN, Some(2)),
+            ]),
+        "{:?}",
+        exits
+    );
+    assert_eq!(output(source), "kept seen\n");
+}
+
+#[test]
+fn loop_bodies_leave_the_stack_as_they_found_it() {
+    let source = "
+var total = 0;
+for (var i = 0; i < 100; i = i + 1) {
+    var a = i;
+    var b = a * 2;
+    {
+        var c = b + 1;
+        var d = c - a;
+        total = total + d;
+    }
+}
+print total;
+";
+    let memory = compile(source);
+    let mut vm = VM::builder().memory(&memory).output(Box::new(Vec::new())).build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    assert!(vm.stack().is_empty(), "{:?}", vm.stack());
+    assert_eq!(output(source), "5050\n");
+}
+
+fn errors(source: &str) -> Vec<String> {
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    match compiler.compile() {
+        Ok(_) => panic!("expected {:?} not to compile", source),
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+#[test]
+fn a_name_is_declared_once_per_scope() {
+    assert_eq!(
+        errors("{\n    var a = 1;\n    var a = 2;\n}"),
+        ["<script>:3:9: variable 'a' already declared in this scope"]
+    );
+    assert_eq!(
+        errors("function f(x, x) {\n    return x;\n}"),
+        ["<script>:1:15: variable 'x' already declared in this scope"]
+    );
+    assert_eq!(
+        errors("function f(x) {\n    var x = 2;\n}"),
+        ["<script>:2:9: variable 'x' already declared in this scope"]
+    );
+    assert_eq!(
+        errors("{\n    var f = 1;\n    function f() {}\n}"),
+        ["<script>:3:14: variable 'f' already declared in this scope"]
+    );
+}
+
+#[test]
+fn inner_scopes_may_still_shadow() {
+    let source = "var a = \"global\";\nvar a = \"again\";\n{\n    var a = 1;\n    {\n        var a = 2;\n        print a;\n    }\n    print a;\n}\nfunction f(a) {\n    {\n        var a = 3;\n        print a;\n    }\n    return a;\n}\nprint f(4);\nprint a;";
+    assert_eq!(output(source), "2\n1\n3\n4\nagain\n");
+}
@@ -0,0 +1,74 @@
+use std::{io, process::Command};
+
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    scanner::Scanner,
+    stats::CompileStats,
+    virtualmachine::{InterpretResult, VM},
+};
+
+const SOURCE: &str = "var a = 2;\nvar b = 2;\n{\n    var c = a;\n    {\n        print c + b;\n    }\n}\nfunction f() {}";
+
+fn compile(source: &str) -> (MemorySlice, CompileStats) {
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    let memory = compiler.compile().unwrap();
+    let stats = compiler.stats();
+    (memory, stats)
+}
+
+fn count(stats: &CompileStats, opcode: OpCode) -> usize {
+    stats.opcodes.iter().find(|(op, _)| *op == opcode).map_or(0, |(_, n)| *n)
+}
+
+#[test]
+fn compile_stats_count_what_was_emitted() {
+    let (memory, stats) = compile(SOURCE);
+    assert_eq!(stats.functions, 1);
+    assert_eq!(stats.max_scope_depth, 2);
+    assert_eq!(stats.deduplicated_constants, 1);
+    assert_eq!(stats.constants, memory.constants().len());
+    // `f` is bound once when it is hoisted and again where it is declared.
+    assert_eq!(count(&stats, OpCode::DefineGlobalSlot), 4);
+    assert_eq!(count(&stats, OpCode::Add), 1);
+    assert_eq!(count(&stats, OpCode::Multiply), 0);
+    assert_eq!(stats.instructions, stats.opcodes.iter().map(|(_, n)| n).sum::<usize>());
+}
+
+#[test]
+fn execution_stats_are_only_kept_when_asked_for() {
+    let (memory, _) = compile(SOURCE);
+    let mut vm = VM::builder().memory(&memory).output(Box::new(io::sink())).build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    assert!(vm.stats().is_none());
+
+    let mut vm = VM::builder()
+        .memory(&memory)
+        .output(Box::new(io::sink()))
+        .stats(true)
+        .build();
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+    let stats = vm.stats().unwrap();
+    assert_eq!(stats.instructions, memory.chunk(0).unwrap().instructions().count() as u64);
+    assert_eq!(stats.peak_stack_depth, 3);
+}
+
+#[test]
+fn reports_go_to_stderr_after_the_output() {
+    let bin = env!("CARGO_BIN_EXE_caurora");
+    let output = Command::new(bin).args(["--stats", "examples/test11.aur"]).output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5\n");
+    let report = String::from_utf8(output.stderr).unwrap();
+    assert!(report.starts_with("== Compile Stats ==\n"), "{}", report);
+    assert!(report.contains("\nfunctions               1\n"), "{}", report);
+    assert!(report.contains("\n== Execution Stats ==\n"), "{}", report);
+
+    let output = Command::new(bin).args(["--stats-json", "examples/test11.aur"]).output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5\n");
+    let json = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(json.lines().count(), 1);
+    assert!(json.starts_with("{\"compile\":{\"instructions\":"), "{}", json);
+    assert!(json.contains("\"functions\":1,"), "{}", json);
+    assert!(json.contains("\"execution\":{\"instructions\":"), "{}", json);
+}
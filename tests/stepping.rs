@@ -0,0 +1,70 @@
+use std::io;
+
+use caurora::{
+    compiler::Compiler,
+    memoryslice::MemorySlice,
+    opcodes::OpCode,
+    scanner::Scanner,
+    values::Value,
+    virtualmachine::{InterpretResult, VM},
+};
+
+fn compile(source: &str) -> MemorySlice {
+    Compiler::new(source, MemorySlice::new(), Scanner::new(source))
+        .compile()
+        .unwrap()
+}
+
+#[test]
+fn each_step_runs_one_instruction() {
+    let memory = compile("var a = 1;\nvar b = a - 3;\nprint b;");
+    let mut vm = VM::builder().memory(&memory).output(Box::new(io::sink())).build();
+    let n = Value::Number;
+    let steps: [(OpCode, Vec<Value>); 11] = [
+        (OpCode::Constant, vec![n(1.0)]),
+        (OpCode::DefineGlobalSlot, vec![]),
+        (OpCode::AssertStack, vec![]),
+        (OpCode::GetGlobalSlot, vec![n(1.0)]),
+        (OpCode::Constant, vec![n(1.0), n(3.0)]),
+        (OpCode::Subtract, vec![n(-2.0)]),
+        (OpCode::DefineGlobalSlot, vec![]),
+        (OpCode::AssertStack, vec![]),
+        (OpCode::GetGlobalSlot, vec![n(-2.0)]),
+        (OpCode::Print, vec![]),
+        (OpCode::AssertStack, vec![]),
+    ];
+    // Only debug builds emit the stack checks.
+    let expected = steps
+        .into_iter()
+        .filter(|(opcode, _)| cfg!(debug_assertions) || *opcode != OpCode::AssertStack);
+    for (opcode, stack) in expected {
+        assert_eq!(vm.next_instruction().unwrap().opcode, opcode);
+        assert_eq!(vm.step(), None, "{:?}", opcode);
+        assert_eq!(vm.stack(), stack, "after {:?}", opcode);
+    }
+    assert_eq!(vm.globals().get("b"), Some(&Value::Number(-2.0)));
+    assert_eq!(vm.next_instruction().unwrap().opcode, OpCode::Eof);
+    assert_eq!(vm.step(), Some(InterpretResult::InterpretOk));
+}
+
+#[test]
+fn breakpoints_pause_and_resume_where_they_stopped() {
+    let memory = compile("var total = 0;\nfunction add(n) {\n    total = total + n;\n}\nadd(1);\nadd(2);\nprint total;");
+    let mut vm = VM::builder().memory(&memory).output(Box::new(io::sink())).build();
+    assert!(vm.add_line_breakpoint(3));
+    assert!(!vm.add_line_breakpoint(40));
+
+    assert_eq!(vm.interpret(), InterpretResult::InterpretPaused);
+    assert_eq!((vm.chunk(), vm.ip(), vm.call_depth()), (1, 0, 1));
+    assert_eq!(vm.globals().get("total"), Some(&Value::Number(0.0)));
+
+    assert_eq!(vm.interpret(), InterpretResult::InterpretPaused);
+    assert_eq!(vm.globals().get("total"), Some(&Value::Number(1.0)));
+
+    vm.clear_breakpoints();
+    vm.add_breakpoint(0, memory.chunk(0).unwrap().len() - 1);
+    assert_eq!(vm.interpret(), InterpretResult::InterpretPaused);
+    assert_eq!(vm.next_instruction().unwrap().opcode, OpCode::Eof);
+    assert_eq!(vm.globals().get("total"), Some(&Value::Number(3.0)));
+    assert_eq!(vm.interpret(), InterpretResult::InterpretOk);
+}
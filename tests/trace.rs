@@ -0,0 +1,35 @@
+use std::process::Command;
+
+// Runs the binary with `--trace` and returns the script's output and the
+// trace, which goes to stderr.
+fn trace(script: &str) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_caurora"))
+        .args(["--trace", script])
+        .output()
+        .expect("could not run caurora");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+// Offsets are left out of the comparison, since only debug builds have the
+// stack checks that shift them.
+#[test]
+fn each_instruction_is_logged_with_the_stack_top() {
+    let (_, trace) = trace("tests/golden/disassemble.aur");
+    let lines: Vec<&str> = trace.lines().collect();
+    assert!(lines.iter().all(|line| line[..4].bytes().all(|b| b.is_ascii_digit())));
+    let instructions: Vec<&str> = lines.iter().map(|line| &line[5..]).collect();
+    assert_eq!(lines[0], "0000 Constant            0 []");
+    assert!(instructions.contains(&"Equal                 [3, 3]"));
+    assert!(instructions.contains(&"Print                 [\"done\"]"));
+    assert_eq!(instructions.last(), Some(&"Eof                   []"));
+}
+
+#[test]
+fn tracing_leaves_the_output_alone() {
+    let (output, _) = trace("tests/golden/disassemble.aur");
+    assert!(output.starts_with("done\n"));
+}
@@ -0,0 +1,56 @@
+use caurora::{
+    compiler::Compiler, memoryslice::MemorySlice, scanner::Scanner, token::TokenType, Interpreter,
+};
+
+#[test]
+fn identifiers_may_use_any_letters() {
+    let source = "var π = 1; var café_2 = π; print naïve;";
+    let mut scanner = Scanner::new(source);
+    let mut identifiers = Vec::new();
+    loop {
+        let token = scanner.scan_token();
+        match token.tokentype {
+            TokenType::Eof => break,
+            TokenType::Error => panic!("{}", scanner.error_msg),
+            TokenType::Identifier => identifiers.push(&source[token.start..token.start + token.length]),
+            _ => (),
+        }
+    }
+    assert_eq!(identifiers, ["π", "café_2", "π", "naïve"]);
+}
+
+#[test]
+fn scripts_with_unicode_names_and_text_run() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter
+        .run(
+            "// ☕ before anything\nvar π = 3;\nfunction площадь(r) { return π * r * r; }\n\
+             var café = \"🍰 /* not a comment */\";\nprint café + \" \" + площадь(2);\nprint len(\"日本語🎉\");",
+        )
+        .unwrap();
+    assert_eq!(interpreter.take_output(), "🍰 /* not a comment */ 12\n4\n");
+}
+
+#[test]
+fn errors_after_multi_byte_text_point_at_the_right_column() {
+    let source = "var 名前 = \"🎉🎉\"; print 名前 名前;";
+    let mut compiler = Compiler::new(source, MemorySlice::new(), Scanner::new(source));
+    let errors = compiler.compile().unwrap_err();
+    assert_eq!(errors[0].to_string(), "<script>:1:25: expect ';' after value, found '名前'");
+}
+
+#[test]
+fn substr_counts_characters_and_clamps_to_the_string() {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter
+        .run(
+            "print substr(\"héllo\", 1, 3), substr(\"héllo\", 3, 100), substr(\"héllo\", -2, 2);\n\
+             print substr(\"héllo\", 1, 1e300), substr(\"héllo\", 1e300, 1) == \"\";",
+        )
+        .unwrap();
+    assert_eq!(interpreter.take_output(), "éll lo hé\néllo true\n");
+    for bad in ["1.5", "sqrt(-1)"] {
+        let error = interpreter.run(&format!("substr(\"héllo\", {}, 1);", bad)).unwrap_err();
+        assert!(error.to_string().contains("expected an integer"), "{}", error);
+    }
+}
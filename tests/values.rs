@@ -0,0 +1,42 @@
+use std::{fs, mem::size_of};
+
+use caurora::{values::Value, Interpreter};
+
+fn run(source: &str) -> String {
+    let mut interpreter = Interpreter::builder().capture_output().build();
+    interpreter.run(source).unwrap();
+    interpreter.take_output()
+}
+
+// Everything bigger than a number lives behind an Rc, so copying a value
+// around the stack copies at most a pointer and a length.
+#[test]
+fn a_value_is_three_words() {
+    assert!(size_of::<Value>() <= 3 * size_of::<usize>(), "{} bytes", size_of::<Value>());
+}
+
+#[test]
+fn sharing_values_keeps_their_semantics() {
+    let source = "
+var text = \"a\";
+var copy = text;
+copy = copy + \"b\";
+print text, copy;
+var list = [1, 2];
+var alias = list;
+alias[0] = 9;
+print list[0];
+function f() { return 1; }
+function g() { return 1; }
+var h = f;
+print f == h, f == g, len == len;
+";
+    assert_eq!(run(source), "a ab\n9\ntrue false true\n");
+}
+
+// Each kind of value printed once, checked against the `.txt` golden file.
+#[test]
+fn printed_values_match_golden_file() {
+    let expected = fs::read_to_string("tests/golden/print.txt").unwrap();
+    assert_eq!(run(&fs::read_to_string("tests/golden/print.aur").unwrap()), expected);
+}